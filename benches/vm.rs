@@ -0,0 +1,38 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use iridium::{
+    assembler::Assembler,
+    vm::VM,
+};
+
+/// The loop from the assembler tests, counting down so it terminates: decrement `$0` until it
+/// equals `$2`, jumping back through `$3` each time round.
+const LOOP_PROGRAM: &str = ".data
+.code
+load $0 #10000
+load $2 #0
+load $3 @test
+test: dec $0
+neq $0 $2
+jmpe $3
+hlt";
+
+fn run_loop(program: &[u8], predecode: bool) {
+    let mut vm = VM::new();
+    vm.set_predecode(predecode);
+    vm.add_bytes(program.to_vec());
+    vm.run();
+    assert_eq!(vm.registers[0], 0);
+}
+
+fn dispatch(c: &mut Criterion) {
+    let program = Assembler::new().assemble(LOOP_PROGRAM).expect("loop program should assemble");
+
+    let mut group = c.benchmark_group("loop");
+    group.bench_function("byte decoding", |b| b.iter(|| run_loop(&program, false)));
+    group.bench_function("predecoded", |b| b.iter(|| run_loop(&program, true)));
+    group.finish();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
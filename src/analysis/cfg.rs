@@ -0,0 +1,518 @@
+//! Splits a program into basic blocks and works out which blocks can follow which.
+//!
+//! Jumps go through registers, so a jump's target is only known when its register is written
+//! exactly once, by a `load` that runs before the jump on every path to it. `load $0 @loop`
+//! followed by `jmp $0` is the usual case. A jump that can be reached before its register is
+//! loaded, or through a register written anywhere else, isn't followed: its block is marked
+//! `indirect`, and is given every block whose address is loaded somewhere in the program as a
+//! successor, since that's where such a jump could be going.
+//!
+//! On top of the graph are dominators, natural loops and a Graphviz export for looking at it.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::assembler::{
+    PIE_HEADER_PREFIX,
+    program_parser::Program,
+    symbols::{SymbolTable, SymbolType},
+};
+use crate::instructions::{Instruction, Opcode, INSTRUCTION_LENGTH};
+use crate::vm::{CODE_START, REGISTER_COUNT};
+
+use super::{encode, program_symbols, register_written};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BasicBlock {
+    /// Index of the first instruction in the block
+    pub start: usize,
+    /// Index just past the last instruction in the block
+    pub end: usize,
+    /// Blocks control can go to next, without duplicates
+    pub successors: Vec<usize>,
+    /// The block ends in a jump whose target isn't known
+    pub indirect: bool,
+}
+
+/// Where control can go after an instruction
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Flow {
+    Next,
+    Stop,
+    Jump(Option<usize>),
+    /// Jumps if the equal flag is set, otherwise carries on
+    Branch(Option<usize>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    /// The address of the first instruction
+    pub code_start: usize,
+    pub instructions: Vec<Instruction>,
+    /// The blocks in address order. The first one is where the program starts.
+    pub blocks: Vec<BasicBlock>,
+}
+
+/// A loop found from a back edge: a jump to a block that dominates the jump
+#[derive(Debug, PartialEq, Clone)]
+pub struct Loop {
+    /// The block every way into the loop goes through
+    pub header: usize,
+    /// The blocks that jump back to the header
+    pub latches: Vec<usize>,
+    /// Every block in the loop, the header included, in address order
+    pub blocks: Vec<usize>,
+}
+
+impl Cfg {
+    pub fn new(instructions: Vec<Instruction>, code_start: usize) -> Self {
+        let mut cfg = Cfg { code_start, instructions, blocks: vec![] };
+        let constants = constant_registers(&cfg.instructions);
+        // Jumps that can run before the load of their register. Giving up on a target only adds
+        // edges, and edges only take dominance away, so this grows until it settles.
+        let mut unknown = BTreeSet::new();
+        loop {
+            cfg.link(&constants, &unknown);
+            let idom = cfg.dominators();
+            let reachable = cfg.reachable();
+            let before = unknown.len();
+            for (index, i) in cfg.instructions.iter().enumerate() {
+                let load = jump_register(i).and_then(|r| constants.get(r as usize).copied().flatten());
+                if let Some((load, _)) = load {
+                    let (from, to) = (cfg.block_of(load), cfg.block_of(index));
+                    let runs_first = if from == to { load < index } else { Cfg::dominates(&idom, from, to) };
+                    if reachable[to] && !runs_first {
+                        unknown.insert(index);
+                    }
+                }
+            }
+            if unknown.len() == before {
+                return cfg;
+            }
+        }
+    }
+
+    /// Splits the instructions into blocks and links them, following the jumps through
+    /// `constants` except those in `unknown`
+    fn link(&mut self, constants: &[Option<(usize, i32)>], unknown: &BTreeSet<usize>) {
+        let flows: Vec<Flow> = self.instructions.iter().enumerate()
+            .map(|(index, i)| {
+                let target = jump_register(i)
+                    .filter(|_| !unknown.contains(&index))
+                    .and_then(|r| constants.get(r as usize).copied().flatten())
+                    .map(|(_, value)| value);
+                flow(i, self.address(index), target)
+            })
+            .collect();
+
+        // Addresses loaded into registers are where indirect jumps could go
+        let loaded: BTreeSet<usize> = self.instructions.iter()
+            .filter(|i| i.opcode == Opcode::LOAD)
+            .filter_map(|i| self.index_of(i.wide_operand(1) as usize))
+            .collect();
+        let mut leaders = loaded.clone();
+        if !self.instructions.is_empty() {
+            leaders.insert(0);
+        }
+        for (index, flow) in flows.iter().enumerate() {
+            if *flow != Flow::Next && index + 1 < self.instructions.len() {
+                leaders.insert(index + 1);
+            }
+            if let Flow::Jump(Some(address)) | Flow::Branch(Some(address)) = flow {
+                if let Some(target) = self.index_of(*address) {
+                    leaders.insert(target);
+                }
+            }
+        }
+
+        let starts: Vec<usize> = leaders.into_iter().collect();
+        let block_of = |index: usize| starts.partition_point(|&start| start <= index) - 1;
+        let loaded_blocks: Vec<usize> = loaded.iter().map(|&index| block_of(index)).collect();
+        let mut blocks = vec![];
+        for (b, &start) in starts.iter().enumerate() {
+            let end = starts.get(b + 1).copied().unwrap_or(self.instructions.len());
+            let mut indirect = false;
+            let mut successors = vec![];
+            let mut jump_to = |address: Option<usize>, successors: &mut Vec<usize>| match address {
+                // Going past the end ends the program
+                Some(address) if address >= self.end_address() => {},
+                Some(address) if self.index_of(address).is_some() => {
+                    successors.push(block_of(self.index_of(address).unwrap()));
+                },
+                _ => {
+                    indirect = true;
+                    successors.extend(&loaded_blocks);
+                },
+            };
+            let falls_through = end < self.instructions.len();
+            match flows[end - 1] {
+                Flow::Next if falls_through => successors.push(b + 1),
+                Flow::Next | Flow::Stop => {},
+                Flow::Jump(address) => jump_to(address, &mut successors),
+                Flow::Branch(address) => {
+                    jump_to(address, &mut successors);
+                    if falls_through {
+                        successors.push(b + 1);
+                    }
+                },
+            }
+            let mut seen = BTreeSet::new();
+            successors.retain(|s| seen.insert(*s));
+            blocks.push(BasicBlock { start, end, successors, indirect });
+        }
+        self.blocks = blocks;
+    }
+
+    /// Builds the graph of a parsed program, encoded as the assembler would without the optimizer
+    pub fn from_program(program: &Program) -> Self {
+        Cfg::new(encode(program, &program_symbols(program)), CODE_START)
+    }
+
+    /// Builds the graph of assembled bytecode, or returns `None` if it doesn't start with a
+    /// PIE header
+    pub fn from_bytecode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < CODE_START || bytes[..PIE_HEADER_PREFIX.len()] != PIE_HEADER_PREFIX {
+            return None;
+        }
+        let instructions = bytes[CODE_START..].chunks(INSTRUCTION_LENGTH).map(Instruction::decode).collect();
+        Some(Cfg::new(instructions, CODE_START))
+    }
+
+    /// The address of the instruction at `index`
+    pub fn address(&self, index: usize) -> usize {
+        self.code_start + index * INSTRUCTION_LENGTH
+    }
+
+    /// The address just past the last instruction
+    pub fn end_address(&self) -> usize {
+        self.address(self.instructions.len())
+    }
+
+    /// The index of the instruction at `address`, if an instruction starts there
+    pub fn index_of(&self, address: usize) -> Option<usize> {
+        let offset = address.checked_sub(self.code_start)?;
+        if offset % INSTRUCTION_LENGTH == 0 && address < self.end_address() {
+            Some(offset / INSTRUCTION_LENGTH)
+        } else {
+            None
+        }
+    }
+
+    /// The block holding the instruction at `index`
+    pub fn block_of(&self, index: usize) -> usize {
+        self.blocks.partition_point(|block| block.start <= index) - 1
+    }
+
+    /// Which blocks can be reached from the start of the program
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut pending = if self.blocks.is_empty() { vec![] } else { vec![0] };
+        while let Some(b) = pending.pop() {
+            if !reachable[b] {
+                reachable[b] = true;
+                pending.extend(&self.blocks[b].successors);
+            }
+        }
+        reachable
+    }
+
+    /// The blocks that can come before each block
+    pub fn predecessors(&self) -> Vec<Vec<usize>> {
+        let mut predecessors = vec![vec![]; self.blocks.len()];
+        for (b, block) in self.blocks.iter().enumerate() {
+            for &s in &block.successors {
+                predecessors[s].push(b);
+            }
+        }
+        predecessors
+    }
+
+    /// The immediate dominator of every block: the closest block that every path from the
+    /// start goes through to get there. The start and unreachable blocks have none.
+    pub fn dominators(&self) -> Vec<Option<usize>> {
+        // Cooper, Harvey and Kennedy's "A Simple, Fast Dominance Algorithm"
+        let order = self.reverse_postorder();
+        let mut rank = vec![usize::MAX; self.blocks.len()];
+        for (n, &b) in order.iter().enumerate() {
+            rank[b] = n;
+        }
+        let predecessors = self.predecessors();
+        let mut idom: Vec<Option<usize>> = vec![None; self.blocks.len()];
+        if let Some(&entry) = order.first() {
+            idom[entry] = Some(entry);
+        }
+        let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while rank[a] > rank[b] {
+                    a = idom[a].unwrap();
+                }
+                while rank[b] > rank[a] {
+                    b = idom[b].unwrap();
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in order.iter().skip(1) {
+                let new = predecessors[b].iter().copied()
+                    .filter(|p| idom[*p].is_some())
+                    .fold(None, |new, p| Some(new.map_or(p, |n| intersect(&idom, n, p))));
+                if new != idom[b] {
+                    idom[b] = new;
+                    changed = true;
+                }
+            }
+        }
+        if let Some(&entry) = order.first() {
+            idom[entry] = None;
+        }
+        idom
+    }
+
+    /// Whether every path from the start to block `b` goes through block `a`, given the
+    /// immediate dominators from `dominators`
+    pub fn dominates(idom: &[Option<usize>], a: usize, mut b: usize) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+            match idom[b] {
+                Some(up) => b = up,
+                None => return false,
+            }
+        }
+    }
+
+    /// The natural loops, one per header, in address order. Loops whose body can be entered
+    /// other than through the header, which only indirect jumps can make, aren't found.
+    pub fn loops(&self) -> Vec<Loop> {
+        let idom = self.dominators();
+        let reachable = self.reachable();
+        let predecessors = self.predecessors();
+        let mut loops: Vec<Loop> = vec![];
+        for (b, block) in self.blocks.iter().enumerate().filter(|(b, _)| reachable[*b]) {
+            for &header in block.successors.iter().filter(|h| Cfg::dominates(&idom, **h, b)) {
+                let index = match loops.iter().position(|l| l.header == header) {
+                    Some(index) => index,
+                    None => {
+                        loops.push(Loop { header, latches: vec![], blocks: vec![header] });
+                        loops.len() - 1
+                    },
+                };
+                let found = &mut loops[index];
+                found.latches.push(b);
+                // Everything that reaches the latch without going through the header
+                let mut pending = vec![b];
+                while let Some(n) = pending.pop() {
+                    if !found.blocks.contains(&n) {
+                        found.blocks.push(n);
+                        pending.extend(predecessors[n].iter().filter(|p| reachable[**p]));
+                    }
+                }
+            }
+        }
+        for found in &mut loops {
+            found.latches.sort_unstable();
+            found.blocks.sort_unstable();
+        }
+        loops.sort_by_key(|l| l.header);
+        loops
+    }
+
+    /// Writes the graph in Graphviz's DOT language, one box per block listing its
+    /// instructions. Blocks that start at a code label in `symbols` are named after it, jumps
+    /// back to a loop header are dashed and unreachable blocks are grey.
+    pub fn to_dot(&self, symbols: &SymbolTable) -> String {
+        let reachable = self.reachable();
+        let idom = self.dominators();
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+        for (b, block) in self.blocks.iter().enumerate() {
+            let address = self.address(block.start) as u32;
+            let label = symbols.iter()
+                .find(|s| *s.symbol_type() == SymbolType::Label && s.offset() == Some(address))
+                .map(|s| format!("{}:", s.name()))
+                .unwrap_or_else(|| format!("block {}:", b));
+            let mut text = format!("{}\\l", label);
+            for index in block.start..block.end {
+                let _ = write!(text, "{:>5}  {}\\l", self.address(index), self.instructions[index]);
+            }
+            let style = if reachable[b] { "" } else { ", style=filled, fillcolor=lightgrey" };
+            let _ = writeln!(dot, "    b{} [label=\"{}\"{}];", b, text.replace('"', "\\\""), style);
+        }
+        for (b, block) in self.blocks.iter().enumerate() {
+            for &s in &block.successors {
+                let back = reachable[b] && Cfg::dominates(&idom, s, b);
+                let _ = writeln!(dot, "    b{} -> b{}{};", b, s, if back { " [style=dashed]" } else { "" });
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// The reachable blocks, each before the blocks it leads to except along back edges
+    fn reverse_postorder(&self) -> Vec<usize> {
+        let mut order = vec![];
+        if self.blocks.is_empty() {
+            return order;
+        }
+        let mut visited = vec![false; self.blocks.len()];
+        // Each entry is a block and how many of its successors have been visited
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some((b, next)) = stack.pop() {
+            match self.blocks[b].successors.get(next) {
+                Some(&s) => {
+                    stack.push((b, next + 1));
+                    if !visited[s] {
+                        visited[s] = true;
+                        stack.push((s, 0));
+                    }
+                },
+                None => order.push(b),
+            }
+        }
+        order.reverse();
+        order
+    }
+}
+
+/// Where control can go after `i`, which is at `address`. `target` is the value of the register
+/// it jumps through, when that's known.
+fn flow(i: &Instruction, address: usize, target: Option<i32>) -> Flow {
+    let absolute = target.filter(|v| *v >= 0).map(|v| v as usize);
+    // Relative jumps are measured from just after the register operand
+    let after_operand = address + 2;
+    match i.opcode {
+        Opcode::HLT | Opcode::IGL => Flow::Stop,
+        Opcode::JMP => Flow::Jump(absolute),
+        Opcode::JMPF => Flow::Jump(absolute.map(|v| after_operand + v)),
+        Opcode::JMPB => Flow::Jump(absolute.and_then(|v| after_operand.checked_sub(v))),
+        Opcode::JMPE => Flow::Branch(absolute),
+        Opcode::DJMPE => Flow::Branch(Some(i.wide_operand(0) as usize)),
+        _ => Flow::Next,
+    }
+}
+
+/// The register a jump goes through, if it goes through one
+fn jump_register(i: &Instruction) -> Option<u8> {
+    match i.opcode {
+        Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JMPE => Some(i.operands[0]),
+        _ => None,
+    }
+}
+
+/// The index of the `load` and the value of every register that is only ever written by it
+fn constant_registers(instructions: &[Instruction]) -> Vec<Option<(usize, i32)>> {
+    let mut writes: Vec<Vec<(usize, &Instruction)>> = vec![vec![]; REGISTER_COUNT];
+    for (index, i) in instructions.iter().enumerate() {
+        if let Some(r) = register_written(i).filter(|r| (*r as usize) < REGISTER_COUNT) {
+            writes[r as usize].push((index, i));
+        }
+    }
+    writes.iter().map(|w| match w.as_slice() {
+        [(index, i)] if i.opcode == Opcode::LOAD => Some((*index, i.wide_operand(1) as i32)),
+        _ => None,
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{Assembler, program_parser::program};
+
+    fn cfg(source: &str) -> Cfg {
+        let (_, parsed) = program(source).unwrap();
+        Cfg::from_program(&parsed)
+    }
+
+    fn edges(cfg: &Cfg) -> Vec<(usize, usize, Vec<usize>)> {
+        cfg.blocks.iter().map(|b| (b.start, b.end, b.successors.clone())).collect()
+    }
+
+    #[test]
+    fn test_loop() {
+        let cfg = cfg(".data\n.code\nload $0 #3\nload $1 @loop\nloop: dec $0\nneq $0 $2\njmpe $1\nhlt\n");
+        assert_eq!(edges(&cfg), vec![(0, 2, vec![1]), (2, 5, vec![1, 2]), (5, 6, vec![])]);
+        assert_eq!(cfg.predecessors(), vec![vec![], vec![0, 1], vec![1]]);
+        assert_eq!(cfg.block_of(3), 1);
+        assert!(cfg.blocks.iter().all(|b| !b.indirect));
+    }
+
+    #[test]
+    fn test_unreachable_and_relative_jumps() {
+        let cfg = cfg(".data\n.code\nload $0 #6\njmpf $0\nhlt\ninc $1\nhlt\ninc $2\n");
+        // jmpf at 69 lands on 71 + 6 = 77, the inc $1
+        assert_eq!(edges(&cfg), vec![(0, 2, vec![2]), (2, 3, vec![]), (3, 5, vec![]), (5, 6, vec![])]);
+        assert_eq!(cfg.reachable(), vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn test_indirect_jumps() {
+        let cfg = cfg(".data\n.code\nload $0 @a\nload $0 @b\njmp $0\na: hlt\nb: djmpe #200\n");
+        assert_eq!(edges(&cfg), vec![(0, 3, vec![1, 2]), (3, 4, vec![]), (4, 5, vec![])]);
+        assert!(cfg.blocks[0].indirect);
+        assert!(!cfg.blocks[2].indirect);
+    }
+
+    #[test]
+    fn test_jump_before_its_load() {
+        // The first jmp $0 can run before $0 is loaded, the second can't
+        let cfg = cfg(".data\n.code\nload $1 @later\neq $2 $3\njmpe $1\njmp $0\nlater: load $0 @done\njmp $0\ndone: hlt\n");
+        assert_eq!(edges(&cfg), vec![(0, 3, vec![2, 1]), (3, 4, vec![2, 3]), (4, 6, vec![3]), (6, 7, vec![])]);
+        assert!(cfg.blocks[1].indirect);
+        assert!(!cfg.blocks[2].indirect);
+    }
+
+    #[test]
+    fn test_from_bytecode() {
+        let source = ".data\n.code\nload $0 #3\nload $1 @loop\nloop: dec $0\nneq $0 $2\njmpe $1\nhlt\n";
+        let mut asm = Assembler::new();
+        asm.set_optimize(false);
+        let from_bytes = Cfg::from_bytecode(&asm.assemble(source).unwrap()).unwrap();
+        assert_eq!(edges(&from_bytes), edges(&cfg(source)));
+        assert!(Cfg::from_bytecode(&[1, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn test_dominators_and_loops() {
+        // An outer loop around an inner one, with a way out of each
+        let source = ".data\n.code\nload $0 @outer\nload $1 @inner\nload $2 @done\nouter: eq $3 $4\njmpe $2\ninner: inc $3\nlt $3 $4\njmpe $1\njmp $0\ndone: hlt\n";
+        let cfg = cfg(source);
+        assert_eq!(edges(&cfg), vec![
+            (0, 3, vec![1]),
+            (3, 5, vec![4, 2]),
+            (5, 8, vec![2, 3]),
+            (8, 9, vec![1]),
+            (9, 10, vec![]),
+        ]);
+        let idom = cfg.dominators();
+        assert_eq!(idom, vec![None, Some(0), Some(1), Some(2), Some(1)]);
+        assert!(Cfg::dominates(&idom, 1, 3));
+        assert!(!Cfg::dominates(&idom, 3, 4));
+        assert_eq!(cfg.loops(), vec![
+            Loop { header: 1, latches: vec![3], blocks: vec![1, 2, 3] },
+            Loop { header: 2, latches: vec![2], blocks: vec![2] },
+        ]);
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let source = ".data\n.code\nload $1 @loop\nloop: dec $0\njmp $1\nhlt\n";
+        let (_, parsed) = program(source).unwrap();
+        let dot = Cfg::from_program(&parsed).to_dot(&program_symbols(&parsed));
+        let expected = [
+            "digraph cfg {",
+            "    node [shape=box, fontname=monospace];",
+            "    b0 [label=\"block 0:\\l   65  load $1 #69\\l\"];",
+            "    b1 [label=\"loop:\\l   69  dec $0\\l   73  jmp $1\\l\"];",
+            "    b2 [label=\"block 2:\\l   77  hlt\\l\", style=filled, fillcolor=lightgrey];",
+            "    b0 -> b1;",
+            "    b1 -> b1 [style=dashed];",
+            "}",
+            "",
+        ].join("\n");
+        assert_eq!(dot, expected);
+    }
+}
@@ -0,0 +1,125 @@
+//! Maps the instruction counts the VM collects back to the source lines they came from, and
+//! reports them as a text summary or in the lcov format coverage tools read.
+
+use std::io::{self, Write};
+
+use crate::assembler::debug_info::LineTable;
+use crate::vm::coverage::Coverage;
+
+/// How many times the code on one source line ran
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LineHits {
+    pub line: u32,
+    /// The count of the instruction on the line that ran most, for lines with several
+    pub hits: u64,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CoverageReport {
+    /// Every line with an instruction on it, in line order
+    pub lines: Vec<LineHits>,
+}
+
+impl CoverageReport {
+    /// Counts each line of the program `lines` describes. `coverage` has to come from running
+    /// the same bytecode, so programs should be assembled without the optimizer.
+    pub fn new(lines: &LineTable, coverage: &Coverage) -> Self {
+        let mut report: Vec<LineHits> = vec![];
+        let mut entries: Vec<(u32, u32)> = lines.iter().collect();
+        entries.sort_unstable();
+        for (line, address) in entries {
+            let hits = coverage.hits(address as usize);
+            match report.last_mut() {
+                Some(last) if last.line == line => last.hits = last.hits.max(hits),
+                _ => report.push(LineHits { line, hits }),
+            }
+        }
+        CoverageReport { lines: report }
+    }
+
+    /// How many lines ran at least once
+    pub fn covered(&self) -> usize {
+        self.lines.iter().filter(|l| l.hits > 0).count()
+    }
+
+    /// A short summary for `path`, listing the lines that never ran as ranges
+    pub fn summary(&self, path: &str) -> String {
+        let total = self.lines.len();
+        let percent = if total == 0 { 100.0 } else { self.covered() as f64 * 100.0 / total as f64 };
+        let mut summary = format!("{}: {} of {} lines covered ({:.1}%)\n", path, self.covered(), total, percent);
+
+        // Neighbouring lines of code are joined into one range, even with blank lines between
+        let mut missed: Vec<(u32, u32)> = vec![];
+        let mut last_missed: Option<usize> = None;
+        for (n, line) in self.lines.iter().enumerate().filter(|(_, l)| l.hits == 0) {
+            match missed.last_mut() {
+                Some(range) if last_missed == Some(n - 1) => range.1 = line.line,
+                _ => missed.push((line.line, line.line)),
+            }
+            last_missed = Some(n);
+        }
+        if !missed.is_empty() {
+            let ranges: Vec<String> = missed.iter().map(|&(first, last)| match first == last {
+                true => first.to_string(),
+                false => format!("{}-{}", first, last),
+            }).collect();
+            summary.push_str(&format!("not covered: {}\n", ranges.join(", ")));
+        }
+        summary
+    }
+
+    /// Writes the report as an lcov tracefile for `path`
+    pub fn write_lcov<W: Write>(&self, path: &str, mut out: W) -> io::Result<()> {
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", path)?;
+        for line in &self.lines {
+            writeln!(out, "DA:{},{}", line.line, line.hits)?;
+        }
+        writeln!(out, "LF:{}", self.lines.len())?;
+        writeln!(out, "LH:{}", self.covered())?;
+        writeln!(out, "end_of_record")?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+
+    // Jumps over the incs on lines 6 and 8
+    const SOURCE: &str = ".data\n.code\nload $0 #81\njmp $0\n\ninc $1\n\ninc $1\nhlt\n";
+
+    fn report(source: &str) -> CoverageReport {
+        let mut asm = Assembler::new();
+        asm.set_optimize(false);
+        let mut vm = VM::new();
+        vm.set_output(Box::new(io::sink()));
+        vm.add_bytes(asm.assemble(source).unwrap());
+        vm.start_coverage();
+        vm.run();
+        CoverageReport::new(&asm.lines, vm.coverage().unwrap())
+    }
+
+    #[test]
+    fn test_report() {
+        let report = report(SOURCE);
+        assert_eq!(report.lines, vec![
+            LineHits { line: 3, hits: 1 },
+            LineHits { line: 4, hits: 1 },
+            LineHits { line: 6, hits: 0 },
+            LineHits { line: 8, hits: 0 },
+            LineHits { line: 9, hits: 1 },
+        ]);
+        assert_eq!(report.summary("a.iasm"), "a.iasm: 3 of 5 lines covered (60.0%)\nnot covered: 6-8\n");
+    }
+
+    #[test]
+    fn test_lcov() {
+        let mut lcov = vec![];
+        report(".data\n.code\nhlt\ninc $0\n").write_lcov("a.iasm", &mut lcov).unwrap();
+        let expected = "TN:\nSF:a.iasm\nDA:3,1\nDA:4,0\nLF:2\nLH:1\nend_of_record\n";
+        assert_eq!(String::from_utf8(lcov).unwrap(), expected);
+    }
+}
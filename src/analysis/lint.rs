@@ -0,0 +1,485 @@
+//! Checks for mistakes that can be found without running a program. Every rule works on the
+//! parsed `Program` and its control-flow graph, and only looks at code that can be reached.
+
+use std::collections::BTreeSet;
+
+use crate::assembler::{
+    Token,
+    assembler_errors::AssemblerError,
+    comment_parsers::space_and_comments,
+    program_parser::program_with_lines,
+    symbols::{SymbolTable, SymbolType},
+};
+use crate::instructions::Opcode;
+use crate::vm::{CODE_START, REGISTER_COUNT};
+
+use super::{cfg::Cfg, encode, is_comparison, program_symbols, register_written, registers_read};
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Rule {
+    /// `jmpe` or `djmpe` that can run before anything has set the equal flag
+    JumpWithoutComparison,
+    /// A comparison whose result is overwritten or never used by a conditional jump
+    UnusedComparison,
+    /// A register written and then never read
+    DeadStore,
+    /// Code nothing jumps or falls through to, such as after a `hlt`
+    Unreachable,
+    /// `prts` of something that isn't a string
+    PrtsNonString,
+    /// `div` by a register that is always 0 there
+    DivideByZero,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 6] = [
+        Rule::JumpWithoutComparison,
+        Rule::UnusedComparison,
+        Rule::DeadStore,
+        Rule::Unreachable,
+        Rule::PrtsNonString,
+        Rule::DivideByZero,
+    ];
+
+    /// The name rules are chosen by on the command line and reported with
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::JumpWithoutComparison => "jump-without-comparison",
+            Rule::UnusedComparison => "unused-comparison",
+            Rule::DeadStore => "dead-store",
+            Rule::Unreachable => "unreachable",
+            Rule::PrtsNonString => "prts-non-string",
+            Rule::DivideByZero => "divide-by-zero",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Rule> {
+        Rule::ALL.iter().copied().find(|rule| rule.name() == name)
+    }
+
+    /// Mistakes that will fault when the code runs are errors, the rest are warnings
+    pub fn severity(self) -> Severity {
+        match self {
+            Rule::DivideByZero => Severity::Error,
+            _ => Severity::Warning,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Finding {
+    pub rule: Rule,
+    /// The source line, counting from 1
+    pub line: u32,
+    pub message: String,
+}
+
+/// Which rules to run. All of them are on by default.
+#[derive(Debug, Clone)]
+pub struct LintConfig {
+    rules: BTreeSet<Rule>,
+}
+
+impl Default for LintConfig {
+    fn default() -> Self {
+        LintConfig { rules: Rule::ALL.iter().copied().collect() }
+    }
+}
+
+impl LintConfig {
+    /// Runs only `rules`
+    pub fn only(rules: &[Rule]) -> Self {
+        LintConfig { rules: rules.iter().copied().collect() }
+    }
+
+    pub fn disable(&mut self, rule: Rule) {
+        self.rules.remove(&rule);
+    }
+
+    pub fn is_enabled(&self, rule: Rule) -> bool {
+        self.rules.contains(&rule)
+    }
+}
+
+/// Lints `source`, returning what was found in line order, or an error if it doesn't parse
+pub fn lint(source: &str, config: &LintConfig) -> Result<Vec<Finding>, AssemblerError> {
+    if space_and_comments(source).is_empty() {
+        return Ok(vec![]);
+    }
+    let (parsed, lines) = match program_with_lines(source) {
+        Ok((remainder, _)) if !space_and_comments(remainder).is_empty() => {
+            let line = space_and_comments(remainder).lines().next().unwrap_or_default();
+            return Err(AssemblerError::ParseError { error: format!("Unable to parse: {}", line) });
+        },
+        Ok((_, parsed)) => parsed,
+        Err(e) => return Err(AssemblerError::ParseError { error: e.to_string() }),
+    };
+    let symbols = program_symbols(&parsed);
+    let opcodes: Vec<usize> = (0..parsed.instructions.len()).filter(|&n| parsed.instructions[n].is_opcode()).collect();
+    let linter = Linter {
+        cfg: Cfg::new(encode(&parsed, &symbols), CODE_START),
+        lines: opcodes.iter().map(|&n| lines[n]).collect(),
+        operands: opcodes.iter().map(|&n| parsed.instructions[n].operand1.clone()).collect(),
+        symbols,
+    };
+
+    let mut findings = vec![];
+    let reachable = linter.cfg.reachable();
+    for rule in Rule::ALL.iter().copied().filter(|rule| config.is_enabled(*rule)) {
+        let found = match rule {
+            Rule::JumpWithoutComparison => linter.jumps_without_comparison(&reachable),
+            Rule::UnusedComparison => linter.unused_comparisons(&reachable),
+            Rule::DeadStore => linter.dead_stores(&reachable),
+            Rule::Unreachable => linter.unreachable(&reachable),
+            Rule::PrtsNonString => linter.prts_non_strings(&reachable),
+            Rule::DivideByZero => linter.divisions_by_zero(&reachable),
+        };
+        findings.extend(found.into_iter().map(|(index, message)| Finding { rule, line: linter.lines[index], message }));
+    }
+    findings.sort_by_key(|f| (f.line, f.rule));
+    Ok(findings)
+}
+
+struct Linter {
+    cfg: Cfg,
+    /// The source line of each instruction
+    lines: Vec<u32>,
+    /// The first operand of each instruction as written, for labels
+    operands: Vec<Option<Token>>,
+    symbols: SymbolTable,
+}
+
+impl Linter {
+    /// The indexes of the instructions in the reachable blocks
+    fn reachable_instructions<'a>(&'a self, reachable: &'a [bool]) -> impl Iterator<Item = usize> + 'a {
+        self.cfg.blocks.iter().zip(reachable).filter(|(_, r)| **r).flat_map(|(block, _)| block.start..block.end)
+    }
+
+    fn mnemonic(&self, index: usize) -> &'static str {
+        self.cfg.instructions[index].opcode.mnemonic()
+    }
+
+    fn jumps_without_comparison(&self, reachable: &[bool]) -> Vec<(usize, String)> {
+        // Whether every path to the start of each block has made a comparison
+        let compared = self.forward(reachable, false, |state: &mut bool, index| {
+            *state |= is_comparison(self.cfg.instructions[index].opcode);
+        }, |a, b| a && b);
+
+        let mut found = vec![];
+        for (b, state) in compared.into_iter().enumerate() {
+            let mut state = match state {
+                Some(state) => state,
+                None => continue,
+            };
+            for index in self.cfg.blocks[b].start..self.cfg.blocks[b].end {
+                let opcode = self.cfg.instructions[index].opcode;
+                if matches!(opcode, Opcode::JMPE | Opcode::DJMPE) && !state {
+                    found.push((index, format!("{} can run before any comparison has set the equal flag", self.mnemonic(index))));
+                }
+                state |= is_comparison(opcode);
+            }
+        }
+        found
+    }
+
+    fn unused_comparisons(&self, reachable: &[bool]) -> Vec<(usize, String)> {
+        // Whether the equal flag is read before it is next set
+        let live_out = self.backward(reachable, |live: &mut bool, index| {
+            match self.cfg.instructions[index].opcode {
+                Opcode::JMPE | Opcode::DJMPE => *live = true,
+                opcode if is_comparison(opcode) => *live = false,
+                _ => {},
+            }
+        }, |a, b| a || b);
+
+        let mut found = vec![];
+        for (b, mut live) in live_out.into_iter().enumerate().filter(|(b, _)| reachable[*b]) {
+            for index in (self.cfg.blocks[b].start..self.cfg.blocks[b].end).rev() {
+                match self.cfg.instructions[index].opcode {
+                    Opcode::JMPE | Opcode::DJMPE => live = true,
+                    opcode if is_comparison(opcode) => {
+                        if !live {
+                            found.push((index, format!("The result of this {} is never used by a jmpe or djmpe", self.mnemonic(index))));
+                        }
+                        live = false;
+                    },
+                    _ => {},
+                }
+            }
+        }
+        found
+    }
+
+    fn dead_stores(&self, reachable: &[bool]) -> Vec<(usize, String)> {
+        let transfer = |live: &mut u32, index: usize| {
+            let i = &self.cfg.instructions[index];
+            if let Some(r) = register_written(i) {
+                *live &= !bit(r);
+            }
+            for r in registers_read(i) {
+                *live |= bit(r);
+            }
+        };
+        let live_out = self.backward(reachable, transfer, |a, b| a | b);
+
+        let mut found = vec![];
+        for (b, mut live) in live_out.into_iter().enumerate().filter(|(b, _)| reachable[*b]) {
+            for index in (self.cfg.blocks[b].start..self.cfg.blocks[b].end).rev() {
+                if let Some(r) = register_written(&self.cfg.instructions[index]) {
+                    if live & bit(r) == 0 {
+                        found.push((index, format!("${} is written here but never read afterwards", r)));
+                    }
+                }
+                transfer(&mut live, index);
+            }
+        }
+        found
+    }
+
+    fn unreachable(&self, reachable: &[bool]) -> Vec<(usize, String)> {
+        let mut found = vec![];
+        for (b, block) in self.cfg.blocks.iter().enumerate() {
+            // Only the first of a run of unreachable blocks is reported
+            if reachable[b] || (b > 0 && !reachable[b - 1]) {
+                continue;
+            }
+            let message = match block.start.checked_sub(1).map(|before| self.cfg.instructions[before].opcode) {
+                Some(opcode @ (Opcode::HLT | Opcode::JMP | Opcode::JMPF | Opcode::JMPB)) => {
+                    format!("Unreachable code after {}", opcode.mnemonic())
+                },
+                _ => "Unreachable code".to_string(),
+            };
+            found.push((block.start, message));
+        }
+        found
+    }
+
+    fn prts_non_strings(&self, reachable: &[bool]) -> Vec<(usize, String)> {
+        let strings: BTreeSet<u32> = self.symbols.iter()
+            .filter(|s| *s.symbol_type() == SymbolType::IrString)
+            .filter_map(|s| s.offset())
+            .collect();
+        let mut found = vec![];
+        for index in self.reachable_instructions(reachable) {
+            if self.cfg.instructions[index].opcode != Opcode::PRTS {
+                continue;
+            }
+            match &self.operands[index] {
+                Some(Token::LabelUsage { name }) => {
+                    let code = self.symbols.iter().any(|s| s.name() == name && *s.symbol_type() == SymbolType::Label);
+                    if code {
+                        found.push((index, format!("@{} is a code label, not a string", name)));
+                    }
+                },
+                Some(Token::IntegerOperand { value }) if !strings.contains(&(*value as u32)) => {
+                    found.push((index, format!("No string starts at read-only offset {}", value)));
+                },
+                _ => {},
+            }
+        }
+        found
+    }
+
+    fn divisions_by_zero(&self, reachable: &[bool]) -> Vec<(usize, String)> {
+        // The value of every register that is the same on every path, starting from zero
+        let constants = self.forward(reachable, [Some(0); REGISTER_COUNT], |state, index| {
+            fold_constants(state, index, &self.cfg);
+        }, |a, b| {
+            let mut merged = a;
+            for (m, b) in merged.iter_mut().zip(b.iter()) {
+                if *m != *b {
+                    *m = None;
+                }
+            }
+            merged
+        });
+
+        let mut found = vec![];
+        for (b, state) in constants.into_iter().enumerate() {
+            let mut state = match state {
+                Some(state) => state,
+                None => continue,
+            };
+            for index in self.cfg.blocks[b].start..self.cfg.blocks[b].end {
+                let i = &self.cfg.instructions[index];
+                let divisor = i.operands[1];
+                if i.opcode == Opcode::DIV && state.get(divisor as usize) == Some(&Some(0)) {
+                    found.push((index, format!("${} is always 0 here, so this div will fault", divisor)));
+                }
+                fold_constants(&mut state, index, &self.cfg);
+            }
+        }
+        found
+    }
+
+    /// Runs a forward analysis to a fixed point, returning the state at the start of every
+    /// block. Unreachable blocks have no state.
+    fn forward<S, T, M>(&self, reachable: &[bool], entry: S, transfer: T, meet: M) -> Vec<Option<S>>
+        where S: Clone + PartialEq, T: Fn(&mut S, usize), M: Fn(S, S) -> S
+    {
+        let mut states: Vec<Option<S>> = vec![None; self.cfg.blocks.len()];
+        if self.cfg.blocks.is_empty() {
+            return states;
+        }
+        states[0] = Some(entry);
+        let mut pending = vec![0];
+        while let Some(b) = pending.pop() {
+            let mut state = match states[b].clone() {
+                Some(state) => state,
+                None => continue,
+            };
+            let block = &self.cfg.blocks[b];
+            for index in block.start..block.end {
+                transfer(&mut state, index);
+            }
+            for &s in block.successors.iter().filter(|s| reachable[**s]) {
+                let merged = match states[s].clone() {
+                    Some(existing) => meet(existing, state.clone()),
+                    None => state.clone(),
+                };
+                if states[s].as_ref() != Some(&merged) {
+                    states[s] = Some(merged);
+                    pending.push(s);
+                }
+            }
+        }
+        states
+    }
+
+    /// Runs a backward analysis to a fixed point, returning the state at the end of every block
+    fn backward<S, T, M>(&self, reachable: &[bool], transfer: T, meet: M) -> Vec<S>
+        where S: Copy + PartialEq + Default, T: Fn(&mut S, usize), M: Fn(S, S) -> S
+    {
+        let blocks = &self.cfg.blocks;
+        let mut live_in = vec![S::default(); blocks.len()];
+        let mut live_out = vec![S::default(); blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in (0..blocks.len()).rev().filter(|b| reachable[*b]) {
+                let out = blocks[b].successors.iter().fold(S::default(), |state, &s| meet(state, live_in[s]));
+                let mut state = out;
+                for index in (blocks[b].start..blocks[b].end).rev() {
+                    transfer(&mut state, index);
+                }
+                if state != live_in[b] || out != live_out[b] {
+                    live_in[b] = state;
+                    live_out[b] = out;
+                    changed = true;
+                }
+            }
+        }
+        live_out
+    }
+}
+
+/// Works out the registers the instruction at `index` writes when their inputs are known
+fn fold_constants(state: &mut [Option<i32>; REGISTER_COUNT], index: usize, cfg: &Cfg) {
+    let i = &cfg.instructions[index];
+    let [r0, r1, r2] = i.operands;
+    let value = |r: u8| state.get(r as usize).copied().flatten();
+    let result = match i.opcode {
+        Opcode::LOAD => Some(i.wide_operand(1) as i32),
+        Opcode::INC => value(r0).map(|v| v.wrapping_add(1)),
+        Opcode::DEC => value(r0).map(|v| v.wrapping_sub(1)),
+        Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => match (value(r0), value(r1)) {
+            (Some(a), Some(b)) => match i.opcode {
+                Opcode::ADD => Some(a.wrapping_add(b)),
+                Opcode::SUB => Some(a.wrapping_sub(b)),
+                Opcode::MUL => Some(a.wrapping_mul(b)),
+                _ if b != 0 => Some(a.wrapping_div(b)),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => return,
+    };
+    let written = if matches!(i.opcode, Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV) { r2 } else { r0 };
+    if let Some(slot) = state.get_mut(written as usize) {
+        *slot = result;
+    }
+}
+
+/// The bit for register `r` in a set of registers. Registers that don't exist have none.
+fn bit(r: u8) -> u32 {
+    1u32.checked_shl(r as u32).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(source: &str, rule: Rule) -> Vec<(u32, String)> {
+        lint(source, &LintConfig::only(&[rule])).unwrap().into_iter().map(|f| (f.line, f.message)).collect()
+    }
+
+    const COUNTDOWN: &str = ".data\nhi: .asciiz 'Hi'\n.code\nload $0 #3\nload $1 @loop\nloop: prts @hi\ndec $0\nneq $0 $2\njmpe $1\nhlt\n";
+
+    #[test]
+    fn test_clean_program() {
+        assert_eq!(lint(COUNTDOWN, &LintConfig::default()).unwrap(), vec![]);
+        assert_eq!(lint("; nothing here\n", &LintConfig::default()).unwrap(), vec![]);
+        assert!(lint(".code\n123\n", &LintConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let source = ".data\n.code\nload $1 @end\njmpe $1\neq $0 $2\ngt $0 $2\njmpe $1\nlt $0 $2\nend: hlt\n";
+        assert_eq!(found(source, Rule::JumpWithoutComparison), vec![(4, "jmpe can run before any comparison has set the equal flag".to_string())]);
+        assert_eq!(found(source, Rule::UnusedComparison), vec![
+            (5, "The result of this eq is never used by a jmpe or djmpe".to_string()),
+            (8, "The result of this lt is never used by a jmpe or djmpe".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_dead_stores_and_unreachable_code() {
+        let source = ".data\n.code\nload $0 #1\nload $0 #2\ninc $0\nadd $0 $0 $3\nhlt\ninc $4\n";
+        assert_eq!(found(source, Rule::DeadStore), vec![
+            (3, "$0 is written here but never read afterwards".to_string()),
+            (6, "$3 is written here but never read afterwards".to_string()),
+        ]);
+        assert_eq!(found(source, Rule::Unreachable), vec![(8, "Unreachable code after hlt".to_string())]);
+        assert_eq!(found(COUNTDOWN, Rule::Unreachable), vec![]);
+    }
+
+    #[test]
+    fn test_prts_and_division() {
+        let source = ".data\nhi: .asciiz 'Hi'\n.code\nstart: prts @start\nprts #1\nprts #0\nload $1 #4\ndiv $1 $2 $3\nload $2 #2\ndiv $1 $2 $3\nsub $2 $2 $2\ndiv $1 $2 $3\n";
+        assert_eq!(found(source, Rule::PrtsNonString), vec![
+            (4, "@start is a code label, not a string".to_string()),
+            (5, "No string starts at read-only offset 1".to_string()),
+        ]);
+        assert_eq!(found(source, Rule::DivideByZero), vec![
+            (8, "$2 is always 0 here, so this div will fault".to_string()),
+            (12, "$2 is always 0 here, so this div will fault".to_string()),
+        ]);
+        assert_eq!(found(COUNTDOWN, Rule::DivideByZero), vec![]);
+    }
+
+    #[test]
+    fn test_config() {
+        let mut config = LintConfig::default();
+        config.disable(Rule::DeadStore);
+        assert!(!config.is_enabled(Rule::DeadStore));
+        assert!(config.is_enabled(Rule::Unreachable));
+        assert_eq!(Rule::from_name("divide-by-zero"), Some(Rule::DivideByZero));
+        assert_eq!(Rule::DivideByZero.severity(), Severity::Error);
+        assert_eq!(Rule::from_name("nope"), None);
+    }
+}
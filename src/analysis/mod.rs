@@ -0,0 +1,111 @@
+//! Static analysis of programs: control-flow graphs and the checks built on them.
+//!
+//! Programs parsed from source are encoded the way the assembler would without the optimizer,
+//! so the instruction at index `i` is at address `CODE_START + i * INSTRUCTION_LENGTH`.
+
+use crate::assembler::{
+    Token,
+    program_parser::Program,
+    symbols::{Symbol, SymbolTable, SymbolType},
+};
+use crate::instructions::{Instruction, Opcode, INSTRUCTION_LENGTH};
+use crate::vm::CODE_START;
+
+pub mod cfg;
+pub mod coverage;
+pub mod lint;
+pub mod profile;
+
+/// Builds the symbol table the assembler would for `program`: code labels at the address of
+/// their instruction and strings at their offset in the read-only section
+pub fn program_symbols(program: &Program) -> SymbolTable {
+    let mut symbols = SymbolTable::new();
+    let mut address = CODE_START as u32;
+    let mut ro_offset = 0;
+    for i in &program.instructions {
+        let string = i.get_directive_name().as_deref() == Some("asciiz");
+        if let Some(name) = i.get_label_name() {
+            if i.is_opcode() {
+                symbols.add_symbol(Symbol::new_with_offset(name, SymbolType::Label, address));
+            } else if string {
+                symbols.add_symbol(Symbol::new_with_offset(name, SymbolType::IrString, ro_offset));
+            }
+        }
+        if i.is_opcode() {
+            address += INSTRUCTION_LENGTH as u32;
+        }
+        if let (true, Some(text)) = (string, i.get_string_constant()) {
+            ro_offset += text.len() as u32 + 1;
+        }
+    }
+    symbols
+}
+
+/// Encodes the instructions of `program`, leaving out directives. Labels that haven't been
+/// declared are encoded as 0.
+pub fn encode(program: &Program, symbols: &SymbolTable) -> Vec<Instruction> {
+    program.instructions.iter().filter_map(|i| {
+        let code = match i.opcode {
+            Some(Token::Op { code }) => code,
+            _ => return None,
+        };
+        let mut bytes = vec![code as u8];
+        for token in [&i.operand1, &i.operand2, &i.operand3].iter().copied().flatten() {
+            match token {
+                Token::Register { reg_num } => bytes.push(*reg_num),
+                Token::IntegerOperand { value } => bytes.extend_from_slice(&(*value as u16).to_be_bytes()),
+                Token::LabelUsage { name } => {
+                    let value = symbols.symbol_value(name).unwrap_or(0);
+                    bytes.extend_from_slice(&(value as u16).to_be_bytes());
+                },
+                _ => {},
+            }
+        }
+        Some(Instruction::decode(&bytes))
+    }).collect()
+}
+
+/// The registers an instruction reads
+pub fn registers_read(i: &Instruction) -> Vec<u8> {
+    let [r0, r1, _] = i.operands;
+    match i.opcode {
+        Opcode::INC | Opcode::DEC | Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JMPE | Opcode::ALOC => vec![r0],
+        Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => vec![r0, r1],
+        Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTE | Opcode::LTE => vec![r0, r1],
+        _ => vec![],
+    }
+}
+
+/// The register an instruction writes, if any
+pub fn register_written(i: &Instruction) -> Option<u8> {
+    let [r0, _, r2] = i.operands;
+    match i.opcode {
+        Opcode::LOAD | Opcode::INC | Opcode::DEC => Some(r0),
+        Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => Some(r2),
+        _ => None,
+    }
+}
+
+/// Whether an instruction sets the equal flag
+pub fn is_comparison(opcode: Opcode) -> bool {
+    matches!(opcode, Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTE | Opcode::LTE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{Assembler, program_parser::program};
+
+    #[test]
+    fn test_encode_matches_assembler() {
+        let source = ".data\nhi: .asciiz 'Hi'\nbye: .asciiz 'Bye'\n.code\nload $0 @loop\nloop: prts @bye\njmp $0\n";
+        let (_, parsed) = program(source).unwrap();
+        let symbols = program_symbols(&parsed);
+        assert_eq!(symbols.symbol_value("bye"), Some(3));
+        let bytes: Vec<u8> = encode(&parsed, &symbols).iter().flat_map(|i| i.to_bytes().to_vec()).collect();
+
+        let mut asm = Assembler::new();
+        asm.set_optimize(false);
+        assert_eq!(bytes, asm.assemble(source).unwrap()[CODE_START..].to_vec());
+    }
+}
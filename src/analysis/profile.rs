@@ -0,0 +1,166 @@
+//! Turns a VM profile into something to read: the costliest labels and instructions, or a
+//! collapsed-stack file for flame graph tools such as `flamegraph.pl` and `inferno`.
+//!
+//! Stacks are a label and then an instruction under it, weighted by gas, so a flame graph shows
+//! which parts of the program the work goes to and which instructions within them.
+
+use std::io::{self, Write};
+
+use crate::assembler::{debug_info::LineTable, symbols::SymbolTable};
+use crate::instructions::Instruction;
+use crate::vm::profile::{Profile, Samples};
+
+/// What instructions before the first label are grouped under
+pub const NO_LABEL: &str = "(start)";
+
+/// One instruction that ran
+#[derive(Debug, PartialEq, Clone)]
+pub struct InstructionProfile {
+    pub address: usize,
+    pub instruction: Instruction,
+    /// The code label the instruction comes after
+    pub label: String,
+    /// The source line, when the program was assembled without the optimizer
+    pub line: Option<u32>,
+    pub samples: Samples,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProfileReport {
+    /// Every instruction that ran, in address order
+    pub instructions: Vec<InstructionProfile>,
+}
+
+impl ProfileReport {
+    /// Describes `profile`, which came from running `program`, using the symbols and lines the
+    /// assembler recorded for it
+    pub fn new(profile: &Profile, program: &[u8], symbols: &SymbolTable, lines: &LineTable) -> Self {
+        let instructions = profile.iter().map(|(address, samples)| InstructionProfile {
+            address,
+            instruction: Instruction::decode(program.get(address..).unwrap_or_default()),
+            label: symbols.label_before(address as u32).map(|s| s.name().to_string()).unwrap_or_else(|| NO_LABEL.to_string()),
+            line: lines.line_for_address(address as u32),
+            samples,
+        }).collect();
+        ProfileReport { instructions }
+    }
+
+    pub fn total(&self) -> Samples {
+        let mut total = Samples::default();
+        for i in &self.instructions {
+            total += i.samples;
+        }
+        total
+    }
+
+    /// The samples of each label, costliest first
+    pub fn by_label(&self) -> Vec<(String, Samples)> {
+        let mut labels: Vec<(String, Samples)> = vec![];
+        for i in &self.instructions {
+            match labels.iter_mut().find(|(label, _)| *label == i.label) {
+                Some((_, samples)) => *samples += i.samples,
+                None => labels.push((i.label.clone(), i.samples)),
+            }
+        }
+        labels.sort_by(|a, b| b.1.cost.cmp(&a.1.cost).then(a.0.cmp(&b.0)));
+        labels
+    }
+
+    /// A table of the `count` costliest labels and the `count` costliest instructions
+    pub fn top(&self, count: usize) -> String {
+        let total = self.total();
+        let percent = |cost: u64| if total.cost == 0 { 0.0 } else { cost as f64 * 100.0 / total.cost as f64 };
+        let mut report = format!("{} instructions run, costing {} gas\n\n", total.count, total.cost);
+
+        report.push_str(&format!("{:>10} {:>6} {:>10}  label\n", "gas", "%", "count"));
+        for (label, samples) in self.by_label().into_iter().take(count) {
+            report.push_str(&format!("{:>10} {:>5.1}% {:>10}  {}\n", samples.cost, percent(samples.cost), samples.count, label));
+        }
+
+        let mut instructions: Vec<&InstructionProfile> = self.instructions.iter().collect();
+        instructions.sort_by(|a, b| b.samples.cost.cmp(&a.samples.cost).then(a.address.cmp(&b.address)));
+        report.push_str(&format!("\n{:>10} {:>6} {:>10} {:>8} {:>5}  instruction\n", "gas", "%", "count", "address", "line"));
+        for i in instructions.into_iter().take(count) {
+            let line = i.line.map(|l| l.to_string()).unwrap_or_default();
+            report.push_str(&format!(
+                "{:>10} {:>5.1}% {:>10} {:>8} {:>5}  {}\n",
+                i.samples.cost, percent(i.samples.cost), i.samples.count, i.address, line, i.instruction,
+            ));
+        }
+        report
+    }
+
+    /// Writes one `label;instruction gas` line for each instruction, the format flame graph
+    /// tools read
+    pub fn write_collapsed<W: Write>(&self, mut out: W) -> io::Result<()> {
+        for i in &self.instructions {
+            writeln!(out, "{};{} {} {}", i.label, i.address, i.instruction, i.samples.cost)?;
+        }
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::instructions::Opcode;
+    use crate::vm::{gas::CostTable, VM};
+
+    fn report() -> ProfileReport {
+        let source = ".data\n.code\nload $0 #3\nload $1 @loop\nloop: dec $0\nneq $0 $2\njmpe $1\nhlt\n";
+        let mut asm = Assembler::new();
+        asm.set_optimize(false);
+        let program = asm.assemble(source).unwrap();
+        let mut costs = CostTable::new();
+        costs.set_cost(Opcode::DEC, 5);
+        let mut vm = VM::new();
+        vm.set_output(Box::new(io::sink()));
+        vm.set_cost_table(costs);
+        vm.add_bytes(program.clone());
+        vm.start_profile();
+        vm.run();
+        ProfileReport::new(vm.profile().unwrap(), &program, &asm.symbols, &asm.lines)
+    }
+
+    #[test]
+    fn test_by_label() {
+        let report = report();
+        assert_eq!(report.total(), Samples { count: 12, cost: 24 });
+        assert_eq!(report.by_label(), vec![
+            ("loop".to_string(), Samples { count: 10, cost: 22 }),
+            (NO_LABEL.to_string(), Samples { count: 2, cost: 2 }),
+        ]);
+    }
+
+    #[test]
+    fn test_top() {
+        let expected = [
+            "12 instructions run, costing 24 gas",
+            "",
+            "       gas      %      count  label",
+            "        22  91.7%         10  loop",
+            "",
+            "       gas      %      count  address  line  instruction",
+            "        15  62.5%          3       73     5  dec $0",
+            "",
+        ].join("\n");
+        assert_eq!(report().top(1), expected);
+    }
+
+    #[test]
+    fn test_collapsed() {
+        let mut collapsed = vec![];
+        report().write_collapsed(&mut collapsed).unwrap();
+        let expected = [
+            "(start);65 load $0 #3 1",
+            "(start);69 load $1 #73 1",
+            "loop;73 dec $0 15",
+            "loop;77 neq $0 $2 3",
+            "loop;81 jmpe $1 3",
+            "loop;85 hlt 1",
+            "",
+        ].join("\n");
+        assert_eq!(String::from_utf8(collapsed).unwrap(), expected);
+    }
+}
@@ -10,6 +10,7 @@ pub enum AssemblerError {
     NonOpcodeInOpcodeField,
     InsufficientSections,
     InvalidSection { name: String },
+    UnknownLabel { name: String },
     ParseError { error: String },
 }
 
@@ -26,6 +27,7 @@ impl fmt::Display for AssemblerError {
             AssemblerError::NonOpcodeInOpcodeField => f.write_str("A non-opcode was found in an opcode field"),
             AssemblerError::InsufficientSections => f.write_str("Less than two sections/segments were found"),
             AssemblerError::InvalidSection { name } => f.write_str(&format!("Invalid segment name found: {}", name)),
+            AssemblerError::UnknownLabel { name } => f.write_str(&format!("No label named {} has been declared", name)),
             AssemblerError::ParseError { ref error } => f.write_str(&format!("There was an error parsing the code: {}", error))
         }
     }
@@ -41,6 +43,7 @@ impl Error for AssemblerError {
             AssemblerError::NonOpcodeInOpcodeField => "A non-opcode was found in an opcode field",
             AssemblerError::InsufficientSections => "Less than two sections/segments were found",
             AssemblerError::InvalidSection { .. } => "Invalid segment name found",
+            AssemblerError::UnknownLabel { .. } => "A label was used without being declared",
             AssemblerError:: ParseError { .. } => "There was an error parsing",
         }
     }
@@ -0,0 +1,47 @@
+/// Starts a comment, which runs to the end of the line: `hlt ; all done`
+pub const COMMENT_START: char = ';';
+
+/// Skips whitespace and comments, returning what follows them
+pub fn space_and_comments(input: &str) -> &str {
+    let mut input = input.trim_start();
+    while input.starts_with(COMMENT_START) {
+        input = match input.find('\n') {
+            Some(end) => input[end..].trim_start(),
+            None => "",
+        };
+    }
+    input
+}
+
+/// Splits a line into its code and its comment, if it has one. The comment is returned without
+/// its `;`. Semicolons inside strings don't start comments.
+pub fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\'' => in_string = !in_string,
+            COMMENT_START if !in_string => return (&line[..i], Some(&line[i + 1..])),
+            _ => {},
+        }
+    }
+    (line, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_space_and_comments() {
+        assert_eq!(space_and_comments("  ; one\n\n; two\n  hlt ; three"), "hlt ; three");
+        assert_eq!(space_and_comments("; only a comment"), "");
+        assert_eq!(space_and_comments("hlt"), "hlt");
+    }
+
+    #[test]
+    fn test_split_comment() {
+        assert_eq!(split_comment("load $0 #1 ; one"), ("load $0 #1 ", Some(" one")));
+        assert_eq!(split_comment("hi: .asciiz 'a;b' ;c"), ("hi: .asciiz 'a;b' ", Some("c")));
+        assert_eq!(split_comment("hlt"), ("hlt", None));
+    }
+}
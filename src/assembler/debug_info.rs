@@ -0,0 +1,59 @@
+/// Which source line every instruction of an assembled program came from, so debuggers can
+/// translate between lines and addresses
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct LineTable {
+    /// `(line, address)` pairs in address order
+    entries: Vec<(u32, u32)>,
+}
+
+impl LineTable {
+    pub fn new() -> Self {
+        LineTable { entries: vec![] }
+    }
+
+    pub fn add(&mut self, line: u32, address: u32) {
+        self.entries.push((line, address));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Every `(line, address)` pair in address order
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.entries.iter().copied()
+    }
+
+    /// The line of the instruction at `address`
+    pub fn line_for_address(&self, address: u32) -> Option<u32> {
+        self.entries.iter().find(|(_, a)| *a == address).map(|(line, _)| *line)
+    }
+
+    /// Where a breakpoint asked for on `line` ends up: the first instruction on that line, or on
+    /// the next line that has one. Returns the line and address.
+    pub fn breakpoint_location(&self, line: u32) -> Option<(u32, u32)> {
+        self.entries.iter()
+            .filter(|(l, _)| *l >= line)
+            .min_by_key(|(l, a)| (*l, *a))
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_table() {
+        let mut table = LineTable::new();
+        table.add(3, 65);
+        table.add(4, 69);
+        table.add(7, 73);
+        assert_eq!(table.line_for_address(69), Some(4));
+        assert_eq!(table.line_for_address(70), None);
+        assert_eq!(table.breakpoint_location(4), Some((4, 69)));
+        assert_eq!(table.breakpoint_location(5), Some((7, 73)));
+        assert_eq!(table.breakpoint_location(1), Some((3, 65)));
+        assert_eq!(table.breakpoint_location(8), None);
+    }
+}
@@ -0,0 +1,232 @@
+//! Lays out `.iasm` source the same way every time. Labels go in the first column, mnemonics
+//! and directives in the second and operands after them, with section headers at the left
+//! margin. Mnemonics and register aliases are lowercased, spaces inside operands are dropped and
+//! numbers lose their leading zeros. Comments are kept, trailing ones lined up with each other,
+//! and runs of blank lines become one.
+//!
+//! Formatting never changes what a file assembles to: the result is parsed again and has to
+//! give the same `Program` as the original.
+
+use super::{
+    Token,
+    assembler_errors::AssemblerError,
+    comment_parsers::{space_and_comments, split_comment, COMMENT_START},
+    instruction_parser::{instruction, AssemblerInstruction},
+    program_parser::program,
+};
+
+/// An instruction split into its columns
+struct Columns {
+    label: String,
+    operation: String,
+    operands: Vec<String>,
+}
+
+impl Columns {
+    fn new(i: &AssemblerInstruction, text: &str) -> Self {
+        let mut registers = register_spellings(text).into_iter();
+        let operands = [&i.operand1, &i.operand2, &i.operand3].iter().copied().flatten().map(|token| match token {
+            Token::Register { reg_num } => match registers.next() {
+                // Aliases are kept, numbers are written without leading zeros
+                Some(alias) if !alias.is_empty() && !alias.bytes().all(|b| b.is_ascii_digit()) => format!("${}", alias),
+                _ => format!("${}", reg_num),
+            },
+            Token::IntegerOperand { value } => format!("#{}", value),
+            Token::LabelUsage { name } => format!("@{}", name),
+            Token::IrString { name } => format!("'{}'", name),
+            _ => String::new(),
+        }).collect();
+
+        let operation = match &i.directive {
+            Some(Token::Directive { name }) => format!(".{}", name),
+            // Spelled as written, since unknown mnemonics are all parsed as `igl`
+            _ => {
+                let after_label = if i.is_label() { text.split_once(':').map(|(_, rest)| rest).unwrap_or(text) } else { text };
+                after_label.trim_start().chars().take_while(char::is_ascii_alphabetic).collect::<String>().to_lowercase()
+            },
+        };
+        Columns {
+            label: i.get_label_name().map(|name| format!("{}:", name)).unwrap_or_default(),
+            operation,
+            operands,
+        }
+    }
+
+    /// Section headers such as `.code` sit at the left margin
+    fn is_section(&self) -> bool {
+        self.label.is_empty() && self.operands.is_empty() && self.operation.starts_with('.')
+    }
+}
+
+/// What a line of the source becomes
+enum SourceLine {
+    Blank,
+    Comment { indented: bool, text: String },
+    Code { instructions: Vec<Columns>, comment: Option<String> },
+}
+
+/// Returns `source` formatted, or an error if it doesn't parse
+pub fn format(source: &str) -> Result<String, AssemblerError> {
+    let mut lines: Vec<SourceLine> = source.lines().map(|line| {
+        let (code, comment) = split_comment(line);
+        match comment {
+            _ if !code.trim().is_empty() => SourceLine::Code { instructions: vec![], comment: comment.map(|c| c.trim_end().to_string()) },
+            Some(text) => SourceLine::Comment { indented: line.starts_with(char::is_whitespace), text: text.trim_end().to_string() },
+            None => SourceLine::Blank,
+        }
+    }).collect();
+
+    // Find each instruction the way `program` does, noting the text it came from and the line
+    // it starts on
+    let mut rest = source;
+    loop {
+        let start = space_and_comments(rest);
+        if start.is_empty() {
+            break;
+        }
+        let remaining = match instruction(rest) {
+            Ok((remaining, i)) if remaining.len() < rest.len() => {
+                let text = &start[..start.trim_end().len() - remaining.len()];
+                let line = source[..source.len() - start.len()].matches('\n').count();
+                if let Some(SourceLine::Code { instructions, .. }) = lines.get_mut(line) {
+                    instructions.push(Columns::new(&i, text));
+                }
+                remaining
+            },
+            _ => {
+                let line = start.lines().next().unwrap_or_default();
+                return Err(AssemblerError::ParseError { error: format!("Unable to parse: {}", line) });
+            },
+        };
+        rest = remaining;
+    }
+
+    let formatted = layout(&lines);
+    // Should never happen, but a formatter that breaks programs is worse than none
+    let has_code = !space_and_comments(source).is_empty();
+    if has_code && program(source).ok().map(|(_, p)| p) != program(&formatted).ok().map(|(_, p)| p) {
+        return Err(AssemblerError::ParseError { error: "Formatting would have changed the program".to_string() });
+    }
+    Ok(formatted)
+}
+
+fn layout(lines: &[SourceLine]) -> String {
+    let columns = || lines.iter().flat_map(|line| match line {
+        SourceLine::Code { instructions, .. } => instructions.iter().filter(|c| !c.is_section()).collect(),
+        _ => vec![],
+    });
+    let label_width = columns().map(|c| c.label.len()).max().unwrap_or(0);
+    let label_width = if label_width > 0 { label_width + 1 } else { 0 };
+    let operation_width = columns().map(|c| c.operation.len()).max().unwrap_or(0);
+    let code = |c: &Columns| if c.is_section() {
+        c.operation.clone()
+    } else {
+        format!("{:<lw$}{:<ow$} {}", c.label, c.operation, c.operands.join(" "), lw = label_width, ow = operation_width)
+            .trim_end()
+            .to_string()
+    };
+    let comment_column = lines.iter().filter_map(|line| match line {
+        SourceLine::Code { instructions, comment: Some(_) } => instructions.last().map(|c| code(c).len()),
+        _ => None,
+    }).max().unwrap_or(0);
+
+    let mut output: Vec<String> = vec![];
+    for line in lines {
+        match line {
+            SourceLine::Blank => {
+                if output.last().map(|l| !l.is_empty()).unwrap_or(false) {
+                    output.push(String::new());
+                }
+            },
+            SourceLine::Comment { indented, text } => {
+                let indent = if *indented { label_width } else { 0 };
+                output.push(format!("{:indent$}{}{}", "", COMMENT_START, text, indent = indent));
+            },
+            SourceLine::Code { instructions, comment } => {
+                for c in instructions {
+                    output.push(code(c));
+                }
+                if let Some(text) = comment {
+                    if instructions.is_empty() {
+                        // The line only continued an instruction from the line above
+                        output.push(format!("{:indent$}{}{}", "", COMMENT_START, text, indent = label_width));
+                    } else if let Some(last) = output.last_mut() {
+                        *last = format!("{:<width$} {}{}", last, COMMENT_START, text, width = comment_column);
+                    }
+                }
+            },
+        }
+    }
+    while output.last().map(|l| l.is_empty()).unwrap_or(false) {
+        output.pop();
+    }
+
+    let mut formatted = output.join("\n");
+    if !formatted.is_empty() {
+        formatted.push('\n');
+    }
+    formatted
+}
+
+/// How each register in `text` is written, in order and lowercased, without its `$`
+fn register_spellings(text: &str) -> Vec<String> {
+    let mut spellings = vec![];
+    let mut in_string = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\'' => in_string = !in_string,
+            '$' if !in_string => {
+                let name = text[i + 1..].trim_start().chars().take_while(char::is_ascii_alphanumeric);
+                spellings.push(name.collect::<String>().to_lowercase());
+            },
+            _ => {},
+        }
+    }
+    spellings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    const MESSY: &str = "; Counts down from three\n\n\n.data\n   hello:    .asciiz   'Hi;there'   ; greeting\n.code\nload $0 #003\n  LOAD $T1 @loop\nloop:dec $ 0\nprts   @hello\n      ; compare\nneq $0 $1\njmpe $t1 ; again\nhlt\n\n\n";
+
+    #[test]
+    fn test_format() {
+        let expected = [
+            "; Counts down from three",
+            "",
+            ".data",
+            "hello: .asciiz 'Hi;there' ; greeting",
+            ".code",
+            "       load    $0 #3",
+            "       load    $t1 @loop",
+            "loop:  dec     $0",
+            "       prts    @hello",
+            "       ; compare",
+            "       neq     $0 $1",
+            "       jmpe    $t1        ; again",
+            "       hlt",
+            "",
+        ].join("\n");
+        let formatted = format(MESSY).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_format_keeps_bytecode() {
+        let formatted = format(MESSY).unwrap();
+        assert_eq!(Assembler::new().assemble(&formatted).unwrap(), Assembler::new().assemble(MESSY).unwrap());
+    }
+
+    #[test]
+    fn test_format_edge_cases() {
+        assert_eq!(format("").unwrap(), "");
+        assert_eq!(format("  ; just a note  \n\n").unwrap(), "; just a note\n");
+        assert_eq!(format(".code\nhlt hlt\nfoo $1").unwrap(), ".code\nhlt\nhlt\nfoo $1\n");
+        assert_eq!(format(".code\nload $0\n  #1 ; one\n").unwrap(), ".code\nload $0 #1\n; one\n");
+        assert!(format(".code\nload $0 #1\n123\n").is_err());
+    }
+}
@@ -4,6 +4,7 @@ use super::{
     operand_parser::operand,
     directive_parser::directive,
     label_parsers::label_declaration,
+    comment_parsers::space_and_comments,
 };
 
 use nom::{
@@ -13,7 +14,7 @@ use nom::{
 };
 use crate::assembler::SymbolTable;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct AssemblerInstruction {
     pub opcode: Option<Token>,
     pub label: Option<Token>,
@@ -36,17 +37,15 @@ impl AssemblerInstruction {
             }
         }
 
-        for operand in &[&self.operand1, &self.operand2, &self.operand3] {
-            if let Some(token) = operand {
-                AssemblerInstruction::extract_operand(token, &mut results, symbols);
-            }
+        for token in [&self.operand1, &self.operand2, &self.operand3].iter().copied().flatten() {
+            AssemblerInstruction::extract_operand(token, &mut results, symbols);
         }
 
         while results.len() < 4 {
             results.push(0);
         }
 
-        return results;
+        results
     }
 
     pub fn is_label(&self) -> bool {
@@ -130,7 +129,7 @@ impl AssemblerInstruction {
 }
 
 pub fn instruction(input: &str) -> IResult<&str, AssemblerInstruction> {
-    let input = input.trim();
+    let input = space_and_comments(input).trim_end();
     alt((instruction_combined, directive))(input)
 }
 
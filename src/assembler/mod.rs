@@ -1,9 +1,13 @@
+use log::debug;
+
 use crate::instructions::Opcode;
 use super::assembler::{
     program_parser::*,
     assembler_errors::AssemblerError,
     instruction_parser::AssemblerInstruction,
     symbols::*,
+    debug_info::LineTable,
+    comment_parsers::space_and_comments,
 };
 
 pub mod opcode_parser;
@@ -13,14 +17,27 @@ pub mod instruction_parser;
 pub mod program_parser;
 pub mod directive_parser;
 pub mod label_parsers;
+pub mod comment_parsers;
 pub mod assembler_errors;
 pub mod symbols;
+pub mod optimizer;
+pub mod debug_info;
+pub mod formatter;
 
 /// Magic number that begins every bytecode file
 pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
 pub const PIE_HEADER_LENGTH: usize = 64;
 
-#[derive(Debug, PartialEq)]
+/// The output of assembling one more piece of a program with `Assembler::assemble_more`
+#[derive(Debug, PartialEq, Default)]
+pub struct Assembled {
+    /// Bytecode to append to the program
+    pub code: Vec<u8>,
+    /// Bytes to append to the read-only section
+    pub ro: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Op{ code: Opcode },
     Register{ reg_num: u8 },
@@ -31,29 +48,23 @@ pub enum Token {
     IrString{name: String},
 }
 
-#[derive(Debug,PartialEq)]
-pub enum AssemblerPhase { First, Second, Clone }
-
-impl Default for AssemblerPhase {
-    fn default() -> Self {
-        AssemblerPhase::First
-    }
+#[derive(Debug, PartialEq, Default)]
+pub enum AssemblerPhase {
+    #[default]
+    First,
+    Second,
+    Clone,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub enum AssemblerSection {
     Data { starting_instruction: Option<u32> },
     Code { starting_instruction: Option<u32> },
+    #[default]
     Unknown,
 }
 
-impl Default for AssemblerSection {
-    fn default() -> Self {
-        AssemblerSection::Unknown
-    }
-}
-
-impl<'a> From<&'a str> for AssemblerSection {
+impl From<&str> for AssemblerSection {
     fn from(name: &str) -> Self {
         match name {
             "data" => AssemblerSection::Data { starting_instruction: None },
@@ -83,6 +94,17 @@ pub struct Assembler {
     current_instruction: u32,
     /// Any errors we find along the way. At the end, we'll present them to the user
     errors: Vec<AssemblerError>,
+    /// Whether to run the peephole optimizer between the two phases
+    optimize: bool,
+    /// The source line of every instruction `assemble` produced. Left empty when the optimizer
+    /// changed anything, since the code no longer matches the source line for line.
+    pub lines: LineTable,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Assembler {
@@ -97,22 +119,33 @@ impl Assembler {
             current_section: None,
             current_instruction: 0,
             errors: vec![],
+            optimize: false,
+            lines: LineTable::new(),
         }
     }
 
+    /// Turns the optimization pass on or off. It is off by default, so the bytecode matches the
+    /// source one-to-one, which is easier to follow when debugging.
+    pub fn set_optimize(&mut self, enabled: bool) {
+        self.optimize = enabled;
+    }
+
     pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
         // Runs the raw input through our `nom` parser
-        match program(raw) {
+        match program_with_lines(raw) {
             // If there were no parsing errors, we now have a Vec<AssemblyInstruction> to process.
-            // `remainer` _should_ be "".
-            // TODO: A check for `remainder` to make sure it is "".
-            Ok((_remainder, prog)) => {
+            // `remainder` should be empty, otherwise the parser stopped on something it didn't understand.
+            Ok((remainder, (mut prog, lines))) => {
+                if let Some(line) = space_and_comments(remainder).lines().next() {
+                    return Err(vec![AssemblerError::ParseError { error: format!("Unable to parse: {}", line) }]);
+                }
+
                 // First get the header so we can smush it into the bytecode later.
                 let mut assembled_program = self.write_pie_header();
 
                 // Start processing the AssemblyInstruction's this is the first pass of our two pass assembler
                 // We pass a read-only reference down to another function.
-                self.process_first_phase(&prog);
+                self.process_first_phase(&prog, PIE_HEADER_LENGTH as u32 + 1);
 
                 // If we accumulated any errors in the first pass, return them and don't try to do the second pass.
                 if !self.errors.is_empty() {
@@ -128,6 +161,19 @@ impl Assembler {
                     return Err(self.errors.clone());
                 }
 
+                // Tidy up the code between the passes. Instructions may move, so the labels pointing at
+                // them need their offsets worked out again.
+                let mut rewrites = 0;
+                if self.optimize {
+                    rewrites = optimizer::optimize(&mut prog);
+                    debug!("Optimizer made {} rewrites", rewrites);
+                    self.assign_code_label_offsets(&prog, PIE_HEADER_LENGTH as u32 + 1);
+                }
+                self.lines = LineTable::new();
+                if rewrites == 0 {
+                    self.record_lines(&prog, &lines, PIE_HEADER_LENGTH as u32 + 1);
+                }
+
                 // Run the second pass which translates opcodes and associated operands into bytecode
                 let mut body = self.process_second_phase(&prog);
 
@@ -142,8 +188,54 @@ impl Assembler {
         }
     }
 
+    /// Assembles `raw` as a continuation of everything this assembler has seen so far, for
+    /// building a program up a piece at a time. Labels from earlier pieces can be used, the
+    /// section carries over (code if none has been declared yet) and `code_start` is the address
+    /// the new code will be loaded at. No header is written and the code isn't optimized, since
+    /// later pieces may jump into it. If there are errors the assembler is left as it was.
+    pub fn assemble_more(&mut self, raw: &str, code_start: u32) -> Result<Assembled, Vec<AssemblerError>> {
+        let prog = match program(raw) {
+            Ok((remainder, _)) if !space_and_comments(remainder).is_empty() => {
+                let line = space_and_comments(remainder).lines().next().unwrap_or_default();
+                return Err(vec![AssemblerError::ParseError { error: format!("Unable to parse: {}", line) }]);
+            },
+            Ok((_, prog)) => prog,
+            Err(e) => return Err(vec![AssemblerError::ParseError { error: e.to_string() }]),
+        };
+
+        let symbols = self.symbols.clone();
+        let ro_len = self.ro.len();
+        let ro_offset = self.ro_offset;
+        let sections = self.sections.len();
+        let current_section = self.current_section.clone();
+
+        self.errors.clear();
+        self.phase = AssemblerPhase::First;
+        self.current_instruction = 0;
+        if self.current_section.is_none() {
+            self.current_section = Some(AssemblerSection::Code { starting_instruction: None });
+        }
+
+        self.process_first_phase(&prog, code_start);
+        if self.errors.is_empty() {
+            self.check_label_usages(&prog);
+        }
+        if !self.errors.is_empty() {
+            self.symbols = symbols;
+            self.ro.truncate(ro_len);
+            self.ro_offset = ro_offset;
+            self.sections.truncate(sections);
+            self.current_section = current_section;
+            return Err(self.errors.clone());
+        }
+
+        let code = self.process_second_phase(&prog);
+        Ok(Assembled { code, ro: self.ro[ro_len..].to_vec() })
+    }
+
     /// Runs the first pass of the two-pass assembling process. It looks for labels and puts them in the symbol table.
-    fn process_first_phase(&mut self, p: &Program) {
+    /// `code_start` is the address the first instruction will be loaded at.
+    fn process_first_phase(&mut self, p: &Program, code_start: u32) {
         // Iterate over every instruction even though we only care able labels in this phase.
         for i in &p.instructions {
             if i.is_label() {
@@ -164,9 +256,48 @@ impl Assembler {
             self.current_instruction += 1;
         }
 
+        self.assign_code_label_offsets(p, code_start);
         self.phase = AssemblerPhase::Second;
     }
 
+    /// Reports every label used as an operand that isn't in the symbol table
+    fn check_label_usages(&mut self, p: &Program) {
+        for i in p.instructions.iter().filter(|i| i.is_opcode()) {
+            for operand in [&i.operand1, &i.operand2, &i.operand3].iter().copied().flatten() {
+                if let Token::LabelUsage { name } = operand {
+                    if self.symbols.symbol_value(name).is_none() {
+                        self.errors.push(AssemblerError::UnknownLabel { name: name.clone() });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sets the offset of every label attached to an opcode to the address the VM will find that
+    /// instruction at, so `load $0 @label` followed by `jmp $0` lands on it.
+    fn assign_code_label_offsets(&mut self, p: &Program, code_start: u32) {
+        let mut offset = code_start;
+        for i in &p.instructions {
+            if i.is_opcode() {
+                if let Some(name) = i.get_label_name() {
+                    self.symbols.set_symbol_offset(&name, offset);
+                }
+                offset += 4;
+            }
+        }
+    }
+
+    /// Fills in `lines` from the line each instruction of `p` was parsed from
+    fn record_lines(&mut self, p: &Program, lines: &[u32], code_start: u32) {
+        let mut address = code_start;
+        for (i, line) in p.instructions.iter().zip(lines) {
+            if i.is_opcode() {
+                self.lines.add(*line, address);
+                address += 4;
+            }
+        }
+    }
+
     /// Runs the second pass of the assembler
     fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
         // Restart the counting of instructions
@@ -213,7 +344,9 @@ impl Assembler {
             return;
         }
 
-        let symbol = Symbol::new(name, SymbolType::Label);
+        // Labels on directives name data in the read-only section, the rest name code
+        let symbol_type = if i.is_directive() { SymbolType::IrString } else { SymbolType::Label };
+        let symbol = Symbol::new(name, symbol_type);
         self.symbols.add_symbol(symbol);
     }
 
@@ -235,7 +368,6 @@ impl Assembler {
                 "asciiz" => self.handle_asciiz(i),
                 _ => {
                     self.errors.push(AssemblerError::UnknownDirectiveFound { directive: directive_name.clone() });
-                    return;
                 }
             }
         } else {
@@ -287,7 +419,8 @@ impl Assembler {
         }
     }
 
-    fn write_pie_header(&self) -> Vec<u8> {
+    /// The header every assembled program starts with
+    pub fn write_pie_header(&self) -> Vec<u8> {
         let mut header = PIE_HEADER_PREFIX.to_vec();
 
         while header.len() <= PIE_HEADER_LENGTH {
@@ -311,7 +444,7 @@ mod tests {
         let mut vm = VM::new();
         assert_eq!(program.len(), 93);
         vm.add_bytes(program);
-        assert_eq!(vm.program.len(), 93);
+        assert_eq!(vm.program().len(), 93);
     }
 
     #[test]
@@ -330,6 +463,122 @@ mod tests {
         assert!(program.is_err());
     }
 
+    #[test]
+    fn test_invalid_register() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\n.code\nload $200 #1\nhlt";
+        let program = asm.assemble(test_string);
+        assert!(program.is_err());
+    }
+
+    #[test]
+    fn test_register_aliases_and_mnemonic_case() {
+        let mut asm = Assembler::new();
+        let aliased = asm.assemble(".data\n.code\nLOAD $sp #100\nADD $a0 $t0 $ra\nhlt").unwrap();
+        let mut asm = Assembler::new();
+        let numbered = asm.assemble(".data\n.code\nload $29 #100\nadd $0 $4 $31\nhlt").unwrap();
+        assert_eq!(aliased, numbered);
+    }
+
+    #[test]
+    fn test_code_label_offsets() {
+        let mut asm = Assembler::new();
+        asm.set_optimize(false);
+        let test_string = ".data\n.code\nload $0 @target\njmp $0\ninc $1\ntarget: hlt";
+        let program = asm.assemble(test_string).unwrap();
+        assert_eq!(asm.symbols.symbol_value("target"), Some(77));
+        assert_eq!(program[65..69], [1, 0, 0, 77]);
+
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(vm.registers[1], 0);
+    }
+
+    #[test]
+    fn test_optimizer_keeps_behaviour() {
+        let test_string = ".data\n.code\nload $0 #0\nload $1 #1\nload $2 #10\nload $3 @loop\n\
+                           loop: add $0 $1 $0\nnop\nneq $0 $2\njmpe $3\nload $3 @done\njmp $3\n\
+                           inc $4\ndone: hlt\ninc $5";
+        let mut results = vec![];
+        for optimize in &[false, true] {
+            let mut asm = Assembler::new();
+            asm.set_optimize(*optimize);
+            let program = asm.assemble(test_string).unwrap();
+            let mut vm = VM::new();
+            vm.add_bytes(program.clone());
+            vm.run();
+            results.push((program.len(), vm.registers));
+        }
+        let (unoptimized_len, unoptimized_registers) = results[0];
+        let (optimized_len, optimized_registers) = results[1];
+        assert!(optimized_len < unoptimized_len);
+        // $3 holds a code address, which is expected to move when the code shrinks
+        assert_eq!(optimized_registers[..3], unoptimized_registers[..3]);
+        assert_eq!(optimized_registers[4..], unoptimized_registers[4..]);
+        assert_eq!(optimized_registers[0], 10);
+    }
+
+    #[test]
+    fn test_line_table() {
+        let test_string = ".data\nmsg: .asciiz 'Hi'\n.code\nload $0 #1\n\nnop\nloop: inc $0\nhlt";
+        let mut asm = Assembler::new();
+        asm.set_optimize(false);
+        asm.assemble(test_string).unwrap();
+        assert_eq!(asm.lines.line_for_address(65), Some(4));
+        assert_eq!(asm.lines.breakpoint_location(5), Some((6, 69)));
+        assert_eq!(asm.lines.line_for_address(73), Some(7));
+
+        // The optimizer removes the NOP, so the lines no longer match
+        let mut asm = Assembler::new();
+        asm.set_optimize(true);
+        asm.assemble(test_string).unwrap();
+        assert!(asm.lines.is_empty());
+    }
+
+    #[test]
+    fn test_comments() {
+        let mut asm = Assembler::new();
+        let plain = asm.assemble(".data\nhello: .asciiz 'a;b'\n.code\nload $0 #1\nhlt\n").unwrap();
+        let mut asm = Assembler::new();
+        let commented = asm.assemble("; greeting\n.data\nhello: .asciiz 'a;b' ; not 'a'\n.code\n\n  ; start\nload $0 #1;one\nhlt ; done\n; the end").unwrap();
+        assert_eq!(commented, plain);
+        assert_eq!(asm.ro, b"a;b\0".to_vec());
+        assert_eq!(asm.lines.line_for_address(69), Some(8));
+    }
+
+    #[test]
+    fn test_assemble_more() {
+        let mut asm = Assembler::new();
+        let start = PIE_HEADER_LENGTH as u32 + 1;
+        let first = asm.assemble_more("load $0 #5\nloop: dec $0", start).unwrap();
+        assert_eq!(first.code, vec![1, 0, 0, 5, 3, 0, 0, 0]);
+        assert_eq!(asm.symbols.symbol_value("loop"), Some(69));
+
+        let second = asm.assemble_more("load $1 @loop\nhello: .asciiz 'Hi'\nbye: .asciiz 'Bye'", start + 8).unwrap();
+        assert_eq!(second.code, vec![1, 1, 0, 69]);
+        assert_eq!(second.ro, b"Hi\0Bye\0".to_vec());
+        assert_eq!(asm.symbols.symbol_value("bye"), Some(3));
+
+        let third = asm.assemble_more("prts @bye", start + 12).unwrap();
+        assert_eq!(third.code, vec![20, 0, 3, 0]);
+        assert!(third.ro.is_empty());
+    }
+
+    #[test]
+    fn test_assemble_more_rolls_back_errors() {
+        let mut asm = Assembler::new();
+        asm.assemble_more("msg: .asciiz 'Hi'", 65).unwrap();
+        assert!(asm.assemble_more("other: .asciiz 'Lost'\nmsg: hlt", 65).is_err());
+        assert!(asm.assemble_more("load $0 @missing", 65).is_err());
+        assert_eq!(asm.symbols.symbol_value("other"), None);
+        assert_eq!(asm.ro, b"Hi\0".to_vec());
+
+        let next = asm.assemble_more("other: .asciiz 'Kept'", 65).unwrap();
+        assert_eq!(next.ro, b"Kept\0".to_vec());
+        assert_eq!(asm.symbols.symbol_value("other"), Some(3));
+    }
+
     #[test]
     fn test_first_phase_no_segment() {
         let mut asm = Assembler::new();
@@ -337,7 +586,7 @@ mod tests {
         let result = program(test_string);
         assert!(result.is_ok());
         let (_, p) = result.unwrap();
-        asm.process_first_phase(&p);
+        asm.process_first_phase(&p, PIE_HEADER_LENGTH as u32 + 1);
         assert_eq!(asm.errors.len(), 1);
     }
 
@@ -348,7 +597,7 @@ mod tests {
         let result = program(test_string);
         assert!(result.is_ok());
         let (_, p) = result.unwrap();
-        asm.process_first_phase(&p);
+        asm.process_first_phase(&p, PIE_HEADER_LENGTH as u32 + 1);
         assert_eq!(asm.errors.len(), 0);
     }
 }
\ No newline at end of file
@@ -25,12 +25,12 @@ mod tests {
     fn test_opcode_load() {
         // First tests that the opcode is detected and parsed correctly
         let result = opcode_load("load");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, token) = result.unwrap();
         assert_eq!(token, Token::Op {code: Opcode::LOAD});
         assert_eq!(rest, "");
         let result = opcode_load("oadl");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_rest, token) = result.unwrap();
         assert_eq!(token, Token::Op {code: Opcode::IGL});
     }
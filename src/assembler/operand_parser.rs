@@ -31,7 +31,7 @@ pub fn operand(input: &str) -> IResult<&str, Token> {
 }
 
 fn parse_operand(input: &str) -> Result<i32, std::num::ParseIntError> {
-    i32::from_str_radix(input, 10)
+    input.parse::<i32>()
 }
 
 fn integer_operand(input: &str) -> IResult<&str, Token> {
@@ -55,13 +55,13 @@ mod tests {
     fn test_parse_integer_operand() {
         // Test a valid operand
         let result = operand("#10");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, value) = result.unwrap();
         assert_eq!(rest, "");
         assert_eq!(value, Token::IntegerOperand {value:10});
 
         let result = integer_operand("10");
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
     }
 
     #[test]
@@ -0,0 +1,435 @@
+use crate::instructions::Opcode;
+use crate::vm::REGISTER_COUNT;
+
+use super::{
+    Token,
+    program_parser::Program,
+    instruction_parser::AssemblerInstruction,
+};
+
+/// Upper bound on how many times the passes are repeated looking for more work. Every pass
+/// shrinks or simplifies the program, so this is only a guard against jump chains that keep
+/// rewriting each other.
+const MAX_ROUNDS: usize = 16;
+
+/// A value the optimizer knows a register holds at a given point in a basic block
+#[derive(Debug, PartialEq, Clone)]
+enum Value {
+    Integer(i32),
+    Label(String),
+}
+
+type Constants = Vec<Option<Value>>;
+
+/// Runs the peephole optimizations over a parsed program until they stop finding anything to do
+/// and returns how many rewrites were made.
+///
+/// Nothing is done unless every jump in the program goes through a label. Those are the only
+/// targets the assembler can fix up once the code has moved, and the only places the passes
+/// know execution can arrive from; a computed jump could land on any instruction.
+pub fn optimize(p: &mut Program) -> usize {
+    if !is_relocatable(p) {
+        return 0;
+    }
+    let mut total = 0;
+
+    for _ in 0..MAX_ROUNDS {
+        let changed = fold_increments(p)
+            + thread_jumps(p)
+            + remove_jumps_to_next(p)
+            + remove_dead_loads(p)
+            + remove_nops(p)
+            + remove_unreachable(p);
+
+        if changed == 0 {
+            break;
+        }
+        total += changed;
+    }
+
+    total
+}
+
+/// Checks that every jump target in the program is the address of a label, which means removing
+/// instructions is safe as long as label offsets are recalculated afterwards.
+fn is_relocatable(p: &Program) -> bool {
+    let mut jump_registers = vec![];
+    for i in &p.instructions {
+        match opcode(i) {
+            Some(Opcode::JMPF) | Some(Opcode::JMPB) => return false,
+            Some(Opcode::JMP) | Some(Opcode::JMPE) => match register(&i.operand1) {
+                Some(r) => jump_registers.push(r),
+                None => return false,
+            },
+            Some(Opcode::DJMPE) if label(&i.operand1).is_none() => return false,
+            _ => {}
+        }
+    }
+
+    // Every write to a register that is jumped through has to load a label
+    p.instructions.iter().all(|i| match writes(i) {
+        Some(r) if jump_registers.contains(&r) => {
+            opcode(i) == Some(Opcode::LOAD) && label(&i.operand2).is_some()
+        },
+        _ => true,
+    })
+}
+
+/// Rewrites `add`/`sub` of a register known to hold 1 into `inc`/`dec`, e.g.
+/// `load $1 #1` followed by `add $0 $1 $0` becomes `inc $0`. The load is left for
+/// `remove_dead_loads` to clean up if nothing else reads it.
+fn fold_increments(p: &mut Program) -> usize {
+    let constants = constants_before(p);
+    let mut changed = 0;
+
+    for (index, i) in p.instructions.iter_mut().enumerate() {
+        let (lhs, rhs, dest) = match (register(&i.operand1), register(&i.operand2), register(&i.operand3)) {
+            (Some(lhs), Some(rhs), Some(dest)) => (lhs, rhs, dest),
+            _ => continue,
+        };
+        let is_one = |r: u8| constants[index][r as usize] == Some(Value::Integer(1));
+
+        let replacement = match opcode(i) {
+            Some(Opcode::ADD) if lhs == dest && is_one(rhs) => Opcode::INC,
+            Some(Opcode::ADD) if rhs == dest && is_one(lhs) => Opcode::INC,
+            Some(Opcode::SUB) if lhs == dest && is_one(rhs) => Opcode::DEC,
+            _ => continue,
+        };
+
+        *i = AssemblerInstruction {
+            opcode: Some(Token::Op { code: replacement }),
+            label: i.label.take(),
+            directive: None,
+            operand1: Some(Token::Register { reg_num: dest }),
+            operand2: None,
+            operand3: None,
+        };
+        changed += 1;
+    }
+
+    changed
+}
+
+/// Points a `djmpe` whose target is another `djmpe` straight at the final destination, and turns
+/// a `jmp` to a `hlt` into a `hlt`.
+fn thread_jumps(p: &mut Program) -> usize {
+    let constants = constants_before(p);
+    let mut changed = 0;
+
+    for (index, known) in constants.iter().enumerate() {
+        let i = &p.instructions[index];
+        match opcode(i) {
+            Some(Opcode::DJMPE) => {
+                let target = match label(&i.operand1).and_then(|l| find_label(p, l)) {
+                    Some(target) => &p.instructions[target],
+                    None => continue,
+                };
+                if opcode(target) != Some(Opcode::DJMPE) || target.operand1 == i.operand1 {
+                    continue;
+                }
+                let destination = target.operand1.clone();
+                p.instructions[index].operand1 = destination;
+                changed += 1;
+            },
+            Some(Opcode::JMP) => {
+                let halts = match jump_target(i, known).and_then(|l| find_label(p, &l)) {
+                    Some(target) => opcode(&p.instructions[target]) == Some(Opcode::HLT),
+                    None => false,
+                };
+                if halts {
+                    let i = &mut p.instructions[index];
+                    i.opcode = Some(Token::Op { code: Opcode::HLT });
+                    i.operand1 = None;
+                    changed += 1;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    changed
+}
+
+/// Removes jumps whose target is the instruction immediately after them.
+fn remove_jumps_to_next(p: &mut Program) -> usize {
+    let constants = constants_before(p);
+    let mut candidates = vec![];
+
+    for (index, i) in p.instructions.iter().enumerate() {
+        let target = match opcode(i) {
+            Some(Opcode::DJMPE) => label(&i.operand1).map(|l| l.to_string()),
+            Some(Opcode::JMP) | Some(Opcode::JMPE) => jump_target(i, &constants[index]),
+            _ => None,
+        };
+        let next = p.instructions.get(index + 1);
+        if let (Some(target), Some(next)) = (target, next) {
+            if next.is_opcode() && next.get_label_name() == Some(target) {
+                candidates.push(index);
+            }
+        }
+    }
+
+    remove_all(p, candidates)
+}
+
+/// Removes `load`s whose value is overwritten before anything in the same basic block reads it.
+fn remove_dead_loads(p: &mut Program) -> usize {
+    let mut candidates = vec![];
+
+    for (index, i) in p.instructions.iter().enumerate() {
+        if opcode(i) != Some(Opcode::LOAD) {
+            continue;
+        }
+        let r = match register(&i.operand1) {
+            Some(r) => r,
+            None => continue,
+        };
+
+        for next in &p.instructions[index + 1..] {
+            if starts_block(next) || reads(next).contains(&r) {
+                break;
+            }
+            if writes(next) == Some(r) {
+                candidates.push(index);
+                break;
+            }
+            if ends_block(next) {
+                break;
+            }
+        }
+    }
+
+    remove_all(p, candidates)
+}
+
+fn remove_nops(p: &mut Program) -> usize {
+    let candidates = p.instructions.iter()
+        .enumerate()
+        .filter(|(_, i)| opcode(i) == Some(Opcode::NOP))
+        .map(|(index, _)| index)
+        .collect();
+    remove_all(p, candidates)
+}
+
+/// Removes code following a `hlt` or unconditional `jmp` that no label leads to.
+fn remove_unreachable(p: &mut Program) -> usize {
+    let mut candidates = vec![];
+    let mut reachable = true;
+
+    for (index, i) in p.instructions.iter().enumerate() {
+        if starts_block(i) {
+            reachable = true;
+        }
+        if !reachable {
+            candidates.push(index);
+        }
+        if let Some(Opcode::HLT) | Some(Opcode::JMP) = opcode(i) {
+            reachable = false;
+        }
+    }
+
+    remove_all(p, candidates)
+}
+
+/// Removes the instructions at the given indices. A label on a removed instruction moves to the
+/// following instruction; if that isn't possible the instruction is kept.
+fn remove_all(p: &mut Program, mut indices: Vec<usize>) -> usize {
+    // Work backwards so earlier indices stay valid and labels can cascade forwards
+    indices.sort_unstable();
+    let mut removed = 0;
+
+    for index in indices.into_iter().rev() {
+        if p.instructions[index].is_label() {
+            match p.instructions.get(index + 1) {
+                Some(next) if next.is_opcode() && !next.is_label() => {
+                    let label = p.instructions[index].label.take();
+                    p.instructions[index + 1].label = label;
+                },
+                _ => continue,
+            }
+        }
+        p.instructions.remove(index);
+        removed += 1;
+    }
+
+    removed
+}
+
+/// Works out the values registers are known to hold before each instruction. Knowledge is only
+/// tracked inside a basic block and is forgotten at every label.
+fn constants_before(p: &Program) -> Vec<Constants> {
+    let mut result = Vec::with_capacity(p.instructions.len());
+    let mut known: Constants = vec![None; REGISTER_COUNT];
+
+    for i in &p.instructions {
+        if starts_block(i) {
+            known = vec![None; REGISTER_COUNT];
+        }
+        result.push(known.clone());
+
+        if let Some(r) = writes(i) {
+            known[r as usize] = match (opcode(i), &i.operand2) {
+                (Some(Opcode::LOAD), Some(Token::IntegerOperand { value })) => Some(Value::Integer(*value as u16 as i32)),
+                (Some(Opcode::LOAD), Some(Token::LabelUsage { name })) => Some(Value::Label(name.clone())),
+                _ => None,
+            };
+        }
+        if ends_block(i) {
+            known = vec![None; REGISTER_COUNT];
+        }
+    }
+
+    result
+}
+
+/// The label a register-indirect jump is known to go to
+fn jump_target(i: &AssemblerInstruction, known: &[Option<Value>]) -> Option<String> {
+    match register(&i.operand1).and_then(|r| known[r as usize].clone()) {
+        Some(Value::Label(name)) => Some(name),
+        _ => None,
+    }
+}
+
+fn find_label(p: &Program, name: &str) -> Option<usize> {
+    p.instructions.iter().position(|i| i.is_opcode() && i.get_label_name().as_deref() == Some(name))
+}
+
+fn starts_block(i: &AssemblerInstruction) -> bool {
+    i.is_label() || i.is_directive()
+}
+
+fn ends_block(i: &AssemblerInstruction) -> bool {
+    match opcode(i) {
+        Some(op) => is_branch(op),
+        None => true,
+    }
+}
+
+fn is_branch(op: Opcode) -> bool {
+    matches!(op, Opcode::HLT | Opcode::IGL | Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JMPE | Opcode::DJMPE)
+}
+
+fn opcode(i: &AssemblerInstruction) -> Option<Opcode> {
+    match i.opcode {
+        Some(Token::Op { code }) => Some(code),
+        _ => None,
+    }
+}
+
+fn register(t: &Option<Token>) -> Option<u8> {
+    match t {
+        Some(Token::Register { reg_num }) => Some(*reg_num),
+        _ => None,
+    }
+}
+
+fn label(t: &Option<Token>) -> Option<&str> {
+    match t {
+        Some(Token::LabelUsage { name }) => Some(name),
+        _ => None,
+    }
+}
+
+/// The registers an instruction reads
+fn reads(i: &AssemblerInstruction) -> Vec<u8> {
+    let operands = match opcode(i) {
+        Some(Opcode::INC) | Some(Opcode::DEC) | Some(Opcode::JMP) | Some(Opcode::JMPF)
+        | Some(Opcode::JMPB) | Some(Opcode::JMPE) | Some(Opcode::ALOC) => vec![&i.operand1],
+        Some(Opcode::ADD) | Some(Opcode::SUB) | Some(Opcode::MUL) | Some(Opcode::DIV)
+        | Some(Opcode::EQ) | Some(Opcode::NEQ) | Some(Opcode::GT) | Some(Opcode::LT)
+        | Some(Opcode::GTE) | Some(Opcode::LTE) => vec![&i.operand1, &i.operand2],
+        _ => vec![],
+    };
+    operands.into_iter().filter_map(register).collect()
+}
+
+/// The register an instruction writes, if any
+fn writes(i: &AssemblerInstruction) -> Option<u8> {
+    match opcode(i) {
+        Some(Opcode::LOAD) | Some(Opcode::INC) | Some(Opcode::DEC) => register(&i.operand1),
+        Some(Opcode::ADD) | Some(Opcode::SUB) | Some(Opcode::MUL) | Some(Opcode::DIV) => register(&i.operand3),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parser::program;
+
+    fn optimized(source: &str) -> Program {
+        let (_, mut p) = program(source).unwrap();
+        optimize(&mut p);
+        p
+    }
+
+    fn opcodes(p: &Program) -> Vec<Opcode> {
+        p.instructions.iter().filter_map(opcode).collect()
+    }
+
+    #[test]
+    fn test_fold_increment() {
+        let p = optimized("load $1 #1\nadd $0 $1 $0\nload $1 #5\nhlt");
+        assert_eq!(opcodes(&p), vec![Opcode::INC, Opcode::LOAD, Opcode::HLT]);
+        assert_eq!(p.instructions[0].operand1, Some(Token::Register { reg_num: 0 }));
+    }
+
+    #[test]
+    fn test_fold_keeps_live_load() {
+        let p = optimized("load $1 #1\nsub $0 $1 $0\nhlt");
+        assert_eq!(opcodes(&p), vec![Opcode::LOAD, Opcode::DEC, Opcode::HLT]);
+    }
+
+    #[test]
+    fn test_no_fold_across_labels() {
+        let p = optimized("load $1 #1\nloop: add $0 $1 $0\nhlt");
+        assert_eq!(opcodes(&p), vec![Opcode::LOAD, Opcode::ADD, Opcode::HLT]);
+    }
+
+    #[test]
+    fn test_remove_nop_moves_label() {
+        let p = optimized("load $0 @here\njmp $0\nhere: nop\nhlt");
+        assert_eq!(opcodes(&p), vec![Opcode::LOAD, Opcode::HLT]);
+        assert_eq!(p.instructions[1].get_label_name(), Some("here".to_string()));
+    }
+
+    #[test]
+    fn test_remove_unreachable() {
+        let p = optimized("hlt\ninc $0\ninc $1\nlater: inc $2\nhlt");
+        assert_eq!(opcodes(&p), vec![Opcode::HLT, Opcode::INC, Opcode::HLT]);
+    }
+
+    #[test]
+    fn test_remove_jump_to_next() {
+        let p = optimized("eq $0 $1\ndjmpe @next\nnext: inc $0\nhlt");
+        assert_eq!(opcodes(&p), vec![Opcode::EQ, Opcode::INC, Opcode::HLT]);
+    }
+
+    #[test]
+    fn test_thread_jumps() {
+        let p = optimized("eq $0 $1\ndjmpe @first\ninc $0\nhlt\nfirst: djmpe @second\ninc $1\nsecond: hlt");
+        assert_eq!(p.instructions[1].operand1, Some(Token::LabelUsage { name: "second".to_string() }));
+
+        let p = optimized("load $3 @done\njmp $3\ninc $0\ndone: hlt");
+        assert_eq!(opcodes(&p), vec![Opcode::LOAD, Opcode::HLT, Opcode::HLT]);
+    }
+
+    #[test]
+    fn test_relative_jumps_prevent_removal() {
+        let p = optimized("load $0 #4\nnop\njmpf $0\nhlt\ninc $0");
+        assert_eq!(opcodes(&p), vec![Opcode::LOAD, Opcode::NOP, Opcode::JMPF, Opcode::HLT, Opcode::INC]);
+    }
+
+    #[test]
+    fn test_computed_jump_prevents_removal() {
+        let p = optimized("load $0 #69\nnop\njmp $0\nhlt");
+        assert_eq!(opcodes(&p), vec![Opcode::LOAD, Opcode::NOP, Opcode::JMP, Opcode::HLT]);
+    }
+
+    #[test]
+    fn test_computed_jump_prevents_folding() {
+        // The jump lands on the add, skipping the load that makes $1 hold 1
+        let p = optimized("load $1 #5\nload $2 #81\njmp $2\nload $1 #1\nadd $0 $1 $0\nhlt");
+        assert_eq!(opcodes(&p)[4], Opcode::ADD);
+    }
+}
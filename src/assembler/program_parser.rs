@@ -1,14 +1,16 @@
 use nom::{
     IResult,
+    error::ErrorKind,
     multi::many1,
 };
 
 use super::{
     instruction_parser::*,
+    comment_parsers::space_and_comments,
     SymbolTable,
 };
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Program {
     pub instructions: Vec<AssemblerInstruction>
 }
@@ -28,6 +30,32 @@ pub fn program(input: &str) -> IResult<&str, Program> {
     Ok((input, Program { instructions }))
 }
 
+/// Parses the same way as `program`, and also returns the line each instruction starts on,
+/// counting from 1
+pub fn program_with_lines(input: &str) -> IResult<&str, (Program, Vec<u32>)> {
+    let mut instructions = vec![];
+    let mut lines = vec![];
+    let mut rest = input;
+    loop {
+        match instruction(rest) {
+            // Like `many1`, stop once the parser can't make progress
+            Ok((remaining, i)) if remaining.len() < rest.len() => {
+                let start = space_and_comments(rest);
+                let offset = start.as_ptr() as usize - input.as_ptr() as usize;
+                lines.push(input[..offset].matches('\n').count() as u32 + 1);
+                instructions.push(i);
+                rest = remaining;
+            },
+            Ok(_) | Err(nom::Err::Error(_)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    if instructions.is_empty() {
+        return Err(nom::Err::Error((input, ErrorKind::Many1)));
+    }
+    Ok((rest, (Program { instructions }, lines)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -35,7 +63,7 @@ mod tests {
     #[test]
     fn test_parse_program() {
         let result = program("load $0 #100\n");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, prog) = result.unwrap();
         assert_eq!(rest, "");
         assert_eq!(prog.instructions.len(), 1);
@@ -52,6 +80,16 @@ mod tests {
         assert_eq!(bytecode, vec![1, 0, 0, 100]);
     }
 
+    #[test]
+    fn test_program_with_lines() {
+        let input = ".data\n\nhello: .asciiz 'Hi'\n.code\n  load $0 #1\n\n\nhlt\n";
+        let (rest, (with_lines, lines)) = program_with_lines(input).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(with_lines, program(input).unwrap().1);
+        assert_eq!(lines, vec![1, 3, 4, 5, 8]);
+        assert!(program_with_lines("").is_err());
+    }
+
     #[test]
     fn test_complete_program() {
         let input = ".data\nhello: .asciiz 'Hello everyone!'\n.code\nhlt";
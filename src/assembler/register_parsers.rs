@@ -2,12 +2,14 @@ use super::*;
 
 use nom::{
     IResult,
-    combinator::map_res,
+    combinator::map_opt,
     bytes::complete::tag,
-    character::complete::{digit1, multispace0},
+    character::complete::{alphanumeric1, multispace0},
     sequence::delimited,
 };
 
+use crate::vm::REGISTER_COUNT;
+
 // named!(register <&str, Token>,
 //     ws!(
 //         do_parse!(
@@ -22,15 +24,51 @@ use nom::{
 //     )
 // );
 
-fn parse_register(input: &str) -> Result<u8, std::num::ParseIntError> {
-    u8::from_str_radix(input, 10)
+/// ABI names for the general purpose registers. This table is the one place the aliases are
+/// defined; anything that needs to print or complete a register name should use it.
+///
+/// | Alias          | Register      | Intended use                       |
+/// |----------------|---------------|------------------------------------|
+/// | `$a0` - `$a3`  | `$0` - `$3`   | Arguments and return values        |
+/// | `$t0` - `$t11` | `$4` - `$15`  | Temporaries, not preserved         |
+/// | `$s0` - `$s11` | `$16` - `$27` | Saved values, preserved over calls |
+/// | `$gp`          | `$28`         | Global pointer                     |
+/// | `$sp`          | `$29`         | Stack pointer                      |
+/// | `$fp`          | `$30`         | Frame pointer                      |
+/// | `$ra`          | `$31`         | Return address                     |
+///
+/// Aliases are matched case-insensitively, so `$SP` and `$sp` are the same register.
+pub const REGISTER_ALIASES: [(&str, u8); REGISTER_COUNT] = [
+    ("a0", 0), ("a1", 1), ("a2", 2), ("a3", 3),
+    ("t0", 4), ("t1", 5), ("t2", 6), ("t3", 7),
+    ("t4", 8), ("t5", 9), ("t6", 10), ("t7", 11),
+    ("t8", 12), ("t9", 13), ("t10", 14), ("t11", 15),
+    ("s0", 16), ("s1", 17), ("s2", 18), ("s3", 19),
+    ("s4", 20), ("s5", 21), ("s6", 22), ("s7", 23),
+    ("s8", 24), ("s9", 25), ("s10", 26), ("s11", 27),
+    ("gp", 28), ("sp", 29), ("fp", 30), ("ra", 31),
+];
+
+/// Resolves the text following a `$` to a register number. Accepts either a register number
+/// below `REGISTER_COUNT` or one of the names in `REGISTER_ALIASES`.
+fn parse_register(input: &str) -> Option<u8> {
+    if input.bytes().all(|b| b.is_ascii_digit()) {
+        return match input.parse::<u8>() {
+            Ok(reg_num) if (reg_num as usize) < REGISTER_COUNT => Some(reg_num),
+            _ => None,
+        };
+    }
+
+    REGISTER_ALIASES.iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(input))
+        .map(|(_, reg_num)| *reg_num)
 }
 
 pub fn register(input: &str) -> IResult<&str, Token> {
     // Trim spaces surrounding the tag
     let (input, _) = delimited(multispace0, tag("$"), multispace0)(input)?;
-    // Get 1 or more digits and pass to parsing the number
-    let (input, reg_num) = map_res(digit1, parse_register)(input)?;
+    // Get the register number or alias and make sure it names a real register
+    let (input, reg_num) = map_opt(alphanumeric1, parse_register)(input)?;
     Ok((input, Token::Register {reg_num}))
 }
 
@@ -41,14 +79,42 @@ mod tests {
     #[test]
     fn test_parse_register() {
         let result = register("$0");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, value) = result.unwrap();
         assert_eq!(rest, "");
         assert_eq!(value, Token::Register {reg_num: 0});
 
         let result = register("0");
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
         let result = register("$a");
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_register_out_of_range() {
+        let result = register("$31");
+        assert_eq!(result, Ok(("", Token::Register {reg_num: 31})));
+
+        assert!(register("$32").is_err());
+        assert!(register("$200").is_err());
+        assert!(register("$256").is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_parse_register_alias() {
+        assert_eq!(register("$sp"), Ok(("", Token::Register {reg_num: 29})));
+        assert_eq!(register("$SP"), Ok(("", Token::Register {reg_num: 29})));
+        assert_eq!(register("$a0"), Ok(("", Token::Register {reg_num: 0})));
+        assert_eq!(register("$t0 #1"), Ok((" #1", Token::Register {reg_num: 4})));
+        assert_eq!(register("$ra"), Ok(("", Token::Register {reg_num: 31})));
+        assert!(register("$t12").is_err());
+    }
+
+    #[test]
+    fn test_register_aliases_are_unique() {
+        for (i, (alias, reg_num)) in REGISTER_ALIASES.iter().enumerate() {
+            assert_eq!(*reg_num as usize, i);
+            assert!(REGISTER_ALIASES.iter().filter(|(a, _)| a == alias).count() == 1);
+        }
+    }
+}
@@ -29,6 +29,18 @@ impl Symbol {
             offset: Some(offset)
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn offset(&self) -> Option<u32> {
+        self.offset
+    }
+
+    pub fn symbol_type(&self) -> &SymbolType {
+        &self.symbol_type
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -64,6 +76,19 @@ impl SymbolTable {
         false
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    /// Finds the code label closest to, but not after, `offset`. Useful for describing where
+    /// in the program an address is.
+    pub fn label_before(&self, offset: u32) -> Option<&Symbol> {
+        self.symbols.iter()
+            .filter(|s| s.symbol_type == SymbolType::Label)
+            .filter(|s| s.offset.map(|o| o <= offset).unwrap_or(false))
+            .max_by_key(|s| s.offset)
+    }
+
     pub fn symbol_value(&self, s: &str) -> Option<u32> {
         for symbol in &self.symbols {
             if symbol.name == s {
@@ -91,4 +116,15 @@ mod tests {
         let v = sym.symbol_value("does_not_exist");
         assert!(v.is_none());
     }
+
+    #[test]
+    fn test_label_before() {
+        let mut sym = SymbolTable::new();
+        sym.add_symbol(Symbol::new_with_offset("start".to_string(), SymbolType::Label, 65));
+        sym.add_symbol(Symbol::new_with_offset("loop".to_string(), SymbolType::Label, 73));
+        sym.add_symbol(Symbol::new_with_offset("text".to_string(), SymbolType::IrString, 70));
+        assert_eq!(sym.label_before(77).map(|s| s.name()), Some("loop"));
+        assert_eq!(sym.label_before(72).map(|s| s.name()), Some("start"));
+        assert!(sym.label_before(10).is_none());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,566 @@
+//! A Debug Adapter Protocol server, so `.iasm` programs can be debugged from an editor. The
+//! editor starts `iridium dap` and talks to it over stdin and stdout.
+//!
+//! Programs have a single thread with a single stack frame. Breakpoints are set by line and
+//! moved to the next line with an instruction, using the line table the assembler records.
+//! Each stop exposes three variable scopes: the registers, the flags (with the PC and any gas
+//! left) and the heap, sixteen bytes to a row.
+
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex, mpsc::{self, Receiver, TryRecvError}},
+    thread,
+};
+
+use log::{error, warn};
+use serde_json::{json, Value};
+
+use crate::assembler::{Assembler, debug_info::LineTable, register_parsers::REGISTER_ALIASES, symbols::SymbolTable};
+use crate::vm::{VM, ExitReason, REGISTER_COUNT};
+
+pub mod transport;
+
+const THREAD_ID: i64 = 1;
+const FRAME_ID: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+const FLAGS_REFERENCE: i64 = 2;
+const HEAP_REFERENCE: i64 = 3;
+/// Bytes shown in each row of the heap scope
+const HEAP_ROW_WIDTH: usize = 16;
+/// How many instructions run between checks for requests, such as `pause`, while running
+const RUN_CHUNK: usize = 10_000;
+
+/// Sends messages to the editor. The VM gets a copy so what the program prints can be sent as
+/// output events.
+#[derive(Clone)]
+struct Client(Arc<Mutex<Connection>>);
+
+struct Connection {
+    output: Box<dyn Write + Send>,
+    /// Sequence number of the last message sent
+    seq: i64,
+}
+
+impl Client {
+    fn send(&self, mut message: Value) {
+        let mut connection = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        connection.seq += 1;
+        message["seq"] = json!(connection.seq);
+        if let Err(e) = transport::write_message(&mut connection.output, &message) {
+            warn!("Unable to send a message to the editor: {}", e);
+        }
+    }
+
+    fn event(&self, event: &str, body: Value) {
+        self.send(json!({"type": "event", "event": event, "body": body}));
+    }
+}
+
+/// Passes what the program prints on to the editor
+struct ProgramOutput(Client);
+
+impl Write for ProgramOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.event("output", json!({"category": "stdout", "output": String::from_utf8_lossy(buf)}));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The program being debugged
+struct Target {
+    vm: VM,
+    path: PathBuf,
+    lines: LineTable,
+    symbols: SymbolTable,
+    /// Set once the program has finished, after which it can't be run any further
+    exited: bool,
+}
+
+pub struct DebugSession {
+    client: Client,
+    target: Option<Target>,
+    stop_on_entry: bool,
+    /// Whether the program is running, from a `continue` until it stops
+    running: bool,
+    /// Events to send once the response to the current request has gone
+    pending_events: Vec<(&'static str, Value)>,
+    /// Set when the editor disconnects
+    done: bool,
+}
+
+impl DebugSession {
+    /// Creates a session that sends its responses and events to `output`
+    pub fn new(output: Box<dyn Write + Send>) -> Self {
+        DebugSession {
+            client: Client(Arc::new(Mutex::new(Connection { output, seq: 0 }))),
+            target: None,
+            stop_on_entry: false,
+            running: false,
+            pending_events: vec![],
+            done: false,
+        }
+    }
+
+    /// Handles requests until the editor disconnects or `requests` is closed. While the program
+    /// runs it is stopped every so often to check for new requests.
+    pub fn run(&mut self, requests: Receiver<Value>) {
+        while !self.done {
+            let request = if self.running {
+                match requests.try_recv() {
+                    Ok(request) => Some(request),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            } else {
+                match requests.recv() {
+                    Ok(request) => Some(request),
+                    Err(_) => return,
+                }
+            };
+            if let Some(request) = request {
+                self.handle(&request);
+            }
+            if self.running && !self.done {
+                self.run_chunk();
+            }
+        }
+    }
+
+    fn handle(&mut self, request: &Value) {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let result = match command {
+            "initialize" => Ok(json!({"supportsConfigurationDoneRequest": true})),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(Value::Null),
+            "configurationDone" => self.configuration_done(),
+            "threads" => Ok(json!({"threads": [{"id": THREAD_ID, "name": "main"}]})),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(scopes()),
+            "variables" => self.variables(args),
+            "continue" => self.resume(),
+            // There are no calls to step into or out of
+            "next" | "stepIn" | "stepOut" => self.step(),
+            "pause" => self.pause(),
+            "disconnect" | "terminate" => {
+                self.done = true;
+                Ok(Value::Null)
+            },
+            _ => Err(format!("{} requests aren't supported", command)),
+        };
+
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => {},
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.client.send(response);
+        self.send_pending_events();
+    }
+
+    fn send_pending_events(&mut self) {
+        for (event, body) in self.pending_events.drain(..) {
+            self.client.event(event, body);
+        }
+    }
+
+    fn target(&mut self) -> Result<&mut Target, String> {
+        self.target.as_mut().ok_or_else(|| "No program has been launched".to_string())
+    }
+
+    /// Like `target`, for requests that run the program
+    fn stopped_target(&mut self) -> Result<&mut Target, String> {
+        if self.running {
+            return Err("The program is already running".to_string());
+        }
+        let target = self.target()?;
+        if target.exited {
+            return Err("The program has finished".to_string());
+        }
+        Ok(target)
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["program"].as_str().ok_or("launch needs the path of the program")?;
+        let source = fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+
+        let mut asm = Assembler::new();
+        // Lines are only recorded when the code matches the source line for line
+        asm.set_optimize(false);
+        let program = asm.assemble(&source).map_err(|errors| {
+            errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n")
+        })?;
+        let mut vm = VM::new();
+        vm.set_output(Box::new(ProgramOutput(self.client.clone())));
+        vm.add_bytes(program);
+        vm.add_ro_data(&asm.ro);
+
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.target = Some(Target {
+            vm,
+            path: canonical(path),
+            lines: asm.lines,
+            symbols: asm.symbols,
+            exited: false,
+        });
+        // Now there's a program, the editor can send its breakpoints
+        self.pending_events.push(("initialized", Value::Null));
+        Ok(Value::Null)
+    }
+
+    /// Replaces the breakpoints. Programs are a single file, so only breakpoints in the file
+    /// that was launched can be verified.
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let target = self.target()?;
+        let same_file = args["source"]["path"].as_str().map(canonical) == Some(target.path.clone());
+        let lines = args["breakpoints"].as_array().map(|breakpoints| {
+            breakpoints.iter().filter_map(|b| b["line"].as_u64()).collect::<Vec<_>>()
+        }).unwrap_or_default();
+
+        if same_file {
+            target.vm.clear_breakpoints();
+        }
+        let breakpoints: Vec<Value> = lines.into_iter().map(|line| {
+            let location = if same_file { target.lines.breakpoint_location(line as u32) } else { None };
+            match location {
+                Some((line, address)) => {
+                    target.vm.add_breakpoint(address as usize);
+                    json!({"verified": true, "line": line})
+                },
+                None => json!({"verified": false, "line": line, "message": "There is no instruction on or after this line"}),
+            }
+        }).collect();
+        Ok(json!({"breakpoints": breakpoints}))
+    }
+
+    fn configuration_done(&mut self) -> Result<Value, String> {
+        let stop_on_entry = self.stop_on_entry;
+        let target = self.stopped_target()?;
+        let pc = target.vm.pc();
+        if stop_on_entry {
+            self.pending_events.push(stopped("entry"));
+        } else if target.vm.breakpoints().contains(&pc) {
+            // Running would step over it
+            self.pending_events.push(stopped("breakpoint"));
+        } else {
+            self.running = true;
+        }
+        Ok(Value::Null)
+    }
+
+    fn resume(&mut self) -> Result<Value, String> {
+        self.stopped_target()?;
+        self.running = true;
+        Ok(json!({"allThreadsContinued": true}))
+    }
+
+    fn step(&mut self) -> Result<Value, String> {
+        let target = self.stopped_target()?;
+        match target.vm.step(1) {
+            Some(reason) => self.stop(reason),
+            None => self.pending_events.push(stopped("step")),
+        }
+        Ok(Value::Null)
+    }
+
+    fn pause(&mut self) -> Result<Value, String> {
+        if self.running {
+            self.running = false;
+            self.pending_events.push(stopped("pause"));
+        }
+        Ok(Value::Null)
+    }
+
+    /// Runs the program for a while, stopping early at a breakpoint or if it stops
+    fn run_chunk(&mut self) {
+        let vm = match self.target.as_mut() {
+            Some(target) => &mut target.vm,
+            None => return,
+        };
+        let reason = match vm.step(RUN_CHUNK) {
+            Some(reason) => reason,
+            // `step` starts by stepping over a breakpoint at the PC, so the next chunk would
+            // miss one this chunk ended on
+            None if vm.breakpoints().contains(&vm.pc()) => ExitReason::Breakpoint { pc: vm.pc() },
+            None => return,
+        };
+        self.running = false;
+        self.stop(reason);
+        self.send_pending_events();
+    }
+
+    /// Queues the events that tell the editor why the program stopped
+    fn stop(&mut self, reason: ExitReason) {
+        let exit_code = match reason {
+            ExitReason::Breakpoint { .. } => {
+                self.pending_events.push(stopped("breakpoint"));
+                return;
+            },
+            ExitReason::Watchpoint(_) => {
+                self.pending_events.push(stopped("data breakpoint"));
+                return;
+            },
+            ExitReason::Halted | ExitReason::EndOfProgram => 0,
+            ExitReason::InvalidHeader => {
+                self.pending_events.push(("output", json!({"category": "stderr", "output": format!("{}\n", reason)})));
+                1
+            },
+            ExitReason::IllegalInstruction | ExitReason::Fault(_) | ExitReason::OutOfGas => {
+                let (_, mut body) = stopped("exception");
+                body["description"] = json!(reason.to_string());
+                body["text"] = json!(reason.to_string());
+                self.pending_events.push(("stopped", body));
+                return;
+            },
+        };
+        if let Some(target) = self.target.as_mut() {
+            target.exited = true;
+        }
+        self.pending_events.push(("exited", json!({"exitCode": exit_code})));
+        self.pending_events.push(("terminated", json!({})));
+    }
+
+    fn stack_trace(&mut self) -> Result<Value, String> {
+        let target = self.target()?;
+        let pc = target.vm.pc();
+        let name = target.symbols.label_before(pc as u32)
+            .map(|symbol| symbol.name().to_string())
+            .unwrap_or_else(|| "main".to_string());
+        let file_name = target.path.file_name().map(|name| name.to_string_lossy().into_owned());
+        Ok(json!({
+            "stackFrames": [{
+                "id": FRAME_ID,
+                "name": name,
+                "source": {"name": file_name, "path": target.path},
+                // Line 0 means there is no source for the PC, e.g. once the program has halted
+                "line": target.lines.line_for_address(pc as u32).unwrap_or(0),
+                "column": 1,
+                "instructionPointerReference": pc.to_string(),
+            }],
+            "totalFrames": 1,
+        }))
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value, String> {
+        let vm = &self.target()?.vm;
+        let variables: Vec<Value> = match args["variablesReference"].as_i64() {
+            Some(REGISTERS_REFERENCE) => (0..REGISTER_COUNT).map(|n| {
+                let value = vm.registers[n];
+                variable(format!("${} ({})", n, REGISTER_ALIASES[n].0), format!("{} (0x{:08x})", value, value as u32))
+            }).collect(),
+            Some(FLAGS_REFERENCE) => {
+                let mut flags = vec![
+                    variable("pc".to_string(), vm.pc().to_string()),
+                    variable("equal_flag".to_string(), vm.equal_flag().to_string()),
+                    variable("remainder".to_string(), vm.remainder().to_string()),
+                ];
+                if let Some(gas) = vm.gas() {
+                    flags.push(variable("gas".to_string(), gas.to_string()));
+                }
+                flags
+            },
+            Some(HEAP_REFERENCE) => vm.heap().chunks(HEAP_ROW_WIDTH).enumerate().map(|(row, bytes)| {
+                let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                variable(format!("0x{:04x}", row * HEAP_ROW_WIDTH), hex.join(" "))
+            }).collect(),
+            _ => return Err("Unknown variables reference".to_string()),
+        };
+        Ok(json!({"variables": variables}))
+    }
+}
+
+/// Serves a debug session over stdin and stdout until the editor disconnects
+pub fn serve_stdio() {
+    serve(io::BufReader::new(io::stdin()), Box::new(io::stdout()));
+}
+
+/// Serves a debug session, reading requests from `input` on another thread so that they can
+/// arrive while the program runs
+pub fn serve<R: BufRead + Send + 'static>(mut input: R, output: Box<dyn Write + Send>) {
+    let (sender, requests) = mpsc::channel();
+    thread::spawn(move || {
+        loop {
+            match transport::read_message(&mut input) {
+                Ok(Some(request)) => {
+                    if sender.send(request).is_err() {
+                        return;
+                    }
+                },
+                Ok(None) => return,
+                Err(e) => {
+                    error!("Unable to read a request from the editor: {}", e);
+                    return;
+                }
+            }
+        }
+    });
+    DebugSession::new(output).run(requests);
+}
+
+fn stopped(reason: &str) -> (&'static str, Value) {
+    ("stopped", json!({"reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true}))
+}
+
+fn scopes() -> Value {
+    json!({"scopes": [
+        {"name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false},
+        {"name": "Flags", "variablesReference": FLAGS_REFERENCE, "expensive": false},
+        {"name": "Heap", "variablesReference": HEAP_REFERENCE, "expensive": false},
+    ]})
+}
+
+fn variable(name: String, value: String) -> Value {
+    json!({"name": name, "value": value, "variablesReference": 0})
+}
+
+/// Editors send absolute paths, which may not be how the program was named when launched
+fn canonical(path: &str) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Collects everything the session sends
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Runs a session over `requests`, numbering them, and returns the messages it sent
+    fn session(name: &str, source: &str, requests: Vec<Value>) -> Vec<Value> {
+        let path = std::env::temp_dir().join(format!("iridium-dap-test-{}-{}.iasm", std::process::id(), name));
+        fs::write(&path, source).unwrap();
+
+        let mut input = vec![];
+        for (seq, mut request) in requests.into_iter().enumerate() {
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            if request["command"] == "launch" {
+                request["arguments"]["program"] = json!(path);
+            }
+            if request["command"] == "setBreakpoints" {
+                request["arguments"]["source"] = json!({"path": path});
+            }
+            transport::write_message(&mut input, &request).unwrap();
+        }
+        let output = Output::default();
+        serve(Cursor::new(input), Box::new(output.clone()));
+        fs::remove_file(&path).unwrap();
+
+        let bytes = output.0.lock().unwrap().clone();
+        let mut bytes = &bytes[..];
+        let mut messages = vec![];
+        while let Some(message) = transport::read_message(&mut bytes).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn response(messages: &[Value], request_seq: i64) -> &Value {
+        messages.iter()
+            .find(|m| m["type"] == "response" && m["request_seq"] == request_seq)
+            .unwrap_or_else(|| panic!("No response to request {}", request_seq))
+    }
+
+    fn events<'a>(messages: &'a [Value], event: &str) -> Vec<&'a Value> {
+        messages.iter().filter(|m| m["type"] == "event" && m["event"] == event).map(|m| &m["body"]).collect()
+    }
+
+    const COUNTDOWN: &str = ".data\nmsg: .asciiz 'Hi'\n.code\nload $0 #3\nload $2 @loop\nloop: dec $0\nprts @msg\nneq $0 $1\njmpe $2\nhlt\n";
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        let messages = session("breakpoints", COUNTDOWN, vec![
+            json!({"command": "initialize", "arguments": {"adapterID": "iridium"}}),
+            json!({"command": "launch", "arguments": {}}),
+            json!({"command": "setBreakpoints", "arguments": {"breakpoints": [{"line": 6}, {"line": 11}]}}),
+            json!({"command": "configurationDone"}),
+            json!({"command": "stackTrace", "arguments": {"threadId": 1}}),
+            json!({"command": "variables", "arguments": {"variablesReference": REGISTERS_REFERENCE}}),
+            json!({"command": "continue", "arguments": {"threadId": 1}}),
+            json!({"command": "next", "arguments": {"threadId": 1}}),
+            json!({"command": "stackTrace", "arguments": {"threadId": 1}}),
+            json!({"command": "setBreakpoints", "arguments": {"breakpoints": []}}),
+            json!({"command": "continue", "arguments": {"threadId": 1}}),
+            json!({"command": "next", "arguments": {"threadId": 1}}),
+            json!({"command": "disconnect"}),
+        ]);
+
+        assert_eq!(response(&messages, 1)["body"]["supportsConfigurationDoneRequest"], true);
+        let launched = messages.iter().position(|m| m["request_seq"] == 2).unwrap();
+        assert_eq!(messages[launched + 1]["event"], "initialized");
+        assert_eq!(response(&messages, 3)["body"]["breakpoints"], json!([
+            {"verified": true, "line": 6},
+            {"verified": false, "line": 11, "message": "There is no instruction on or after this line"},
+        ]));
+
+        let frame = &response(&messages, 5)["body"]["stackFrames"][0];
+        assert_eq!(frame["line"], 6);
+        assert_eq!(frame["name"], "loop");
+        assert_eq!(response(&messages, 6)["body"]["variables"][0], json!({"name": "$0 (a0)", "value": "3 (0x00000003)", "variablesReference": 0}));
+        assert_eq!(response(&messages, 9)["body"]["stackFrames"][0]["line"], 7);
+        assert_eq!(response(&messages, 12)["success"], false);
+
+        let reasons: Vec<&Value> = events(&messages, "stopped").iter().map(|body| &body["reason"]).collect();
+        assert_eq!(reasons, vec!["breakpoint", "breakpoint", "step"]);
+        assert_eq!(events(&messages, "exited"), vec![&json!({"exitCode": 0})]);
+        assert_eq!(events(&messages, "terminated").len(), 1);
+        let output: String = events(&messages, "output").iter().filter_map(|body| body["output"].as_str()).collect();
+        assert!(output.starts_with("HiHiHi"));
+
+        let seqs: Vec<i64> = messages.iter().filter_map(|m| m["seq"].as_i64()).collect();
+        assert_eq!(seqs, (1..=messages.len() as i64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_pause_and_scopes() {
+        let messages = session("pause", ".data\n.code\nload $2 @loop\nloop: inc $0\njmp $2\n", vec![
+            json!({"command": "initialize", "arguments": {}}),
+            json!({"command": "launch", "arguments": {"stopOnEntry": true}}),
+            json!({"command": "configurationDone"}),
+            json!({"command": "scopes", "arguments": {"frameId": FRAME_ID}}),
+            json!({"command": "continue", "arguments": {"threadId": 1}}),
+            json!({"command": "pause", "arguments": {"threadId": 1}}),
+            json!({"command": "variables", "arguments": {"variablesReference": FLAGS_REFERENCE}}),
+            json!({"command": "variables", "arguments": {"variablesReference": HEAP_REFERENCE}}),
+            json!({"command": "evaluate", "arguments": {"expression": "$0"}}),
+            json!({"command": "disconnect"}),
+        ]);
+
+        let scopes = &response(&messages, 4)["body"]["scopes"];
+        assert_eq!(scopes.as_array().unwrap().len(), 3);
+        let reasons: Vec<&Value> = events(&messages, "stopped").iter().map(|body| &body["reason"]).collect();
+        assert_eq!(reasons, vec!["entry", "pause"]);
+        let flags = &response(&messages, 7)["body"]["variables"];
+        assert_eq!(flags[0]["name"], "pc");
+        assert_eq!(flags[1], json!({"name": "equal_flag", "value": "false", "variablesReference": 0}));
+        assert_eq!(response(&messages, 8)["body"]["variables"], json!([]));
+        assert_eq!(response(&messages, 9)["success"], false);
+        assert_eq!(response(&messages, 10)["success"], true);
+    }
+}
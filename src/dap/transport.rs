@@ -0,0 +1,61 @@
+//! The base protocol of the Debug Adapter Protocol: each message is a JSON body preceded by a
+//! `Content-Length` header and a blank line. The Language Server Protocol frames its messages
+//! the same way, so the language server uses this too.
+
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value;
+
+/// Reads the next message, or `None` at the end of the input
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let length = length.ok_or_else(|| invalid_data("message has no Content-Length".to_string()))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| invalid_data(format!("message isn't valid JSON: {}", e)))
+}
+
+pub fn write_message<W: Write>(output: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_round_trip() {
+        let message = json!({"seq": 1, "type": "request", "command": "initialize"});
+        let mut bytes = vec![];
+        write_message(&mut bytes, &message).unwrap();
+        assert!(bytes.starts_with(b"Content-Length: 49\r\n\r\n{"));
+
+        let mut input = &bytes[..];
+        assert_eq!(read_message(&mut input).unwrap(), Some(message));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+        assert!(read_message(&mut &b"Content-Type: text\r\n\r\n{}"[..]).is_err());
+    }
+}
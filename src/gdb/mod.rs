@@ -0,0 +1,548 @@
+//! A GDB Remote Serial Protocol server, so debuggers that speak it can drive a VM.
+//!
+//! The target has 35 registers, all 32 bits and sent big-endian like the VM's operands: `r0` to
+//! `r31`, then `pc`, `equal_flag` (0 or 1) and `remainder`. They are described to the debugger
+//! in `target.xml`. Memory is laid out as:
+//!
+//! | Addresses                | Contents                                  |
+//! |--------------------------|-------------------------------------------|
+//! | `0` up                   | The program, starting with its PIE header |
+//! | `RO_DATA_BASE` up        | Read-only data                            |
+//! | `HEAP_BASE` up           | The heap                                  |
+//!
+//! Only one debugger is served at a time.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use log::info;
+
+use crate::vm::{VM, ExitReason, REGISTER_COUNT};
+use crate::vm::faults::VmFault;
+
+pub mod packet;
+
+use self::packet::{Event, INTERRUPT};
+
+/// Address `iridium gdb` listens on unless told otherwise
+pub const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:2345";
+/// Where read-only data appears in the debugger's address space
+pub const RO_DATA_BASE: u64 = 0x4000_0000;
+/// Where the heap appears in the debugger's address space
+pub const HEAP_BASE: u64 = 0x8000_0000;
+
+const PC_REGISTER: usize = REGISTER_COUNT;
+const EQUAL_FLAG_REGISTER: usize = REGISTER_COUNT + 1;
+const REMAINDER_REGISTER: usize = REGISTER_COUNT + 2;
+const TARGET_REGISTER_COUNT: usize = REGISTER_COUNT + 3;
+
+/// How many instructions run between checks for an interrupt from the debugger
+const CONTINUE_CHUNK: usize = 10_000;
+/// Largest packet we accept, advertised to the debugger
+const PACKET_SIZE: usize = 0x1000;
+
+/// What to do after handling a packet
+enum Response {
+    Reply(String),
+    ReplyAndClose(String),
+    Close,
+}
+
+/// Answers debugger requests about a VM
+pub struct GdbStub {
+    vm: VM,
+    /// Whether packets are still acknowledged with `+`
+    acks: bool,
+    /// The stop reply for the last time the VM stopped, sent again when asked with `?`
+    last_stop: String,
+}
+
+impl GdbStub {
+    pub fn new(vm: VM) -> Self {
+        GdbStub { vm, acks: true, last_stop: "S05".to_string() }
+    }
+
+    /// Hands back the VM once the debugger is done with it
+    pub fn into_vm(self) -> VM {
+        self.vm
+    }
+
+    /// Serves one debugger over `stream` until it detaches, kills the target or disconnects
+    pub fn run_session(&mut self, stream: TcpStream) -> io::Result<()> {
+        // Packets are small and each one waits on the last, so don't hold them back to batch
+        stream.set_nodelay(true)?;
+        let mut input = BufReader::new(stream.try_clone()?);
+        let mut output = stream;
+        let mut last_packet = vec![];
+
+        while let Some(event) = packet::read_event(&mut input)? {
+            let packet = match event {
+                Event::Packet(packet) => packet,
+                Event::Corrupt => {
+                    if self.acks {
+                        output.write_all(b"-")?;
+                    }
+                    continue;
+                },
+                Event::Ack(false) => {
+                    output.write_all(&last_packet)?;
+                    continue;
+                },
+                // Nothing is running between packets, so there is nothing to interrupt
+                Event::Ack(true) | Event::Interrupt => continue,
+            };
+            if self.acks {
+                output.write_all(b"+")?;
+            }
+
+            let request = String::from_utf8_lossy(&packet);
+            let (reply, close) = match self.handle(&request, &mut || interrupted(&mut input)) {
+                Response::Reply(reply) => (reply, false),
+                Response::ReplyAndClose(reply) => (reply, true),
+                Response::Close => return Ok(()),
+            };
+            last_packet = packet::encode(reply.as_bytes());
+            output.write_all(&last_packet)?;
+            if request == "QStartNoAckMode" {
+                self.acks = false;
+            }
+            if close {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, request: &str, interrupted: &mut dyn FnMut() -> bool) -> Response {
+        let reply = match request.split_at(request.len().min(1)) {
+            ("?", _) => self.last_stop.clone(),
+            ("g", _) => self.read_registers(),
+            ("G", values) => reply_ok(self.write_registers(values)),
+            ("p", number) => self.read_register(number).unwrap_or_else(|| "E01".to_string()),
+            ("P", assignment) => reply_ok(self.write_register(assignment)),
+            ("m", range) => self.read_memory(range).unwrap_or_else(|| "E01".to_string()),
+            ("M", write) => reply_ok(self.write_memory(write)),
+            ("Z", breakpoint) => self.breakpoint(breakpoint, true),
+            ("z", breakpoint) => self.breakpoint(breakpoint, false),
+            ("s", address) => {
+                self.jump(address);
+                let reason = self.vm.step(1);
+                self.stopped(stop_reply(reason))
+            },
+            ("c", address) => {
+                self.jump(address);
+                let reply = self.continue_running(interrupted);
+                self.stopped(reply)
+            },
+            ("H", _) | ("T", _) => "OK".to_string(),
+            ("D", _) => return Response::ReplyAndClose("OK".to_string()),
+            ("k", _) => return Response::Close,
+            _ => self.query(request),
+        };
+        Response::Reply(reply)
+    }
+
+    /// Handles the `q` and `Q` packets we understand. The rest get an empty reply, which tells
+    /// the debugger they aren't supported.
+    fn query(&self, request: &str) -> String {
+        if request.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE);
+        }
+        if let Some(range) = request.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_range(range) {
+                Some((offset, length)) => read_chunk(target_description().as_bytes(), offset as usize, length),
+                None => "E01".to_string(),
+            };
+        }
+        match request {
+            "QStartNoAckMode" => "OK",
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            _ => "",
+        }.to_string()
+    }
+
+    fn stopped(&mut self, reply: String) -> String {
+        self.last_stop = reply.clone();
+        reply
+    }
+
+    /// Runs until the program stops, reaches a breakpoint or the debugger interrupts it
+    fn continue_running(&mut self, interrupted: &mut dyn FnMut() -> bool) -> String {
+        loop {
+            if let Some(reason) = self.vm.step(CONTINUE_CHUNK) {
+                return stop_reply(Some(reason));
+            }
+            // `step` starts by stepping over a breakpoint at the PC, so the next chunk would miss
+            // one that this chunk ended on
+            if self.vm.breakpoints().contains(&self.vm.pc()) {
+                return stop_reply(Some(ExitReason::Breakpoint { pc: self.vm.pc() }));
+            }
+            if interrupted() {
+                return "S02".to_string();
+            }
+        }
+    }
+
+    /// Moves the PC for `s` and `c` packets that say where to resume
+    fn jump(&mut self, address: &str) {
+        if let Ok(pc) = usize::from_str_radix(address, 16) {
+            self.vm.set_pc(pc);
+        }
+    }
+
+    fn register_value(&self, number: usize) -> Option<u32> {
+        match number {
+            n if n < REGISTER_COUNT => Some(self.vm.registers[n] as u32),
+            PC_REGISTER => Some(self.vm.pc() as u32),
+            EQUAL_FLAG_REGISTER => Some(self.vm.equal_flag() as u32),
+            REMAINDER_REGISTER => Some(self.vm.remainder()),
+            _ => None,
+        }
+    }
+
+    fn set_register_value(&mut self, number: usize, value: u32) -> bool {
+        match number {
+            n if n < REGISTER_COUNT => self.vm.registers[n] = value as i32,
+            PC_REGISTER => self.vm.set_pc(value as usize),
+            EQUAL_FLAG_REGISTER => self.vm.set_equal_flag(value != 0),
+            REMAINDER_REGISTER => self.vm.set_remainder(value),
+            _ => return false,
+        }
+        true
+    }
+
+    fn read_registers(&self) -> String {
+        (0..TARGET_REGISTER_COUNT)
+            .filter_map(|n| self.register_value(n))
+            .map(|value| packet::to_hex(&value.to_be_bytes()))
+            .collect()
+    }
+
+    fn write_registers(&mut self, values: &str) -> bool {
+        let bytes = match packet::from_hex(values) {
+            Some(bytes) if bytes.len() == TARGET_REGISTER_COUNT * 4 => bytes,
+            _ => return false,
+        };
+        for (n, value) in bytes.chunks(4).enumerate() {
+            self.set_register_value(n, u32::from_be_bytes([value[0], value[1], value[2], value[3]]));
+        }
+        true
+    }
+
+    fn read_register(&self, number: &str) -> Option<String> {
+        let number = usize::from_str_radix(number, 16).ok()?;
+        self.register_value(number).map(|value| packet::to_hex(&value.to_be_bytes()))
+    }
+
+    fn write_register(&mut self, assignment: &str) -> bool {
+        let (number, value) = match assignment.split_once('=') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let number = usize::from_str_radix(number, 16).ok();
+        let value = packet::from_hex(value).filter(|bytes| bytes.len() == 4);
+        match (number, value) {
+            (Some(number), Some(bytes)) => self.set_register_value(number, u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            _ => false,
+        }
+    }
+
+    /// The memory `address` falls in and where it is within that memory
+    fn region(&self, address: u64) -> (Region, usize) {
+        if address >= HEAP_BASE {
+            (Region::Heap, (address - HEAP_BASE) as usize)
+        } else if address >= RO_DATA_BASE {
+            (Region::ReadOnly, (address - RO_DATA_BASE) as usize)
+        } else {
+            (Region::Program, address as usize)
+        }
+    }
+
+    fn read_memory(&self, range: &str) -> Option<String> {
+        let (address, length) = parse_range(range)?;
+        let (region, offset) = self.region(address);
+        let memory = match region {
+            Region::Program => self.vm.program(),
+            Region::ReadOnly => self.vm.ro_data(),
+            Region::Heap => self.vm.heap(),
+        };
+        // Reading fewer bytes than asked for is allowed, but not none at all
+        let end = offset.saturating_add(length).min(memory.len());
+        memory.get(offset..end).filter(|bytes| !bytes.is_empty() || length == 0).map(packet::to_hex)
+    }
+
+    fn write_memory(&mut self, write: &str) -> bool {
+        let (range, data) = match write.split_once(':') {
+            Some(parts) => parts,
+            None => return false,
+        };
+        let (address, bytes) = match (parse_range(range), packet::from_hex(data)) {
+            (Some((address, length)), Some(bytes)) if bytes.len() == length => (address, bytes),
+            _ => return false,
+        };
+        let (region, offset) = self.region(address);
+        match region {
+            Region::Program => self.vm.write_program(offset, &bytes),
+            Region::ReadOnly => self.vm.write_ro_data(offset, &bytes),
+            Region::Heap => self.vm.write_heap(offset, &bytes),
+        }
+    }
+
+    /// Handles `Z` and `z` packets. Only software breakpoints (type 0) are supported, and they
+    /// use the VM's own breakpoints rather than patching the program.
+    fn breakpoint(&mut self, request: &str, insert: bool) -> String {
+        let mut fields = request.split(',');
+        let address = match (fields.next(), fields.next()) {
+            (Some("0"), Some(address)) => usize::from_str_radix(address, 16).ok(),
+            _ => return String::new(),
+        };
+        match address {
+            Some(address) => {
+                if insert {
+                    self.vm.add_breakpoint(address);
+                } else {
+                    self.vm.remove_breakpoint(address);
+                }
+                "OK".to_string()
+            },
+            None => "E01".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Region {
+    Program,
+    ReadOnly,
+    Heap,
+}
+
+/// Waits on `address` for a debugger and serves it. Returns the VM once the debugger is done.
+pub fn serve(vm: VM, address: &str) -> io::Result<VM> {
+    let listener = TcpListener::bind(address)?;
+    info!("Waiting for a debugger on {}", listener.local_addr()?);
+    serve_on(listener, vm)
+}
+
+/// Serves the first debugger to connect to `listener`
+pub fn serve_on(listener: TcpListener, vm: VM) -> io::Result<VM> {
+    let (stream, peer) = listener.accept()?;
+    info!("Debugger connected from {}", peer);
+    let mut stub = GdbStub::new(vm);
+    stub.run_session(stream)?;
+    info!("Debugger disconnected");
+    Ok(stub.into_vm())
+}
+
+/// Checks, without waiting, whether the debugger has sent an interrupt
+fn interrupted(input: &mut BufReader<TcpStream>) -> bool {
+    if input.buffer().is_empty() {
+        if input.get_ref().set_nonblocking(true).is_err() {
+            return false;
+        }
+        let read = input.fill_buf().map(|bytes| !bytes.is_empty());
+        let _ = input.get_ref().set_nonblocking(false);
+        if !read.unwrap_or(false) {
+            return false;
+        }
+    }
+    if input.buffer().first() == Some(&INTERRUPT) {
+        input.consume(1);
+        return true;
+    }
+    false
+}
+
+fn reply_ok(ok: bool) -> String {
+    if ok { "OK" } else { "E01" }.to_string()
+}
+
+/// Parses the `address,length` in hex that memory packets use
+fn parse_range(range: &str) -> Option<(u64, usize)> {
+    let (address, length) = range.split_once(',')?;
+    Some((u64::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+/// Replies to a `qXfer` read: `l` and the data if it reaches the end, otherwise `m` and a chunk
+fn read_chunk(data: &[u8], offset: usize, length: usize) -> String {
+    let start = offset.min(data.len());
+    let end = offset.saturating_add(length).min(data.len());
+    let marker = if end == data.len() { 'l' } else { 'm' };
+    format!("{}{}", marker, String::from_utf8_lossy(&data[start..end]))
+}
+
+/// Tells the stop reason to the debugger as the signal a process would have received
+fn stop_reply(reason: Option<ExitReason>) -> String {
+    match reason {
+        None | Some(ExitReason::Breakpoint { .. }) | Some(ExitReason::Watchpoint(_)) => "S05",
+        Some(ExitReason::Halted) | Some(ExitReason::EndOfProgram) => "W00",
+        Some(ExitReason::InvalidHeader) => "W01",
+        Some(ExitReason::IllegalInstruction) => "S04",
+        Some(ExitReason::Fault(VmFault::DivideByZero)) => "S08",
+        Some(ExitReason::Fault(_)) => "S0b",
+        Some(ExitReason::OutOfGas) => "S18",
+    }.to_string()
+}
+
+/// The `target.xml` describing the registers
+fn target_description() -> String {
+    let mut registers = String::new();
+    for n in 0..REGISTER_COUNT {
+        registers.push_str(&format!("    <reg name=\"r{}\" bitsize=\"32\" type=\"int32\" regnum=\"{}\"/>\n", n, n));
+    }
+    format!("<?xml version=\"1.0\"?>\n\
+             <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+             <target version=\"1.0\">\n\
+             \x20 <feature name=\"org.iridium.core\">\n\
+             {}\
+             \x20   <reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>\n\
+             \x20   <reg name=\"equal_flag\" bitsize=\"32\" type=\"uint32\" regnum=\"{}\"/>\n\
+             \x20   <reg name=\"remainder\" bitsize=\"32\" type=\"uint32\" regnum=\"{}\"/>\n\
+             \x20 </feature>\n\
+             </target>\n",
+        registers, PC_REGISTER, EQUAL_FLAG_REGISTER, REMAINDER_REGISTER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::assembler::Assembler;
+
+    /// A debugger that sends packets and checks the replies are framed and acknowledged
+    struct Client {
+        input: BufReader<TcpStream>,
+        output: TcpStream,
+        acks: bool,
+    }
+
+    impl Client {
+        fn start(vm: VM) -> (Client, thread::JoinHandle<VM>) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = thread::spawn(move || serve_on(listener, vm).unwrap());
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let client = Client { input: BufReader::new(stream.try_clone().unwrap()), output: stream, acks: true };
+            (client, server)
+        }
+
+        fn send(&mut self, request: &str) {
+            self.output.write_all(&packet::encode(request.as_bytes())).unwrap();
+            if self.acks {
+                assert_eq!(packet::read_event(&mut self.input).unwrap(), Some(Event::Ack(true)));
+            }
+        }
+
+        fn reply(&mut self) -> String {
+            match packet::read_event(&mut self.input).unwrap() {
+                Some(Event::Packet(reply)) => {
+                    if self.acks {
+                        self.output.write_all(b"+").unwrap();
+                    }
+                    String::from_utf8(reply).unwrap()
+                },
+                other => panic!("Expected a reply, got {:?}", other),
+            }
+        }
+
+        fn request(&mut self, request: &str) -> String {
+            self.send(request);
+            self.reply()
+        }
+    }
+
+    fn assemble(source: &str) -> VM {
+        let mut asm = Assembler::new();
+        asm.set_optimize(false);
+        let program = asm.assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.add_ro_data(&asm.ro);
+        vm
+    }
+
+    /// Counts $0 down from 3. The loop starts at 73 (0x49).
+    fn countdown_vm() -> VM {
+        assemble(".data\nmsg: .asciiz 'Hi'\n.code\nload $0 #3\nload $2 @loop\nloop: dec $0\nneq $0 $1\njmpe $2\nhlt")
+    }
+
+    #[test]
+    fn test_breakpoints_and_continue() {
+        let (mut client, server) = Client::start(countdown_vm());
+        assert!(client.request("qSupported:swbreak+").contains("qXfer:features:read+"));
+        assert_eq!(client.request("?"), "S05");
+        let description = client.request("qXfer:features:read:target.xml:0,fff");
+        assert!(description.starts_with("l<?xml"));
+        assert!(description.contains("<reg name=\"remainder\" bitsize=\"32\" type=\"uint32\" regnum=\"34\"/>"));
+        assert_eq!(client.request("qXfer:features:read:target.xml:0,10"), "m<?xml version=\"1");
+
+        assert_eq!(client.request("Z0,49,4"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p20"), "00000049");
+        assert_eq!(client.request("p0"), "00000003");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p0"), "00000002");
+        assert_eq!(client.request("z0,49,4"), "OK");
+        assert_eq!(client.request("Z1,49,4"), "");
+        assert_eq!(client.request("c"), "W00");
+        assert_eq!(client.request("?"), "W00");
+        assert_eq!(client.request("D"), "OK");
+
+        let vm = server.join().unwrap();
+        assert_eq!(vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let (mut client, server) = Client::start(countdown_vm());
+        let registers = client.request("g");
+        assert_eq!(registers.len(), TARGET_REGISTER_COUNT * 8);
+        assert_eq!(registers[PC_REGISTER * 8..][..8], *"00000041");
+
+        assert_eq!(client.request("P1=fffffffe"), "OK");
+        assert_eq!(client.request("P21=00000001"), "OK");
+        assert_eq!(client.request("P40=00000001"), "E01");
+        let registers = client.request("g");
+        assert_eq!(registers[8..16], *"fffffffe");
+        assert_eq!(client.request(&format!("G{}", registers.replacen("fffffffe", "00000007", 1))), "OK");
+        assert_eq!(client.request("p1"), "00000007");
+
+        assert_eq!(client.request("m0,4"), "2d32312d");
+        assert_eq!(client.request("m41,4"), "01000003");
+        assert_eq!(client.request("M41,4:01000001"), "OK");
+        assert_eq!(client.request("M1000,1:00"), "E01");
+        assert_eq!(client.request("m40000000,10"), "486900");
+        assert_eq!(client.request("M40000001,1:6f"), "OK");
+        assert_eq!(client.request("m80000000,4"), "E01");
+
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p0"), "00000001");
+
+        assert_eq!(client.request("QStartNoAckMode"), "OK");
+        client.acks = false;
+        assert_eq!(client.request("p20"), "00000045");
+        client.send("k");
+
+        let vm = server.join().unwrap();
+        assert_eq!(vm.registers[1], 7);
+        assert!(vm.equal_flag());
+        assert_eq!(vm.ro_data(), b"Ho\0");
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let (mut client, server) = Client::start(assemble(".data\n.code\nload $2 @loop\nloop: inc $0\njmp $2"));
+        client.send("c");
+        client.output.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(client.reply(), "S02");
+        assert_eq!(client.request("?"), "S02");
+        client.send("k");
+        assert!(server.join().unwrap().registers[0] > 0);
+    }
+}
@@ -0,0 +1,146 @@
+//! Framing for the GDB Remote Serial Protocol. A packet is `$payload#cs`, where `cs` is the sum
+//! of the payload's bytes modulo 256 as two hex digits. `#`, `$`, `}` and `*` in a payload are
+//! escaped as `}` followed by the byte XORed with 0x20.
+
+use std::io::{self, BufRead};
+
+const ESCAPE: u8 = b'}';
+/// Sent on its own, outside a packet, to interrupt a running target
+pub const INTERRUPT: u8 = 0x03;
+
+/// Something read from the client
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// A packet whose checksum was correct, unescaped
+    Packet(Vec<u8>),
+    /// A packet whose checksum was wrong. The client will send it again after a `-`.
+    Corrupt,
+    /// Ctrl-C from the user
+    Interrupt,
+    /// The client acknowledged our last packet with `+`, or asked for it again with `-`
+    Ack(bool),
+}
+
+/// Frames `payload` as a packet, escaping it as needed
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(payload.len());
+    for &byte in payload {
+        if matches!(byte, b'#' | b'$' | b'}' | b'*') {
+            body.push(ESCAPE);
+            body.push(byte ^ 0x20);
+        } else {
+            body.push(byte);
+        }
+    }
+    let mut packet = Vec::with_capacity(body.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(&body);
+    packet.extend_from_slice(format!("#{:02x}", checksum(&body)).as_bytes());
+    packet
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// Reads the next event, or `None` at the end of the input. Anything between packets that isn't
+/// an acknowledgement or an interrupt is skipped.
+pub fn read_event<R: BufRead>(input: &mut R) -> io::Result<Option<Event>> {
+    loop {
+        let byte = match read_byte(input)? {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+        match byte {
+            b'+' => return Ok(Some(Event::Ack(true))),
+            b'-' => return Ok(Some(Event::Ack(false))),
+            INTERRUPT => return Ok(Some(Event::Interrupt)),
+            b'$' => return read_packet(input).map(Some),
+            _ => continue,
+        }
+    }
+}
+
+/// Reads the rest of a packet after its `$`
+fn read_packet<R: BufRead>(input: &mut R) -> io::Result<Event> {
+    let mut body = vec![];
+    input.read_until(b'#', &mut body)?;
+    if body.pop() != Some(b'#') {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "packet ended early"));
+    }
+    let mut digits = [0; 2];
+    input.read_exact(&mut digits)?;
+    let expected = std::str::from_utf8(&digits).ok().and_then(|digits| u8::from_str_radix(digits, 16).ok());
+    if expected != Some(checksum(&body)) {
+        return Ok(Event::Corrupt);
+    }
+
+    let mut payload = Vec::with_capacity(body.len());
+    let mut bytes = body.into_iter();
+    while let Some(byte) = bytes.next() {
+        if byte == ESCAPE {
+            if let Some(escaped) = bytes.next() {
+                payload.push(escaped ^ 0x20);
+            }
+        } else {
+            payload.push(byte);
+        }
+    }
+    Ok(Event::Packet(payload))
+}
+
+fn read_byte<R: BufRead>(input: &mut R) -> io::Result<Option<u8>> {
+    let byte = input.fill_buf()?.first().copied();
+    if byte.is_some() {
+        input.consume(1);
+    }
+    Ok(byte)
+}
+
+/// Encodes bytes as pairs of lowercase hex digits
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes pairs of hex digits, returning `None` if there's an odd number or a non-hex digit
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(encode(b"OK"), b"$OK#9a".to_vec());
+        let payload = b"a}b#c$d*".to_vec();
+        let packet = encode(&payload);
+        assert_eq!(packet[..13], *b"$a}]b}\x03c}\x04d}\x0a");
+        assert_eq!(read_event(&mut &packet[..]).unwrap(), Some(Event::Packet(payload)));
+    }
+
+    #[test]
+    fn test_read_events() {
+        let mut input = &b"+$g#67-\x03$m0,4#00junk"[..];
+        assert_eq!(read_event(&mut input).unwrap(), Some(Event::Ack(true)));
+        assert_eq!(read_event(&mut input).unwrap(), Some(Event::Packet(b"g".to_vec())));
+        assert_eq!(read_event(&mut input).unwrap(), Some(Event::Ack(false)));
+        assert_eq!(read_event(&mut input).unwrap(), Some(Event::Interrupt));
+        assert_eq!(read_event(&mut input).unwrap(), Some(Event::Corrupt));
+        assert_eq!(read_event(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0, 0xab, 0x10]), "00ab10");
+        assert_eq!(from_hex("00AB10"), Some(vec![0, 0xab, 0x10]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Opcode {
     HLT,
@@ -56,45 +58,158 @@ impl From<u8> for Opcode {
     }
 }
 
+/// Number of `Opcode` variants, including `IGL`
+pub const OPCODE_COUNT: usize = Opcode::IGL as usize + 1;
+
+/// Assembly mnemonics for every opcode the assembler accepts. Each entry is spelled exactly
+/// like its `Opcode` variant (lowercased); matching against this table is case-insensitive.
+pub const MNEMONICS: [(&str, Opcode); 22] = [
+    ("hlt", Opcode::HLT),
+    ("load", Opcode::LOAD),
+    ("inc", Opcode::INC),
+    ("dec", Opcode::DEC),
+    ("add", Opcode::ADD),
+    ("sub", Opcode::SUB),
+    ("mul", Opcode::MUL),
+    ("div", Opcode::DIV),
+    ("jmp", Opcode::JMP),
+    ("jmpf", Opcode::JMPF),
+    ("jmpb", Opcode::JMPB),
+    ("eq", Opcode::EQ),
+    ("neq", Opcode::NEQ),
+    ("gt", Opcode::GT),
+    ("lt", Opcode::LT),
+    ("gte", Opcode::GTE),
+    ("lte", Opcode::LTE),
+    ("jmpe", Opcode::JMPE),
+    ("djmpe", Opcode::DJMPE),
+    ("aloc", Opcode::ALOC),
+    ("prts", Opcode::PRTS),
+    ("nop", Opcode::NOP),
+];
+
+impl Opcode {
+    /// The mnemonic used for this opcode in assembly, `igl` for anything not in `MNEMONICS`
+    pub fn mnemonic(self) -> &'static str {
+        for (mnemonic, opcode) in MNEMONICS.iter() {
+            if *opcode == self {
+                return mnemonic;
+            }
+        }
+        "igl"
+    }
+
+    /// The operands this opcode takes in assembly, e.g. `$register #number` for `load`
+    pub fn operands(self) -> &'static str {
+        match self {
+            Opcode::HLT | Opcode::NOP | Opcode::IGL => "",
+            Opcode::LOAD => "$register #number",
+            Opcode::INC | Opcode::DEC | Opcode::ALOC => "$register",
+            Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JMPE => "$register",
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => "$a $b $result",
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTE | Opcode::LTE => "$a $b",
+            Opcode::DJMPE => "#address",
+            Opcode::PRTS => "@string",
+        }
+    }
+
+    /// What the opcode does, in a sentence
+    pub fn description(self) -> &'static str {
+        match self {
+            Opcode::HLT => "Stops the program",
+            Opcode::LOAD => "Loads a 16-bit number, or the address of a label, into a register",
+            Opcode::INC => "Adds 1 to a register",
+            Opcode::DEC => "Subtracts 1 from a register",
+            Opcode::ADD => "Stores $a + $b in $result",
+            Opcode::SUB => "Stores $a - $b in $result",
+            Opcode::MUL => "Stores $a * $b in $result",
+            Opcode::DIV => "Stores $a / $b in $result and the remainder in the remainder register. Faults if $b is 0",
+            Opcode::JMP => "Jumps to the address in a register",
+            Opcode::JMPF => "Jumps forward by the number of bytes in a register, counted from just after the register operand",
+            Opcode::JMPB => "Jumps back by the number of bytes in a register, counted from just after the register operand",
+            Opcode::EQ => "Sets the equal flag if $a == $b and clears it otherwise",
+            Opcode::NEQ => "Sets the equal flag if $a != $b and clears it otherwise",
+            Opcode::GT => "Sets the equal flag if $a > $b and clears it otherwise",
+            Opcode::LT => "Sets the equal flag if $a < $b and clears it otherwise",
+            Opcode::GTE => "Sets the equal flag if $a >= $b and clears it otherwise",
+            Opcode::LTE => "Sets the equal flag if $a <= $b and clears it otherwise",
+            Opcode::JMPE => "Jumps to the address in a register if the equal flag is set",
+            Opcode::DJMPE => "Jumps to an address if the equal flag is set",
+            Opcode::ALOC => "Grows the heap by the number of bytes in a register",
+            Opcode::PRTS => "Prints the null-terminated string at an offset in the read-only section",
+            Opcode::NOP => "Does nothing",
+            Opcode::IGL => "An illegal instruction, which stops the program",
+        }
+    }
+}
+
 impl From<&str> for Opcode {
     fn from(v: &str) -> Self {
-        match v {
-            "hlt"  => Opcode::HLT,
-            "load" => Opcode::LOAD,
-            "inc"  => Opcode::INC,
-            "dec"  => Opcode::DEC,
-            "add"  => Opcode::ADD,
-            "sub"  => Opcode::SUB,
-            "mul"  => Opcode::MUL,
-            "div"  => Opcode::DIV,
-            "jmp"  => Opcode::JMP,
-            "jmpf"  => Opcode::JMPF,
-            "jmpb"  => Opcode::JMPB,
-            "eq"  => Opcode::EQ,
-            "neq" => Opcode::NEQ,
-            "gt" => Opcode::GT,
-            "lt" => Opcode::LT,
-            "gte" => Opcode::GTE,
-            "lte" => Opcode::LTE,
-            "jmpe" => Opcode::JMPE,
-            "djmpe" => Opcode::DJMPE,
-            "aloc" => Opcode::ALOC,
-            "ptrs" => Opcode::PRTS,
-            "nop" => Opcode::NOP,
-            _ => Opcode::IGL
+        for (mnemonic, opcode) in MNEMONICS.iter() {
+            if mnemonic.eq_ignore_ascii_case(v) {
+                return *opcode;
+            }
         }
+        Opcode::IGL
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// Number of bytes every instruction occupies in the code section
+pub const INSTRUCTION_LENGTH: usize = 4;
+
+/// A decoded instruction: the opcode and the three bytes that follow it. Not every opcode uses
+/// all three operand bytes, but every instruction is `INSTRUCTION_LENGTH` bytes long.
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub struct Instruction {
-    opcode: Opcode
+    pub opcode: Opcode,
+    pub operands: [u8; 3],
 }
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Self {
         Instruction {
-            opcode
+            opcode,
+            operands: [0; 3],
+        }
+    }
+
+    /// Decodes the instruction at the start of `bytes`. Any bytes missing from the end of a
+    /// truncated instruction are read as 0.
+    pub fn decode(bytes: &[u8]) -> Self {
+        let byte = |i: usize| bytes.get(i).copied().unwrap_or(0);
+        Instruction {
+            opcode: Opcode::from(byte(0)),
+            operands: [byte(1), byte(2), byte(3)],
+        }
+    }
+
+    /// Returns the big-endian 16-bit operand starting at operand byte `index`
+    pub fn wide_operand(&self, index: usize) -> u16 {
+        ((self.operands[index] as u16) << 8) | self.operands[index + 1] as u16
+    }
+
+    /// Encodes the instruction back into the bytes `decode` reads
+    pub fn to_bytes(&self) -> [u8; INSTRUCTION_LENGTH] {
+        let [r0, r1, r2] = self.operands;
+        [self.opcode as u8, r0, r1, r2]
+    }
+}
+
+/// Formats the instruction as assembly, e.g. `load $0 #500`. Addresses are printed as integers
+/// because the labels they came from aren't known here.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [r0, r1, r2] = self.operands;
+        let mnemonic = self.opcode.mnemonic();
+        match self.opcode {
+            Opcode::HLT | Opcode::NOP | Opcode::IGL => f.write_str(mnemonic),
+            Opcode::LOAD => write!(f, "{} ${} #{}", mnemonic, r0, self.wide_operand(1)),
+            Opcode::INC | Opcode::DEC | Opcode::JMP | Opcode::JMPF | Opcode::JMPB
+            | Opcode::JMPE | Opcode::ALOC => write!(f, "{} ${}", mnemonic, r0),
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => write!(f, "{} ${} ${} ${}", mnemonic, r0, r1, r2),
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTE
+            | Opcode::LTE => write!(f, "{} ${} ${}", mnemonic, r0, r1),
+            Opcode::DJMPE | Opcode::PRTS => write!(f, "{} #{}", mnemonic, self.wide_operand(0)),
         }
     }
 }
@@ -115,6 +230,30 @@ mod tests {
         assert_eq!(inst.opcode, Opcode::HLT);
     }
 
+    #[test]
+    fn test_decode_instruction() {
+        let inst = Instruction::decode(&[1, 3, 1, 244]);
+        assert_eq!(inst.opcode, Opcode::LOAD);
+        assert_eq!(inst.operands[0], 3);
+        assert_eq!(inst.wide_operand(1), 500);
+        assert_eq!(inst.to_bytes(), [1, 3, 1, 244]);
+
+        let inst = Instruction::decode(&[18, 0]);
+        assert_eq!(inst.opcode, Opcode::DJMPE);
+        assert_eq!(inst.operands, [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_display_instruction() {
+        assert_eq!(Instruction::decode(&[1, 3, 1, 244]).to_string(), "load $3 #500");
+        assert_eq!(Instruction::decode(&[4, 0, 1, 2]).to_string(), "add $0 $1 $2");
+        assert_eq!(Instruction::decode(&[12, 4, 5, 0]).to_string(), "neq $4 $5");
+        assert_eq!(Instruction::decode(&[17, 3, 0, 0]).to_string(), "jmpe $3");
+        assert_eq!(Instruction::decode(&[18, 0, 77, 0]).to_string(), "djmpe #77");
+        assert_eq!(Instruction::decode(&[0, 0, 0, 0]).to_string(), "hlt");
+        assert_eq!(Instruction::decode(&[200, 0, 0, 0]).to_string(), "igl");
+    }
+
     #[test]
     fn test_opcode_from() {
         let mut opcode = Opcode::from(0);
@@ -132,4 +271,20 @@ mod tests {
         opcode = Opcode::from("oadl");
         assert_eq!(opcode, Opcode::IGL);
     }
+
+    #[test]
+    fn test_opcode_from_str_ignores_case() {
+        assert_eq!(Opcode::from("LOAD"), Opcode::LOAD);
+        assert_eq!(Opcode::from("Djmpe"), Opcode::DJMPE);
+        assert_eq!(Opcode::from("PRTS"), Opcode::PRTS);
+        assert_eq!(Opcode::from("ptrs"), Opcode::IGL);
+    }
+
+    #[test]
+    fn test_mnemonics_match_opcodes() {
+        for (mnemonic, opcode) in MNEMONICS.iter() {
+            assert_eq!(mnemonic.to_uppercase(), format!("{:?}", opcode));
+            assert_eq!(Opcode::from(*opcode as u8), *opcode);
+        }
+    }
 }
\ No newline at end of file
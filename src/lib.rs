@@ -0,0 +1,9 @@
+pub mod vm;
+pub mod instructions;
+
+pub mod repl;
+pub mod assembler;
+pub mod gdb;
+pub mod dap;
+pub mod lsp;
+pub mod analysis;
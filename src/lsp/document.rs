@@ -0,0 +1,509 @@
+//! What the language server knows about one open `.iasm` file. Every line is parsed on its own
+//! with the assembler's parsers, so problems can be pinned to the line they're on, and then the
+//! whole file goes through the `Assembler` for the errors only it can find.
+
+use crate::assembler::{
+    Assembler,
+    Token,
+    assembler_errors::AssemblerError,
+    comment_parsers::split_comment,
+    instruction_parser::{instruction, AssemblerInstruction},
+    register_parsers::{register, REGISTER_ALIASES},
+    symbols::SymbolTable,
+};
+use crate::instructions::{Opcode, MNEMONICS};
+use crate::vm::REGISTER_COUNT;
+
+/// Directives the assembler understands, and what they do
+pub const DIRECTIVES: [(&str, &str); 3] = [
+    ("data", "Starts the read-only data section"),
+    ("code", "Starts the code section"),
+    ("asciiz", "Declares a null-terminated string in the read-only section, e.g. `hello: .asciiz 'Hello'`"),
+];
+
+/// A position the way the protocol counts: lines from 0, and characters in UTF-16 code units
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Position {
+    pub line: u32,
+    pub character: u32,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Range {
+    fn on_line(line: u32, start: u32, end: u32) -> Self {
+        Range {
+            start: Position { line, character: start },
+            end: Position { line, character: end },
+        }
+    }
+
+    pub fn contains(&self, position: Position) -> bool {
+        self.start <= position && position <= self.end
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    /// Something that assembles but probably isn't what was meant
+    Warning,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub range: Range,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CompletionKind {
+    Mnemonic,
+    Register,
+    Directive,
+    Label,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Completion {
+    pub label: String,
+    pub kind: CompletionKind,
+    pub detail: String,
+    pub documentation: Option<&'static str>,
+    /// The text the completion replaces
+    pub range: Range,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum WordKind {
+    Mnemonic,
+    Register,
+    Number,
+    LabelDeclaration,
+    LabelUsage,
+    Directive,
+    Text,
+}
+
+/// A piece of a line between whitespace, or a quoted string
+#[derive(Debug, Clone)]
+struct Word {
+    kind: WordKind,
+    /// The text without its `$`, `@`, `#`, `.`, quotes or trailing `:`
+    text: String,
+    /// Where the word is. For labels this covers only the name, so editors can rename them.
+    range: Range,
+}
+
+#[derive(Debug)]
+pub struct Document {
+    lines: Vec<String>,
+    words: Vec<Word>,
+    /// Every instruction in the file, with the range of the line it's on
+    instructions: Vec<(AssemblerInstruction, Range)>,
+    /// The symbols the assembler found, for the addresses of labels
+    symbols: SymbolTable,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Document {
+    pub fn new(text: &str) -> Self {
+        let mut document = Document {
+            lines: text.lines().map(String::from).collect(),
+            words: vec![],
+            instructions: vec![],
+            symbols: SymbolTable::new(),
+            diagnostics: vec![],
+        };
+        for n in 0..document.lines.len() {
+            document.parse_line(n);
+        }
+        document.check_labels();
+        // The assembler can only be trusted with programs that parse and whose labels all exist
+        let errors = document.diagnostics.iter().any(|d| d.severity == Severity::Error);
+        if !errors && !document.instructions.is_empty() {
+            document.assemble(text);
+        }
+        document
+    }
+
+    fn parse_line(&mut self, n: usize) {
+        let line = self.lines[n].clone();
+        let line_no = n as u32;
+        let (code, _) = split_comment(&line);
+        self.words.extend(words(line_no, code));
+        let line_range = Range::on_line(line_no, column(&line, line.len() - line.trim_start().len()), column(&line, code.trim_end().len()));
+
+        let mut rest = code;
+        while !rest.trim().is_empty() {
+            match instruction(rest) {
+                Ok((remaining, i)) if remaining.len() < rest.len() => {
+                    self.check_instruction(&i, line_range);
+                    self.instructions.push((i, line_range));
+                    rest = remaining;
+                },
+                _ => {
+                    let start = line.len() - rest.trim_start().len();
+                    self.error(Range::on_line(line_no, column(&line, start), line_range.end.character), format!("Unable to parse: {}", rest.trim()));
+                    break;
+                },
+            }
+        }
+
+        for word in self.words.iter().filter(|w| w.range.start.line == line_no && w.kind == WordKind::Mnemonic) {
+            if Opcode::from(word.text.as_str()) == Opcode::IGL {
+                self.diagnostics.push(Diagnostic {
+                    range: word.range,
+                    severity: Severity::Warning,
+                    message: format!("{} isn't a mnemonic, so it assembles to an illegal instruction", word.text),
+                });
+            }
+        }
+    }
+
+    /// Catches operands the assembler would give up on when it writes the bytecode
+    fn check_instruction(&mut self, i: &AssemblerInstruction, range: Range) {
+        if !i.is_opcode() {
+            return;
+        }
+        let operands = [&i.operand1, &i.operand2, &i.operand3];
+        if operands.iter().copied().flatten().any(|t| matches!(t, Token::IrString { .. })) {
+            self.error(range, "Strings can only be declared with .asciiz".to_string());
+        }
+    }
+
+    fn check_labels(&mut self) {
+        let mut declared: Vec<&str> = vec![];
+        let mut errors = vec![];
+        for word in self.words.iter().filter(|w| w.kind == WordKind::LabelDeclaration) {
+            if declared.contains(&word.text.as_str()) {
+                errors.push((word.range, AssemblerError::SymbolAlreadyDeclared.to_string()));
+            } else {
+                declared.push(&word.text);
+            }
+        }
+        for word in self.words.iter().filter(|w| w.kind == WordKind::LabelUsage) {
+            if !declared.contains(&word.text.as_str()) {
+                errors.push((word.range, AssemblerError::UnknownLabel { name: word.text.clone() }.to_string()));
+            }
+        }
+        for (range, message) in errors {
+            self.error(range, message);
+        }
+    }
+
+    fn assemble(&mut self, text: &str) {
+        let mut asm = Assembler::new();
+        asm.set_optimize(false);
+        if let Err(errors) = asm.assemble(text) {
+            for e in errors {
+                self.error(self.locate(&e), e.to_string());
+            }
+        }
+        self.symbols = asm.symbols;
+    }
+
+    /// Works out which line an error from the assembler is about
+    fn locate(&self, error: &AssemblerError) -> Range {
+        let directive = |name: &str| {
+            self.instructions.iter().find(|(i, _)| i.get_directive_name().as_deref() == Some(name))
+        };
+        let found = match error {
+            AssemblerError::NoSegmentDeclarationFound { instruction } => self.instructions.get(*instruction as usize),
+            AssemblerError::StringConstantDeclaredWithoutLabel { .. } => {
+                self.instructions.iter().find(|(i, _)| i.is_directive() && i.has_operands() && !i.is_label())
+            },
+            AssemblerError::UnknownDirectiveFound { directive: name } | AssemblerError::InvalidSection { name } => directive(name),
+            _ => None,
+        };
+        // Errors about the file as a whole go at the start
+        found.map(|(_, range)| *range).unwrap_or_else(|| Range::on_line(0, 0, 0))
+    }
+
+    fn error(&mut self, range: Range, message: String) {
+        self.diagnostics.push(Diagnostic { range, severity: Severity::Error, message });
+    }
+
+    fn word_at(&self, position: Position) -> Option<&Word> {
+        self.words.iter().find(|w| w.range.contains(position))
+    }
+
+    fn label_at(&self, position: Position) -> Option<&Word> {
+        self.word_at(position).filter(|w| matches!(w.kind, WordKind::LabelDeclaration | WordKind::LabelUsage))
+    }
+
+    /// Where the label at `position` is declared
+    pub fn definition(&self, position: Position) -> Option<Range> {
+        let label = self.label_at(position)?;
+        self.words.iter()
+            .find(|w| w.kind == WordKind::LabelDeclaration && w.text == label.text)
+            .map(|w| w.range)
+    }
+
+    /// Everywhere the label at `position` is used
+    pub fn references(&self, position: Position, include_declaration: bool) -> Vec<Range> {
+        let label = match self.label_at(position) {
+            Some(label) => label,
+            None => return vec![],
+        };
+        self.words.iter()
+            .filter(|w| w.text == label.text)
+            .filter(|w| w.kind == WordKind::LabelUsage || (include_declaration && w.kind == WordKind::LabelDeclaration))
+            .map(|w| w.range)
+            .collect()
+    }
+
+    /// Markdown describing what's at `position`, and the range it describes
+    pub fn hover(&self, position: Position) -> Option<(String, Range)> {
+        let word = self.word_at(position)?;
+        let text = match word.kind {
+            WordKind::Mnemonic => match Opcode::from(word.text.as_str()) {
+                Opcode::IGL => format!("`{}` isn't a mnemonic", word.text),
+                opcode => format!("```\n{} {}\n```\n{}", opcode.mnemonic(), opcode.operands(), opcode.description()),
+            },
+            WordKind::Register => match register(&format!("${}", word.text)) {
+                Ok(("", Token::Register { reg_num })) => {
+                    format!("Register `${}`, also called `${}`", reg_num, REGISTER_ALIASES[reg_num as usize].0)
+                },
+                _ => format!("Not a register. Registers are `$0` to `${}`, or an alias such as `$sp`", REGISTER_COUNT - 1),
+            },
+            WordKind::LabelDeclaration | WordKind::LabelUsage => self.describe_label(&word.text),
+            WordKind::Directive => match DIRECTIVES.iter().find(|(name, _)| *name == word.text) {
+                Some((_, description)) => description.to_string(),
+                None => AssemblerError::UnknownDirectiveFound { directive: word.text.clone() }.to_string(),
+            },
+            WordKind::Number | WordKind::Text => return None,
+        };
+        Some((text, word.range))
+    }
+
+    fn describe_label(&self, name: &str) -> String {
+        let declaration = self.instructions.iter().find(|(i, _)| i.get_label_name().as_deref() == Some(name));
+        let offset = self.symbols.symbol_value(name);
+        match declaration {
+            Some((i, _)) if i.is_directive() => {
+                let at = offset.map(|o| format!(" at read-only offset {}", o)).unwrap_or_default();
+                format!("`{}`: string constant '{}'{}", name, i.get_string_constant().unwrap_or_default(), at)
+            },
+            Some(_) => {
+                let at = offset.map(|o| format!(" at address {}", o)).unwrap_or_default();
+                format!("`{}`: code label{}", name, at)
+            },
+            None => AssemblerError::UnknownLabel { name: name.to_string() }.to_string(),
+        }
+    }
+
+    /// What could be typed at `position`: registers after `$`, labels after `@`, directives after
+    /// `.`, and mnemonics at the start of an instruction
+    pub fn completions(&self, position: Position) -> Vec<Completion> {
+        let line = self.lines.get(position.line as usize).map(String::as_str).unwrap_or_default();
+        let before = &line[..byte_offset(line, position.character)];
+        let start = before.char_indices().rev().find(|(_, c)| c.is_whitespace()).map(|(i, c)| i + c.len_utf8()).unwrap_or(0);
+        let word = &before[start..];
+        let range = Range::on_line(position.line, column(line, start), column(line, before.len()));
+        let preceding = before[..start].trim();
+        let first_word = preceding.is_empty() || (preceding.ends_with(':') && !preceding.contains(char::is_whitespace));
+
+        let completion = |label: String, kind: CompletionKind, detail: String, documentation: Option<&'static str>| {
+            Completion { label, kind, detail, documentation, range }
+        };
+        if let Some(prefix) = word.strip_prefix('$') {
+            let prefix = prefix.to_lowercase();
+            let numbers = (0..REGISTER_COUNT).map(|n| (n.to_string(), format!("${}", REGISTER_ALIASES[n].0)));
+            let aliases = REGISTER_ALIASES.iter().map(|(alias, n)| (alias.to_string(), format!("${}", n)));
+            numbers.chain(aliases)
+                .filter(|(name, _)| name.starts_with(&prefix))
+                .map(|(name, detail)| completion(format!("${}", name), CompletionKind::Register, detail, None))
+                .collect()
+        } else if let Some(prefix) = word.strip_prefix('@') {
+            self.instructions.iter()
+                .filter_map(|(i, _)| i.get_label_name().map(|name| (name, i.is_directive())))
+                .filter(|(name, _)| name.starts_with(prefix))
+                .map(|(name, string)| {
+                    let detail = if string { "string constant" } else { "code label" };
+                    completion(format!("@{}", name), CompletionKind::Label, detail.to_string(), None)
+                })
+                .collect()
+        } else if let Some(prefix) = word.strip_prefix('.') {
+            DIRECTIVES.iter()
+                .filter(|(name, _)| name.starts_with(prefix))
+                .map(|(name, description)| completion(format!(".{}", name), CompletionKind::Directive, String::new(), Some(*description)))
+                .collect()
+        } else if first_word {
+            let prefix = word.to_lowercase();
+            MNEMONICS.iter()
+                .filter(|(mnemonic, _)| mnemonic.starts_with(&prefix))
+                .map(|(mnemonic, opcode)| {
+                    let detail = format!("{} {}", mnemonic, opcode.operands()).trim_end().to_string();
+                    completion(mnemonic.to_string(), CompletionKind::Mnemonic, detail, Some(opcode.description()))
+                })
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+}
+
+/// Splits a line into words. Quoted strings are one word even if they contain spaces, and a
+/// label declaration ends at its `:` even when nothing separates it from what follows.
+fn words(line_no: u32, line: &str) -> Vec<Word> {
+    let mut words = vec![];
+    let mut chars = line.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut end = line.len();
+        if c == '\'' {
+            chars.next();
+            for (i, c) in chars.by_ref() {
+                if c == '\'' {
+                    end = i + 1;
+                    break;
+                }
+            }
+        } else {
+            while let Some(&(i, c)) = chars.peek() {
+                if c.is_whitespace() || c == '\'' {
+                    end = i;
+                    break;
+                }
+                chars.next();
+                if c == ':' {
+                    end = i + 1;
+                    break;
+                }
+            }
+        }
+
+        let text = &line[start..end];
+        let (kind, name) = if let Some(name) = text.strip_suffix(':') {
+            (WordKind::LabelDeclaration, name)
+        } else if let Some(name) = text.strip_prefix('$') {
+            (WordKind::Register, name)
+        } else if let Some(name) = text.strip_prefix('@') {
+            (WordKind::LabelUsage, name)
+        } else if let Some(name) = text.strip_prefix('#') {
+            (WordKind::Number, name)
+        } else if let Some(name) = text.strip_prefix('.') {
+            (WordKind::Directive, name)
+        } else if let Some(name) = text.strip_prefix('\'') {
+            (WordKind::Text, name.strip_suffix('\'').unwrap_or(name))
+        } else {
+            (WordKind::Mnemonic, text)
+        };
+        let range = if matches!(kind, WordKind::LabelDeclaration | WordKind::LabelUsage) {
+            let name_start = start + (name.as_ptr() as usize - text.as_ptr() as usize);
+            Range::on_line(line_no, column(line, name_start), column(line, name_start + name.len()))
+        } else {
+            Range::on_line(line_no, column(line, start), column(line, end))
+        };
+        words.push(Word { kind, text: name.to_string(), range });
+    }
+    words
+}
+
+/// Converts a byte offset in `line` to a column in UTF-16 code units
+fn column(line: &str, byte: usize) -> u32 {
+    line[..byte].encode_utf16().count() as u32
+}
+
+/// Converts a column in UTF-16 code units to a byte offset, clamped to the end of the line
+fn byte_offset(line: &str, column: u32) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= column {
+            return i;
+        }
+        units += c.len_utf16() as u32;
+    }
+    line.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = ".data\nhello: .asciiz 'Hi there'\n.code\nload $0 #3\nload $t1 @loop\nloop: dec $0\nprts @hello\nneq $0 $1\njmpe $t1\nhlt ; foo @nowhere\n; the end\n";
+
+    fn at(line: u32, character: u32) -> Position {
+        Position { line, character }
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        assert_eq!(Document::new(PROGRAM).diagnostics, vec![]);
+
+        let document = Document::new(".data\n.code\nload $0 @nowhere\nprts 'hi'\nload $0 #1 $\nfoo\nloop: hlt\nloop: hlt\n");
+        let messages: Vec<(u32, &str)> = document.diagnostics.iter()
+            .map(|d| (d.range.start.line, d.message.as_str()))
+            .collect();
+        assert_eq!(messages, vec![
+            (3, "Strings can only be declared with .asciiz"),
+            (4, "Unable to parse: $"),
+            (5, "foo isn't a mnemonic, so it assembles to an illegal instruction"),
+            (7, "This symbol was previously declared."),
+            (2, "No label named nowhere has been declared"),
+        ]);
+        assert_eq!(document.diagnostics[4].range, Range::on_line(2, 9, 16));
+        assert_eq!(document.diagnostics[2].severity, Severity::Warning);
+
+        let document = Document::new(".data\n.code\n.text\nhlt\n");
+        assert_eq!(document.diagnostics, vec![Diagnostic {
+            range: Range::on_line(2, 0, 5),
+            severity: Severity::Error,
+            message: "Invalid segment name found: text".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn test_definition_and_references() {
+        let document = Document::new(PROGRAM);
+        assert_eq!(document.definition(at(4, 11)), Some(Range::on_line(5, 0, 4)));
+        assert_eq!(document.definition(at(6, 7)), Some(Range::on_line(1, 0, 5)));
+        assert_eq!(document.definition(at(3, 1)), None);
+        assert_eq!(document.references(at(5, 2), false), vec![Range::on_line(4, 10, 14)]);
+        assert_eq!(document.references(at(5, 2), true), vec![Range::on_line(4, 10, 14), Range::on_line(5, 0, 4)]);
+    }
+
+    #[test]
+    fn test_hover() {
+        let document = Document::new(PROGRAM);
+        let (text, range) = document.hover(at(3, 2)).unwrap();
+        assert_eq!(text, "```\nload $register #number\n```\nLoads a 16-bit number, or the address of a label, into a register");
+        assert_eq!(range, Range::on_line(3, 0, 4));
+        assert_eq!(document.hover(at(4, 6)).unwrap().0, "Register `$5`, also called `$t1`");
+        assert_eq!(document.hover(at(4, 12)).unwrap().0, "`loop`: code label at address 73");
+        assert_eq!(document.hover(at(6, 8)).unwrap().0, "`hello`: string constant 'Hi there' at read-only offset 0");
+        assert_eq!(document.hover(at(0, 2)).unwrap().0, "Starts the read-only data section");
+        assert_eq!(document.hover(at(3, 9)), None);
+    }
+
+    #[test]
+    fn test_completions() {
+        let document = Document::new(PROGRAM);
+        let labels = |completions: Vec<Completion>| completions.into_iter().map(|c| c.label).collect::<Vec<_>>();
+
+        let jumps = document.completions(at(8, 3));
+        assert_eq!(labels(jumps.clone()), vec!["jmp", "jmpf", "jmpb", "jmpe"]);
+        assert_eq!(jumps[0].detail, "jmp $register");
+        assert_eq!(jumps[0].range, Range::on_line(8, 0, 3));
+        assert_eq!(labels(document.completions(at(5, 8))), vec!["dec"]);
+        assert_eq!(labels(document.completions(at(4, 8))), vec!["$t1", "$t10", "$t11"]);
+        assert_eq!(labels(document.completions(at(6, 7))), vec!["@hello"]);
+        assert_eq!(labels(document.completions(at(0, 2))), vec![".data"]);
+        assert_eq!(document.completions(at(3, 9)), vec![]);
+        assert_eq!(labels(document.completions(at(20, 0))).len(), MNEMONICS.len());
+
+        let wide_space = Document::new("load\u{3000}$t1");
+        let registers = wide_space.completions(at(0, 8));
+        assert_eq!(labels(registers.clone()), vec!["$t1", "$t10", "$t11"]);
+        assert_eq!(registers[0].range, Range::on_line(0, 5, 8));
+    }
+}
@@ -0,0 +1,259 @@
+//! A Language Server Protocol server for `.iasm` files, started by editors as `iridium lsp` and
+//! spoken to over stdin and stdout. It reports problems as the file is edited, jumps to and
+//! finds labels, describes mnemonics, registers and directives on hover, and completes them.
+//!
+//! Documents are always sent whole, so every change re-analyzes the file from scratch; programs
+//! are small enough that this is quick.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+};
+
+use log::warn;
+use serde_json::{json, Value};
+
+use crate::dap::transport;
+
+pub mod document;
+
+use document::{Completion, CompletionKind, Document, Position, Range, Severity};
+
+/// JSON-RPC error code for a request the server doesn't handle
+const METHOD_NOT_FOUND: i64 = -32601;
+/// JSON-RPC error code for requests with missing or wrong parameters
+const INVALID_PARAMS: i64 = -32602;
+
+pub struct LanguageServer<W: Write> {
+    output: W,
+    /// The open documents by URI
+    documents: HashMap<String, Document>,
+}
+
+impl<W: Write> LanguageServer<W> {
+    pub fn new(output: W) -> Self {
+        LanguageServer {
+            output,
+            documents: HashMap::new(),
+        }
+    }
+
+    /// Handles messages from `input` until the client sends `exit` or closes it
+    pub fn run<R: BufRead>(&mut self, mut input: R) -> io::Result<()> {
+        while let Some(message) = transport::read_message(&mut input)? {
+            if message["method"] == "exit" {
+                break;
+            }
+            self.handle(&message);
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, message: &Value) {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => {
+                self.notification(method, params);
+                return;
+            },
+        };
+
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // Full documents are sent on every change
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "completionProvider": {"triggerCharacters": ["$", "@", "."]},
+                },
+                "serverInfo": {"name": "iridium", "version": env!("CARGO_PKG_VERSION")},
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/hover" => self.at_position(params, |_, document, position| {
+                document.hover(position).map(|(text, range)| json!({
+                    "contents": {"kind": "markdown", "value": text},
+                    "range": range_json(range),
+                })).unwrap_or(Value::Null)
+            }),
+            "textDocument/definition" => self.at_position(params, |uri, document, position| {
+                document.definition(position).map(|range| location_json(uri, range)).unwrap_or(Value::Null)
+            }),
+            "textDocument/references" => {
+                let include_declaration = params["context"]["includeDeclaration"].as_bool().unwrap_or(false);
+                self.at_position(params, |uri, document, position| {
+                    document.references(position, include_declaration).into_iter()
+                        .map(|range| location_json(uri, range))
+                        .collect()
+                })
+            },
+            "textDocument/completion" => self.at_position(params, |_, document, position| {
+                document.completions(position).into_iter().map(completion_json).collect()
+            }),
+            _ => Err((METHOD_NOT_FOUND, format!("{} isn't supported", method))),
+        };
+
+        let response = match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err((code, message)) => json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}}),
+        };
+        self.send(&response);
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.update(uri, text);
+            },
+            "textDocument/didChange" => {
+                // With full syncing the last change holds the whole document
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes.and_then(|c| c.last()).and_then(|c| c["text"].as_str()) {
+                    self.update(uri, text);
+                }
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.publish_diagnostics(&uri, &[]);
+            },
+            _ => {},
+        }
+    }
+
+    fn update(&mut self, uri: String, text: &str) {
+        let document = Document::new(text);
+        let diagnostics: Vec<Value> = document.diagnostics.iter().map(|d| json!({
+            "range": range_json(d.range),
+            "severity": match d.severity { Severity::Error => 1, Severity::Warning => 2 },
+            "source": "iridium",
+            "message": d.message,
+        })).collect();
+        self.publish_diagnostics(&uri, &diagnostics);
+        self.documents.insert(uri, document);
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str, diagnostics: &[Value]) {
+        self.send(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": uri, "diagnostics": diagnostics},
+        }));
+    }
+
+    /// Answers a request about a position in a document with `answer`
+    fn at_position<F>(&self, params: &Value, answer: F) -> Result<Value, (i64, String)>
+        where F: FnOnce(&str, &Document, Position) -> Value
+    {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let document = self.documents.get(uri).ok_or_else(|| (INVALID_PARAMS, format!("{} isn't open", uri)))?;
+        let position = &params["position"];
+        match (position["line"].as_u64(), position["character"].as_u64()) {
+            (Some(line), Some(character)) => {
+                Ok(answer(uri, document, Position { line: line as u32, character: character as u32 }))
+            },
+            _ => Err((INVALID_PARAMS, "The request has no position".to_string())),
+        }
+    }
+
+    fn send(&mut self, message: &Value) {
+        if let Err(e) = transport::write_message(&mut self.output, message) {
+            warn!("Unable to send a message to the editor: {}", e);
+        }
+    }
+}
+
+/// Serves the language server over stdin and stdout until the editor exits
+pub fn serve_stdio() -> io::Result<()> {
+    let stdin = io::stdin();
+    LanguageServer::new(io::stdout()).run(stdin.lock())
+}
+
+fn range_json(range: Range) -> Value {
+    json!({
+        "start": {"line": range.start.line, "character": range.start.character},
+        "end": {"line": range.end.line, "character": range.end.character},
+    })
+}
+
+fn location_json(uri: &str, range: Range) -> Value {
+    json!({"uri": uri, "range": range_json(range)})
+}
+
+fn completion_json(completion: Completion) -> Value {
+    // Kinds as the protocol numbers them
+    let kind = match completion.kind {
+        CompletionKind::Mnemonic | CompletionKind::Directive => 14,
+        CompletionKind::Register => 6,
+        CompletionKind::Label => 18,
+    };
+    let mut item = json!({
+        "label": completion.label,
+        "kind": kind,
+        "detail": completion.detail,
+        "textEdit": {"range": range_json(completion.range), "newText": completion.label},
+    });
+    if let Some(documentation) = completion.documentation {
+        item["documentation"] = json!(documentation);
+    }
+    item
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session() {
+        let uri = "file:///tmp/countdown.iasm";
+        let messages = vec![
+            json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {"capabilities": {}}}),
+            json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+            json!({"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {"textDocument": {
+                "uri": uri, "languageId": "iasm", "version": 1, "text": ".data\n.code\nload $0 @loop\n",
+            }}}),
+            json!({"jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+                "textDocument": {"uri": uri, "version": 2},
+                "contentChanges": [{"text": ".data\n.code\nload $0 @loop\nloop: jmp $0\n"}],
+            }}),
+            json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/definition", "params": {
+                "textDocument": {"uri": uri}, "position": {"line": 2, "character": 10},
+            }}),
+            json!({"jsonrpc": "2.0", "id": 3, "method": "textDocument/completion", "params": {
+                "textDocument": {"uri": uri}, "position": {"line": 3, "character": 12},
+            }}),
+            json!({"jsonrpc": "2.0", "id": 4, "method": "textDocument/formatting", "params": {}}),
+            json!({"jsonrpc": "2.0", "id": 5, "method": "shutdown"}),
+            json!({"jsonrpc": "2.0", "method": "exit"}),
+            json!({"jsonrpc": "2.0", "id": 6, "method": "shutdown"}),
+        ];
+        let mut input = vec![];
+        for message in &messages {
+            transport::write_message(&mut input, message).unwrap();
+        }
+        let mut server = LanguageServer::new(vec![]);
+        server.run(&input[..]).unwrap();
+
+        let mut output = &server.output[..];
+        let mut replies = vec![];
+        while let Some(reply) = transport::read_message(&mut output).unwrap() {
+            replies.push(reply);
+        }
+        assert_eq!(replies.len(), 7);
+        assert_eq!(replies[0]["result"]["capabilities"]["definitionProvider"], true);
+        assert_eq!(replies[1]["params"]["diagnostics"][0]["message"], "No label named loop has been declared");
+        assert_eq!(replies[2]["params"]["diagnostics"], json!([]));
+        assert_eq!(replies[3], json!({"jsonrpc": "2.0", "id": 2, "result": {
+            "uri": uri,
+            "range": {"start": {"line": 3, "character": 0}, "end": {"line": 3, "character": 4}},
+        }}));
+        assert_eq!(replies[4]["result"][0]["label"], "$0");
+        assert_eq!(replies[4]["result"][0]["detail"], "$a0");
+        assert_eq!(replies[5]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(replies[6], json!({"jsonrpc": "2.0", "id": 5, "result": null}));
+    }
+}
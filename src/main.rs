@@ -1,51 +1,479 @@
 use std::{
     fs::File,
     path::Path,
-    io::prelude::*,
+    io::{self, BufReader, BufWriter, IsTerminal, prelude::*},
 };
 
 use clap::{
     App,
+    ArgMatches,
     load_yaml,
 };
 
 use log::info;
-use env_logger;
-
-pub mod vm;
-pub mod instructions;
-
-pub mod repl;
-pub mod assembler;
 
+use iridium::{
+    analysis::{self, cfg::Cfg, coverage::CoverageReport, lint::{self, LintConfig, Rule, Severity}, profile::ProfileReport},
+    assembler::{self, comment_parsers::space_and_comments, program_parser::program, symbols::{SymbolTable, SymbolType}},
+    dap,
+    gdb,
+    instructions::Opcode,
+    lsp,
+    repl,
+    vm::{self, config::VmConfig, coverage::Coverage, trace::TraceReader},
+};
 
 fn main() {
     env_logger::init();
     info!("Starting logging");
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
+    if let Some(trace_matches) = matches.subcommand_matches("trace") {
+        if let Some(show_matches) = trace_matches.subcommand_matches("show") {
+            show_trace(show_matches.value_of("TRACE_FILE").unwrap());
+        } else {
+            eprintln!("{}", trace_matches.usage());
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(repl_matches) = matches.subcommand_matches("repl") {
+        start_repl(repl_matches.value_of("SCRIPT"));
+        return;
+    }
+    if let Some(serve_matches) = matches.subcommand_matches("serve") {
+        let address = serve_matches.value_of("LISTEN").unwrap_or(repl::server::DEFAULT_LISTEN_ADDRESS);
+        let config = repl::server::ServerConfig {
+            secret: serve_matches.value_of("SECRET").map(String::from),
+            ..repl::server::ServerConfig::default()
+        };
+        if let Err(e) = repl::server::serve(address, config) {
+            eprintln!("Unable to serve on {}: {}", address, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(gdb_matches) = matches.subcommand_matches("gdb") {
+        debug_with_gdb(gdb_matches, vm_config(&matches));
+        return;
+    }
+    if matches.subcommand_matches("dap").is_some() {
+        dap::serve_stdio();
+        return;
+    }
+    if let Some(fmt_matches) = matches.subcommand_matches("fmt") {
+        format_files(fmt_matches);
+        return;
+    }
+    if let Some(lint_matches) = matches.subcommand_matches("lint") {
+        lint_files(lint_matches);
+        return;
+    }
+    if let Some(coverage_matches) = matches.subcommand_matches("coverage") {
+        report_coverage(coverage_matches);
+        return;
+    }
+    if let Some(cfg_matches) = matches.subcommand_matches("cfg") {
+        show_cfg(cfg_matches);
+        return;
+    }
+    if matches.subcommand_matches("lsp").is_some() {
+        if let Err(e) = lsp::serve_stdio() {
+            eprintln!("The language server stopped: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let target_file = matches.value_of("INPUT_FILE");
 
     if let Some(filename) = target_file {
         let program = read_file(filename);
         let mut asm = assembler::Assembler::new();
-        let mut vm = vm::VM::new();
+        let coverage_file = matches.value_of("COVERAGE");
+        // Coverage and profiles are reported against source lines, which the optimizer loses
+        asm.set_optimize(matches.is_present("OPTIMIZE") && coverage_file.is_none() && !matches.is_present("PROFILE"));
+        let mut vm = vm::VM::with_config(vm_config(&matches));
         let program = asm.assemble(&program);
 
         if let Ok(prog) = program {
             vm.add_bytes(prog);
-            vm.run();
-            std::process::exit(0);
+            if let Some(gas) = parse_number(&matches, "GAS") {
+                vm.set_gas(gas);
+            }
+            if let Some(trace_file) = matches.value_of("TRACE") {
+                start_trace(&mut vm, trace_file);
+            }
+            if coverage_file.is_some() {
+                vm.start_coverage();
+            }
+            if matches.is_present("PROFILE") {
+                vm.start_profile();
+            }
+
+            let reason = vm.run();
+            if let (Some(filename), Some(coverage)) = (coverage_file, vm.take_coverage()) {
+                save_coverage(coverage, filename);
+            }
+            if let (Some(filename), Some(profile)) = (matches.value_of("PROFILE"), vm.take_profile()) {
+                let report = ProfileReport::new(&profile, vm.program(), &asm.symbols, &asm.lines);
+                save_profile(&report, filename, parse_number(&matches, "PROFILE_TOP").unwrap_or(10));
+            }
+            if let Err(e) = vm.stop_trace() {
+                eprintln!("There was an error writing the trace: {}", e);
+            }
+            match reason {
+                vm::ExitReason::OutOfGas => {
+                    eprintln!("Program ran out of gas");
+                    std::process::exit(1);
+                },
+                vm::ExitReason::Fault(fault) => {
+                    eprintln!("Program stopped: {}", fault);
+                    std::process::exit(1);
+                },
+                vm::ExitReason::InvalidHeader => std::process::exit(1),
+                _ => std::process::exit(0),
+            }
         }
     } else {
-        start_repl();
+        start_repl(None);
+    }
+}
+
+/// Builds the VM's resource limits from the command line options
+fn vm_config(matches: &ArgMatches) -> VmConfig {
+    let allowed_opcodes = matches.value_of("ALLOW_OPCODES").map(|list| {
+        list.split(',').map(|mnemonic| {
+            match Opcode::from(mnemonic.trim()) {
+                Opcode::IGL => {
+                    eprintln!("Unknown opcode in --allow-opcodes: {}", mnemonic);
+                    std::process::exit(1);
+                },
+                opcode => opcode,
+            }
+        }).collect()
+    });
+
+    VmConfig {
+        max_heap_bytes: parse_number(matches, "MAX_HEAP"),
+        max_output_bytes: parse_number(matches, "MAX_OUTPUT"),
+        allowed_opcodes,
+    }
+}
+
+/// Reads a numeric option, exiting with an error if it isn't a number
+fn parse_number<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Option<T> where T::Err: std::fmt::Display {
+    matches.value_of(name).map(|value| match value.parse::<T>() {
+        Ok(number) => number,
+        Err(e) => {
+            eprintln!("Invalid value {} for {}: {}", value, name, e);
+            std::process::exit(1);
+        }
+    })
+}
+
+/// Sends a trace of the VM's execution to `filename`, exiting if the file can't be created
+fn start_trace(vm: &mut vm::VM, filename: &str) {
+    let result = File::create(filename)
+        .and_then(|fh| vm.start_trace(Box::new(BufWriter::new(fh))));
+    if let Err(e) = result {
+        eprintln!("Unable to write trace to {}: {}", filename, e);
+        std::process::exit(1);
+    }
+}
+
+/// Adds the counts from a run to those already in `filename`, exiting if that fails
+fn save_coverage(mut coverage: Coverage, filename: &str) {
+    let existing = match File::open(filename) {
+        Ok(fh) => Coverage::read_from(BufReader::new(fh)).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    };
+    let result = existing.and_then(|existing| {
+        if let Some(existing) = existing {
+            coverage.merge(&existing);
+        }
+        File::create(filename).and_then(|fh| coverage.write_to(BufWriter::new(fh)))
+    });
+    if let Err(e) = result {
+        eprintln!("Unable to save coverage to {}: {}", filename, e);
+        std::process::exit(1);
+    }
+}
+
+/// Writes a profile as collapsed stacks to `filename` and prints its `top` costliest labels and
+/// instructions, exiting if the file can't be written
+fn save_profile(report: &ProfileReport, filename: &str, top: usize) {
+    let result = File::create(filename).and_then(|fh| report.write_collapsed(BufWriter::new(fh)));
+    if let Err(e) = result {
+        eprintln!("Unable to write profile to {}: {}", filename, e);
+        std::process::exit(1);
+    }
+    eprint!("{}", report.top(top));
+}
+
+/// Adds up the coverage counts for a source file and prints which of its lines ran, writing
+/// an lcov file too if asked
+fn report_coverage(matches: &ArgMatches) {
+    let path = matches.value_of("SOURCE").unwrap();
+    let mut asm = assembler::Assembler::new();
+    // The same layout `--coverage` runs with
+    asm.set_optimize(false);
+    if let Err(errors) = asm.assemble(&read_file(path)) {
+        for error in errors {
+            eprintln!("{}: {}", path, error);
+        }
+        std::process::exit(1);
+    }
+
+    let mut coverage = Coverage::new();
+    for filename in matches.values_of("COUNTS").unwrap() {
+        match File::open(filename).and_then(|fh| Coverage::read_from(BufReader::new(fh))) {
+            Ok(counts) => coverage.merge(&counts),
+            Err(e) => {
+                eprintln!("Unable to read coverage from {}: {}", filename, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let report = CoverageReport::new(&asm.lines, &coverage);
+    print!("{}", report.summary(path));
+    if let Some(filename) = matches.value_of("LCOV") {
+        let result = File::create(filename).and_then(|fh| report.write_lcov(path, BufWriter::new(fh)));
+        if let Err(e) = result {
+            eprintln!("Unable to write {}: {}", filename, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints every record of a trace file, exiting with an error if it can't be read
+fn show_trace(filename: &str) {
+    let reader = File::open(filename).and_then(|fh| TraceReader::new(BufReader::new(fh)));
+    let reader = match reader {
+        Ok(reader) => reader,
+        Err(e) => {
+            eprintln!("Unable to read trace {}: {}", filename, e);
+            std::process::exit(1);
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for record in reader {
+        match record {
+            Ok(record) => {
+                if writeln!(out, "{}", record).is_err() {
+                    return;
+                }
+            },
+            Err(e) => {
+                eprintln!("Trace is damaged: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Assembles a program and serves it to a debugger, exiting once the debugger is done
+fn debug_with_gdb(matches: &ArgMatches, config: VmConfig) {
+    let source = read_file(matches.value_of("INPUT_FILE").unwrap());
+    let mut asm = assembler::Assembler::new();
+    // Debug the program exactly as it was written
+    asm.set_optimize(false);
+    let program = match asm.assemble(&source) {
+        Ok(program) => program,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            std::process::exit(1);
+        }
+    };
+    let mut vm = vm::VM::with_config(config);
+    vm.add_bytes(program);
+    vm.add_ro_data(&asm.ro);
+
+    let address = matches.value_of("LISTEN").unwrap_or(gdb::DEFAULT_LISTEN_ADDRESS);
+    eprintln!("Waiting for a debugger on {}", address);
+    if let Err(e) = gdb::serve(vm, address) {
+        eprintln!("Debugging session failed: {}", e);
+        std::process::exit(1);
     }
 }
 
-/// Starts the REPL that will run until the user kills it.
-fn start_repl() {
+/// Starts the REPL that will run until the user kills it. Commands come from `script` if one is
+/// given, or from stdin when it is redirected, in which case the process exits with an error if
+/// any assertion fails.
+fn start_repl(script: Option<&str>) {
     let mut r = repl::REPL::new();
-    r.run()
+    let result = match script {
+        Some(filename) => File::open(filename)
+            .and_then(|fh| r.run_script(BufReader::new(fh))),
+        None if !io::stdin().is_terminal() => r.run_script(io::stdin().lock()),
+        None => return r.run(),
+    };
+    match result {
+        Ok(0) => {},
+        Ok(failures) => {
+            eprintln!("{} assertion(s) failed", failures);
+            std::process::exit(1);
+        },
+        Err(e) => {
+            eprintln!("Unable to read script: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Formats each file in place, or with `--check` only reports the ones that need it. Exits
+/// with 1 if any file couldn't be formatted or, when checking, wasn't formatted.
+fn format_files(matches: &ArgMatches) {
+    let check = matches.is_present("CHECK");
+    let mut failed = false;
+    for path in matches.values_of("FILES").unwrap() {
+        let source = read_file(path);
+        let formatted = match assembler::formatter::format(&source) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                failed = true;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", path);
+            failed = true;
+        } else if let Err(e) = std::fs::write(path, formatted) {
+            eprintln!("Unable to write {}: {}", path, e);
+            failed = true;
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Lints each file, printing what was found as text or, with `--format json`, as one JSON
+/// array. Exits with 1 if any file couldn't be parsed or had an error.
+fn lint_files(matches: &ArgMatches) {
+    let mut config = match matches.values_of("RULES") {
+        Some(names) => LintConfig::only(&names.map(lint_rule).collect::<Vec<Rule>>()),
+        None => LintConfig::default(),
+    };
+    for rule in matches.values_of("SKIP").into_iter().flatten().map(lint_rule) {
+        config.disable(rule);
+    }
+    let json = matches.value_of("FORMAT") == Some("json");
+    let mut failed = false;
+    let mut reports = vec![];
+    for path in matches.values_of("FILES").unwrap() {
+        let findings = match lint::lint(&read_file(path), &config) {
+            Ok(findings) => findings,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                failed = true;
+                continue;
+            }
+        };
+        for f in findings {
+            let severity = f.rule.severity();
+            failed |= severity == Severity::Error;
+            if json {
+                reports.push(serde_json::json!({
+                    "file": path,
+                    "line": f.line,
+                    "rule": f.rule.name(),
+                    "severity": severity.name(),
+                    "message": f.message,
+                }));
+            } else {
+                println!("{}:{}: {}[{}]: {}", path, f.line, severity.name(), f.rule.name(), f.message);
+            }
+        }
+    }
+    if json {
+        println!("{}", serde_json::Value::Array(reports));
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+fn lint_rule(name: &str) -> Rule {
+    match Rule::from_name(name) {
+        Some(rule) => rule,
+        None => {
+            let names: Vec<&str> = Rule::ALL.iter().map(|r| r.name()).collect();
+            eprintln!("Unknown rule {}, expected one of {}", name, names.join(", "));
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints the control-flow graph of a source file or assembled program, as a list of blocks
+/// and loops or, with `--dot`, in Graphviz's DOT language.
+fn show_cfg(matches: &ArgMatches) {
+    let path = matches.value_of("INPUT_FILE").unwrap();
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Unable to read {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    let (cfg, symbols) = match Cfg::from_bytecode(&bytes) {
+        Some(cfg) => (cfg, SymbolTable::new()),
+        None => {
+            let source = String::from_utf8_lossy(&bytes);
+            match program(&source) {
+                Ok((rest, parsed)) if space_and_comments(rest).is_empty() => {
+                    (Cfg::from_program(&parsed), analysis::program_symbols(&parsed))
+                },
+                _ => {
+                    eprintln!("{} is neither an assembled program nor source that parses", path);
+                    std::process::exit(1);
+                }
+            }
+        }
+    };
+    if matches.is_present("DOT") {
+        print!("{}", cfg.to_dot(&symbols));
+        return;
+    }
+
+    let reachable = cfg.reachable();
+    let idom = cfg.dominators();
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        let successors: Vec<String> = block.successors.iter().map(|s| s.to_string()).collect();
+        let mut line = format!("block {}: {}-{}", b, cfg.address(block.start), cfg.address(block.end) - 1);
+        let label = symbols.iter()
+            .find(|s| *s.symbol_type() == SymbolType::Label && s.offset() == Some(cfg.address(block.start) as u32));
+        if let Some(name) = label.map(|s| s.name()) {
+            line.push_str(&format!(" @{}", name));
+        }
+        if !successors.is_empty() {
+            line.push_str(&format!(" -> {}", successors.join(" ")));
+        }
+        match idom[b] {
+            Some(d) => line.push_str(&format!(", dominated by {}", d)),
+            None if !reachable[b] => line.push_str(", unreachable"),
+            None => {},
+        }
+        if block.indirect {
+            line.push_str(", indirect jump");
+        }
+        println!("{}", line);
+    }
+    for found in cfg.loops() {
+        let blocks: Vec<String> = found.blocks.iter().map(|b| b.to_string()).collect();
+        println!("loop at block {}: {}", found.header, blocks.join(" "));
+    }
 }
 
 /// Attempts to read a file and return the contents. Exits if unable to read the file for any reason.
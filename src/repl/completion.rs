@@ -0,0 +1,100 @@
+use rustyline::{
+    Context,
+    Helper,
+    completion::Completer,
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::Validator,
+};
+
+use crate::assembler::register_parsers::REGISTER_ALIASES;
+use crate::instructions::MNEMONICS;
+use crate::vm::REGISTER_COUNT;
+
+use super::COMMANDS;
+
+/// Tab completion for the REPL's line editor. Completes command names at the start of a line,
+/// mnemonics at the start of an instruction, `$` registers and `@` labels anywhere.
+#[derive(Default)]
+pub struct ReplHelper {
+    /// Names of the labels in the assembler's symbol table, refreshed before each line is read
+    pub labels: Vec<String>,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(complete(&line[..pos], &self.labels))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+/// Finds the candidates for the word that ends `line`, returning where that word starts
+pub fn complete(line: &str, labels: &[String]) -> (usize, Vec<String>) {
+    let start = line.char_indices().rev().find(|(_, c)| c.is_whitespace()).map(|(i, c)| i + c.len_utf8()).unwrap_or(0);
+    let word = &line[start..];
+    let first_word = line[..start].trim().is_empty();
+
+    let candidates: Vec<String> = if let Some(register) = word.strip_prefix('$') {
+        let numbers = (0..REGISTER_COUNT).map(|n| n.to_string());
+        let aliases = REGISTER_ALIASES.iter().map(|(alias, _)| alias.to_string());
+        numbers.chain(aliases)
+            .filter(|name| name.starts_with(register))
+            .map(|name| format!("${}", name))
+            .collect()
+    } else if let Some(label) = word.strip_prefix('@') {
+        labels.iter()
+            .filter(|name| name.starts_with(label))
+            .map(|name| format!("@{}", name))
+            .collect()
+    } else if first_word && word.starts_with('.') {
+        COMMANDS.iter()
+            .map(|command| command.name)
+            .filter(|name| name.starts_with(word))
+            .map(String::from)
+            .collect()
+    } else if first_word {
+        let word = word.to_lowercase();
+        MNEMONICS.iter()
+            .map(|(mnemonic, _)| *mnemonic)
+            .filter(|mnemonic| mnemonic.starts_with(&word))
+            .map(String::from)
+            .collect()
+    } else {
+        vec![]
+    };
+    (start, candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complete_commands_and_mnemonics() {
+        assert_eq!(complete(".rc", &[]), (0, vec![".rcontinue".to_string()]));
+        assert_eq!(complete("  jmp", &[]), (2, vec!["jmp".to_string(), "jmpf".to_string(), "jmpb".to_string(), "jmpe".to_string()]));
+        assert_eq!(complete("DJ", &[]), (0, vec!["djmpe".to_string()]));
+        assert_eq!(complete("load 12", &[]), (5, vec![]));
+        assert_eq!(complete("load\u{3000}$3", &[]), (7, vec!["$3".to_string(), "$30".to_string(), "$31".to_string()]));
+        assert_eq!(complete("inc\u{a0}\t$s1", &[]).0, 6);
+    }
+
+    #[test]
+    fn test_complete_registers_and_labels() {
+        assert_eq!(complete("load $3", &[]), (5, vec!["$3".to_string(), "$30".to_string(), "$31".to_string()]));
+        assert_eq!(complete(".set $s1", &[]), (5, vec!["$s1".to_string(), "$s10".to_string(), "$s11".to_string()]));
+        let labels = vec!["loop".to_string(), "done".to_string(), "looped".to_string()];
+        assert_eq!(complete(".break @lo", &labels), (7, vec!["@loop".to_string(), "@looped".to_string()]));
+    }
+}
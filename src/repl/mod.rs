@@ -1,144 +1,982 @@
 use std::{
     self,
-    io::{self, Write, Read},
-    path::Path,
+    sync::{Arc, Mutex},
+    io::{self, Write, Read, BufRead, IsTerminal},
+    path::{Path, PathBuf},
     num::ParseIntError,
     fs::File,
 };
 
-use crate::vm::VM;
-use crate::assembler::Assembler;
-use crate::assembler::program_parser::*;
+use rustyline::{Config, Editor, error::ReadlineError};
+
+pub mod views;
+pub mod completion;
+pub mod server;
+
+use self::completion::ReplHelper;
+
+use crate::instructions::{Instruction, Opcode, INSTRUCTION_LENGTH};
+use crate::vm::{VM, ExitReason, REGISTER_COUNT, CODE_START};
+use crate::vm::config::VmConfig;
+use crate::vm::snapshot::Snapshot;
+use crate::vm::watchpoints::{Watchpoint, WatchTarget, WatchCondition, WatchAction};
+use crate::assembler::{Assembler, Token};
+use crate::assembler::register_parsers::register;
+
+/// How many instructions the REPL's VM remembers for `.rstep` and `.rcontinue` unless changed
+/// with `.history_size`
+const DEFAULT_HISTORY_SIZE: usize = 10_000;
+
+/// Name of the file in the home directory that keeps the line editor's history
+const HISTORY_FILE: &str = ".iridium_history";
+/// How many lines of history are kept between sessions
+const MAX_HISTORY_LINES: usize = 1000;
+
+/// How large a sandboxed session's heap can grow, in bytes
+pub const SANDBOX_MAX_HEAP: usize = 16 * 1024 * 1024;
+/// How much a sandboxed session's programs can print in one run, in bytes
+pub const SANDBOX_MAX_OUTPUT: usize = 1024 * 1024;
+/// How much gas a sandboxed session can spend on each line
+pub const SANDBOX_GAS_PER_LINE: u64 = 10_000_000;
+/// The most instructions a sandboxed session can keep the history of
+pub const SANDBOX_MAX_HISTORY: usize = DEFAULT_HISTORY_SIZE;
+/// The longest line a sandboxed session reads, in bytes
+pub const SANDBOX_MAX_LINE: usize = 4096;
+/// How large a sandboxed session's program and read-only data can grow, in bytes
+pub const SANDBOX_MAX_PROGRAM: usize = 1024 * 1024;
+
+/// Limits on a REPL serving someone else, such as a client of `iridium serve`. Commands that
+/// use the host's files are turned off.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sandbox {
+    /// Limits the VM enforces
+    pub config: VmConfig,
+    /// Gas every line can spend running code, so a loop can't run forever
+    pub gas_per_line: u64,
+    /// The largest `.history_size` can be set to
+    pub max_history: usize,
+    /// Longer lines end the session
+    pub max_line_bytes: usize,
+    /// Code and data stop being accepted once the program is this large
+    pub max_program_bytes: usize,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            config: VmConfig {
+                max_heap_bytes: Some(SANDBOX_MAX_HEAP),
+                max_output_bytes: Some(SANDBOX_MAX_OUTPUT),
+                allowed_opcodes: None,
+            },
+            gas_per_line: SANDBOX_GAS_PER_LINE,
+            max_history: SANDBOX_MAX_HISTORY,
+            max_line_bytes: SANDBOX_MAX_LINE,
+            max_program_bytes: SANDBOX_MAX_PROGRAM,
+        }
+    }
+}
+
+/// Commands that read or write the host's files, which sandboxed sessions can't use
+const FILE_COMMANDS: &[&str] = &[".load_file", ".save", ".restore"];
+
+/// A REPL command. `run` is given the arguments that followed the command's name.
+struct Command {
+    name: &'static str,
+    /// Argument synopsis shown by `.help`
+    args: &'static str,
+    help: &'static str,
+    run: fn(&mut REPL, &[&str]),
+}
+
+/// Every command the REPL understands. `.help` is generated from this table.
+const COMMANDS: &[Command] = &[
+    Command { name: ".help", args: "[command]", help: "List the commands, or describe one of them", run: REPL::help },
+    Command { name: ".mode", args: "[asm|hex]", help: "Choose whether lines are assembly or raw hex bytes, or show which", run: REPL::mode },
+    Command { name: ".quit", args: "", help: "Leave the REPL", run: REPL::quit },
+    Command { name: ".history", args: "", help: "List everything entered so far", run: REPL::history },
+    Command { name: ".load_file", args: "<path>", help: "Assemble a file and load it into the VM", run: REPL::load_file },
+    Command { name: ".run", args: "", help: "Reset the VM and run the program from the start", run: REPL::run_program },
+    Command { name: ".reset", args: "", help: "Clear the registers, flags and heap and move the PC to the start, keeping the program", run: REPL::reset },
+    Command { name: ".set", args: "<$reg|pc|flag|remainder> <value>", help: "Change a register, the PC, equal_flag or remainder", run: REPL::set },
+    Command { name: ".program", args: "", help: "Dump the bytes of the program", run: REPL::program },
+    Command { name: ".registers", args: "", help: "Show the registers, marking the ones the last command changed", run: REPL::registers },
+    Command { name: ".flags", args: "", help: "Show equal_flag and remainder", run: REPL::flags },
+    Command { name: ".mem", args: "<heap|ro> [addr] [len]", help: "Dump part of the heap or read-only data", run: REPL::mem },
+    Command { name: ".disasm", args: "[addr] [count]", help: "Disassemble the program, around the PC by default", run: REPL::disasm },
+    Command { name: ".symbols", args: "", help: "List the symbol table", run: REPL::symbols },
+    Command { name: ".clear_program", args: "", help: "Remove the program from the VM", run: REPL::clear_program },
+    Command { name: ".clear_registers", args: "", help: "Set every register to 0", run: REPL::clear_registers },
+    Command { name: ".break", args: "[addr|@label]", help: "Set a breakpoint, or list them", run: REPL::set_breakpoint },
+    Command { name: ".clear_breakpoints", args: "", help: "Remove every breakpoint", run: REPL::clear_breakpoints },
+    Command { name: ".watch", args: "[<$reg|flag|remainder|heap addr len> [write|change] [break|log]]", help: "Set a watchpoint, or list them", run: REPL::watch },
+    Command { name: ".unwatch", args: "<number>", help: "Remove a watchpoint", run: REPL::unwatch },
+    Command { name: ".clear_watchpoints", args: "", help: "Remove every watchpoint", run: REPL::clear_watchpoints },
+    Command { name: ".step", args: "[count]", help: "Execute the next instruction, or count of them", run: REPL::step },
+    Command { name: ".next", args: "", help: "Execute the next instruction, stepping over calls", run: REPL::next },
+    Command { name: ".continue", args: "", help: "Run until the program stops or reaches a breakpoint", run: REPL::continue_running },
+    Command { name: ".rstep", args: "[count]", help: "Undo the last instruction, or count of them", run: REPL::rstep },
+    Command { name: ".rcontinue", args: "", help: "Undo instructions until a breakpoint or the start of the history", run: REPL::rcontinue },
+    Command { name: ".history_size", args: "[size]", help: "Set or show how many instructions can be undone", run: REPL::history_size },
+    Command { name: ".save", args: "<path>", help: "Save the state of the VM to a file", run: REPL::save },
+    Command { name: ".restore", args: "<path>", help: "Restore the VM from a file written by .save", run: REPL::restore },
+    Command { name: ".where", args: "", help: "Show the next instruction", run: REPL::where_am_i },
+    Command { name: ".assert", args: "<$reg|pc|flag|remainder> <==|!=|<|<=|>|>=> <value>", help: "Check a register, the PC, equal_flag or remainder, failing a script if it doesn't hold", run: REPL::assert },
+];
+
+/// How lines that aren't commands are read
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum InputMode {
+    /// Assembly, e.g. `load $1 #1000`
+    Assembly,
+    /// Raw bytecode as hex bytes, e.g. `01 01 03 E8`
+    Hex,
+}
+
+impl InputMode {
+    fn prompt(self) -> &'static str {
+        match self {
+            InputMode::Assembly => ">>> ",
+            InputMode::Hex => "hex> ",
+        }
+    }
+}
+
+/// Commands this close to a mistyped one are offered as suggestions
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+/// Writes a line to the REPL's output. Write errors are ignored: they mean the other end of a
+/// remote session has gone, and the session ends when its input does.
+macro_rules! outln {
+    ($repl:expr, $($arg:tt)*) => {{ let _ = writeln!($repl.out, $($arg)*); }};
+}
+
+/// Writes to the REPL's output without starting a new line
+macro_rules! out {
+    ($repl:expr, $($arg:tt)*) => {{ let _ = write!($repl.out, $($arg)*); }};
+}
+
+/// Writes a line to the REPL's error output
+macro_rules! errln {
+    ($repl:expr, $($arg:tt)*) => {{ let _ = writeln!($repl.err, $($arg)*); }};
+}
 
 pub struct REPL {
     command_buffer: Vec<String>,
+    /// Where responses are written
+    out: Box<dyn Write>,
+    /// Where errors are written
+    err: Box<dyn Write>,
+    /// Whether `out` is a terminal that can show colors
+    color: bool,
     // The VM the REPL will use to execute code
     vm: VM,
     asm: Assembler,
+    /// Breakpoints on labels that haven't been assembled yet. They are set once a file
+    /// defining them is loaded.
+    pending_breakpoints: Vec<String>,
+    /// Register contents before the last command that ran code, so `.registers` can show what
+    /// changed
+    previous_registers: [i32; REGISTER_COUNT],
+    /// Set by `.quit` to end the session
+    done: bool,
+    mode: InputMode,
+    /// How many `.assert` commands have failed
+    failed_assertions: usize,
+    /// Limits for a session serving someone else
+    sandbox: Option<Sandbox>,
 }
 
 impl REPL {
-    /// Creates and returns a new assembly REPL
+    /// Creates and returns a new assembly REPL that talks to the terminal
     pub fn new() -> Self {
+        let color = io::stdout().is_terminal();
+        Self::with_writers(Box::new(io::stdout()), Box::new(io::stderr()), color, VmConfig::default())
+    }
+
+    /// Creates a REPL that writes everything, including what programs print, to `output`. Pass
+    /// the lines to execute to `run_session` or `run_script`.
+    pub fn with_io<W: Write + Send + 'static>(output: W) -> Self {
+        Self::with_io_and_config(output, VmConfig::default())
+    }
+
+    /// Like `with_io`, but the session can't use more than `sandbox` allows
+    pub fn sandboxed<W: Write + Send + 'static>(output: W, sandbox: Sandbox) -> Self {
+        let mut repl = Self::with_io_and_config(output, sandbox.config.clone());
+        repl.vm.set_history_size(DEFAULT_HISTORY_SIZE.min(sandbox.max_history));
+        repl.sandbox = Some(sandbox);
+        repl
+    }
+
+    fn with_io_and_config<W: Write + Send + 'static>(output: W, config: VmConfig) -> Self {
+        let output = SharedWriter(Arc::new(Mutex::new(output)));
+        let mut repl = Self::with_writers(Box::new(output.clone()), Box::new(output.clone()), false, config);
+        repl.vm.set_output(Box::new(output));
+        repl
+    }
+
+    fn with_writers(out: Box<dyn Write>, err: Box<dyn Write>, color: bool, config: VmConfig) -> Self {
+        let mut vm = VM::with_config(config);
+        vm.set_history_size(DEFAULT_HISTORY_SIZE);
         REPL {
-            vm: VM::new(),
+            out,
+            err,
+            color,
+            vm,
             asm: Assembler::new(),
-            command_buffer: vec![]
+            command_buffer: vec![],
+            pending_breakpoints: vec![],
+            previous_registers: [0; REGISTER_COUNT],
+            done: false,
+            mode: InputMode::Assembly,
+            failed_assertions: 0,
+            sandbox: None,
         }
     }
 
+    /// Reads and executes lines until `.quit` or the end of input. Lines are read with a line
+    /// editor that keeps its history in `~/.iridium_history` between sessions.
     pub fn run(&mut self) {
-        println!("Welcome to Iridium! Let's be productive!");
-        loop {
-            // This allocates a new String in which to store whatever the user types each iteration.
-            // TODO: Figure out how to create this outside of the loop and re-use it every iteration
-            let mut buffer = String::new();
-
-            // Blocking call until the user types in a command.
-            let stdin = io::stdin();
-
-            // Annoyingly, `print!` does not automatically flush stdout like `println!` does so we
-            // have to do that there for the user to see our `>>> ` prompt.
-            print!(">>> ");
-            io::stdout().flush().expect("Unable to flush stdout");
-
-            // Here we'll look at the string the user gave us.
-            stdin.read_line(&mut buffer).expect("unable to read line from user");
+        outln!(self, "Welcome to Iridium! Let's be productive!");
+        let config = Config::builder()
+            .max_history_size(MAX_HISTORY_LINES)
+            .history_ignore_dups(true)
+            .build();
+        let mut editor = Editor::<ReplHelper>::with_config(config);
+        editor.set_helper(Some(ReplHelper::default()));
+        let history_path = history_path();
+        if let Some(path) = &history_path {
+            // There won't be a history file the first time the REPL is used
+            let _ = editor.load_history(path);
+        }
+        self.command_buffer.extend(editor.history().iter().cloned());
+
+        while !self.done {
+            // Labels can be defined by any line, so completion needs the latest ones
+            if let Some(helper) = editor.helper_mut() {
+                helper.labels = self.asm.symbols.iter().map(|symbol| symbol.name().to_string()).collect();
+            }
+
+            let buffer = match editor.readline(self.mode.prompt()) {
+                Ok(buffer) => buffer,
+                // Ctrl-C abandons the line being edited, like a shell
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    errln!(self, "Unable to read line from user: {}", e);
+                    break;
+                }
+            };
             let buffer = buffer.trim();
 
+            if !buffer.is_empty() {
+                editor.add_history_entry(buffer);
+            }
             self.command_buffer.push(buffer.to_string());
-            match buffer {
-                ".quit" => {
-                    println!("Farewell! Have a great day");
-                    std::process::exit(0);
-                },
-                ".history" => {
-                    for command in &self.command_buffer {
-                        println!("{}", command);
-                    }
-                },
-                ".program" => {
-                    println!("Listing instructions currently in the VM's program vector:");
-                    for instruction in &self.vm.program {
-                        println!("{}", instruction);
-                    }
-                    println!("End of Program Listing");
-                },
-                ".registers" => {
-                    println!("Listing registers and all contents:");
-                    println!("{:#?}", self.vm.registers);
-                    println!("End of Register Listing");
+            self.execute_line(buffer);
+        }
+
+        if let Some(path) = &history_path {
+            if let Err(e) = editor.save_history(path) {
+                errln!(self, "Unable to save history to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Executes every line of `script` as if it had been typed, stopping early at `.quit`. Each
+    /// line is echoed after the prompt so the output reads like a session. Blank lines and lines
+    /// starting with `#` are skipped. Returns how many assertions failed.
+    pub fn run_script<R: BufRead>(&mut self, script: R) -> io::Result<usize> {
+        self.execute_lines(script, true)?;
+        Ok(self.failed_assertions)
+    }
+
+    /// Runs an interactive session without a line editor, for a user on the other end of
+    /// `input` and the REPL's output, such as a network connection. A prompt is written before
+    /// each line is read, and the session lasts until `.quit` or the end of input.
+    pub fn run_session<R: BufRead>(&mut self, input: R) -> io::Result<()> {
+        outln!(self, "Welcome to Iridium! Let's be productive!");
+        self.execute_lines(input, false)
+    }
+
+    /// Executes lines from `input` until `.quit` or the end of input. Either each line is echoed
+    /// after the prompt, or the prompt is written before waiting for the line.
+    fn execute_lines<R: BufRead>(&mut self, mut input: R, echo: bool) -> io::Result<()> {
+        let max_line = self.sandbox.as_ref().map(|sandbox| sandbox.max_line_bytes);
+        while !self.done {
+            if !echo {
+                out!(self, "{}", self.mode.prompt());
+                let _ = self.out.flush();
+            }
+            let line = match read_line(&mut input, max_line) {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) => {
+                    errln!(self, "Unable to read line: {}", e);
+                    return Err(e);
+                }
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if echo {
+                outln!(self, "{}{}", self.mode.prompt(), line);
+            }
+            self.remember(line);
+            self.execute_line(line);
+        }
+        Ok(())
+    }
+
+    /// Adds a line to the list `.history` shows. Sandboxed sessions only keep the latest lines.
+    fn remember(&mut self, line: &str) {
+        self.command_buffer.push(line.to_string());
+        if self.sandbox.is_some() && self.command_buffer.len() > MAX_HISTORY_LINES {
+            self.command_buffer.remove(0);
+        }
+    }
+
+    /// Runs a command if the line starts with `.`, otherwise executes the line as assembly or
+    /// hex depending on the mode
+    fn execute_line(&mut self, line: &str) {
+        if let Some(sandbox) = &self.sandbox {
+            self.vm.set_gas(sandbox.gas_per_line);
+        }
+        if line.starts_with('.') {
+            self.execute_command(line);
+        } else if let Some(limit) = self.program_limit_reached() {
+            errln!(self, "The program can't grow past {} bytes in a remote session", limit);
+        } else if !line.is_empty() {
+            match self.mode {
+                InputMode::Assembly => self.execute_assembly(line),
+                InputMode::Hex => self.execute_hex(line),
+            }
+        }
+    }
+
+    fn execute_command(&mut self, line: &str) {
+        let words = match split_args(line) {
+            Ok(words) => words,
+            Err(e) => {
+                errln!(self, "{}", e);
+                return;
+            }
+        };
+        let args: Vec<&str> = words.iter().skip(1).map(String::as_str).collect();
+        let name = words[0].as_str();
+        match find_command(name) {
+            Some(command) if self.sandbox.is_some() && FILE_COMMANDS.contains(&command.name) => {
+                errln!(self, "{} isn't available in a remote session", command.name);
+            },
+            Some(command) => (command.run)(self, &args),
+            None => {
+                let suggestions = suggest_commands(name);
+                if suggestions.is_empty() {
+                    errln!(self, "Unknown command {}. Type .help for a list of commands", name);
+                } else {
+                    errln!(self, "Unknown command {}. Did you mean {}?", name, suggestions.join(" or "));
+                }
+            }
+        }
+    }
+
+    /// Assembles the line on top of everything typed or loaded so far, so labels and constants
+    /// declared earlier can be used, then executes the new instructions
+    fn execute_assembly(&mut self, line: &str) {
+        let start = self.code_end();
+        let assembled = match self.asm.assemble_more(line, start as u32) {
+            Ok(assembled) => assembled,
+            Err(errors) => {
+                for error in errors {
+                    errln!(self, "Unable to parse input: {}", error);
+                }
+                return;
+            }
+        };
+
+        self.vm.add_ro_data(&assembled.ro);
+        let count = assembled.code.len() / INSTRUCTION_LENGTH;
+        self.add_code(assembled.code);
+        self.run_new_code(start, count);
+    }
+
+    /// Checks that the bytes on the line are whole instructions, shows them as assembly and then
+    /// executes them
+    fn execute_hex(&mut self, line: &str) {
+        let bytes = match self.parse_hex(line) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                errln!(self, "Unable to parse hex: {}", e);
+                return;
+            }
+        };
+        let instructions = match decode_instructions(&bytes) {
+            Ok(instructions) => instructions,
+            Err(e) => {
+                errln!(self, "{}", e);
+                return;
+            }
+        };
+        for instruction in &instructions {
+            outln!(self, "  {}", instruction);
+        }
+
+        let start = self.code_end();
+        self.add_code(bytes);
+        self.run_new_code(start, instructions.len());
+    }
+
+    /// Executes `count` instructions that were just added at `start`
+    fn run_new_code(&mut self, start: usize, count: usize) {
+        if count == 0 {
+            return;
+        }
+        self.vm.set_pc(start);
+        self.previous_registers = self.vm.registers;
+        for _ in 0..count {
+            if let Some(reason) = self.vm.run_once() {
+                self.report_stop(Some(reason));
+                return;
+            }
+        }
+        self.print_watch_log();
+    }
+
+    /// The sandbox's program size limit, if the program and its read-only data have reached it
+    fn program_limit_reached(&self) -> Option<usize> {
+        let limit = self.sandbox.as_ref()?.max_program_bytes;
+        let size = self.vm.program().len() + self.vm.ro_data().len();
+        (size >= limit).then_some(limit)
+    }
+
+    /// The address the next piece of code added to the program will be loaded at
+    fn code_end(&self) -> usize {
+        self.vm.program().len().max(CODE_START)
+    }
+
+    /// Appends code typed into the REPL to the VM's program, starting the program with a header
+    /// if it is empty so that the code lands where the PC starts
+    fn add_code(&mut self, bytes: Vec<u8>) {
+        if self.vm.program().is_empty() {
+            self.vm.add_bytes(self.asm.write_pie_header());
+        }
+        self.vm.add_bytes(bytes);
+    }
+
+    fn mode(&mut self, args: &[&str]) {
+        match args.first() {
+            Some(&"asm") => self.mode = InputMode::Assembly,
+            Some(&"hex") => self.mode = InputMode::Hex,
+            Some(mode) => {
+                errln!(self, "Unknown mode {}. Use asm or hex", mode);
+                return;
+            },
+            None => {},
+        }
+        match self.mode {
+            InputMode::Assembly => outln!(self, "Lines are read as assembly"),
+            InputMode::Hex => outln!(self, "Lines are read as hex bytes, e.g. 01 01 03 E8"),
+        }
+    }
+
+    fn help(&mut self, args: &[&str]) {
+        match args.first() {
+            Some(name) => {
+                let name = if name.starts_with('.') { name.to_string() } else { format!(".{}", name) };
+                match find_command(&name) {
+                    Some(command) => {
+                        outln!(self, "Usage: {} {}", command.name, command.args);
+                        outln!(self, "{}", command.help);
+                    },
+                    None => errln!(self, "There is no command {}", name),
+                }
+            },
+            None => {
+                outln!(self, "Commands:");
+                for command in COMMANDS {
+                    outln!(self, "  {:<20}{}", command.name, command.help);
+                }
+                outln!(self, "Anything else is assembled and executed. Type .help <command> for its arguments.");
+            }
+        }
+    }
+
+    fn quit(&mut self, _args: &[&str]) {
+        outln!(self, "Farewell! Have a great day");
+        self.done = true;
+    }
+
+    fn history(&mut self, _args: &[&str]) {
+        for command in &self.command_buffer {
+            outln!(self, "{}", command);
+        }
+    }
+
+    fn load_file(&mut self, args: &[&str]) {
+        let filename = match args.first() {
+            Some(filename) => Path::new(filename),
+            None => {
+                errln!(self, "Usage: .load_file <path>");
+                return;
+            }
+        };
+        let mut f = match File::open(filename) {
+            Ok(f) => f,
+            Err(e) => {
+                errln!(self, "There was an error opening the file: {:?}", e);
+                return;
+            }
+        };
+        let mut contents = String::new();
+        if let Err(e) = f.read_to_string(&mut contents) {
+            errln!(self, "There was an error reading from the file: {:?}", e);
+            return;
+        }
+        // Files are assembled into the same session as typed code, so they can use its labels and
+        // are appended after it rather than starting a second program
+        match self.asm.assemble_more(&contents, self.code_end() as u32) {
+            Ok(assembled) => {
+                outln!(self, "Sending assembled program to the VM");
+                self.vm.add_ro_data(&assembled.ro);
+                self.add_code(assembled.code);
+                out!(self, "{}", views::hexdump(self.vm.program(), 0));
+                self.set_pending_breakpoints();
+                outln!(self, "Loaded {}. Use .run to start it", filename.display());
+            },
+            Err(errors) => {
+                for error in errors {
+                    errln!(self, "Unable to parse input: {}", error);
+                }
+            }
+        }
+    }
+
+    fn run_program(&mut self, _args: &[&str]) {
+        self.vm.reset();
+        self.previous_registers = self.vm.registers;
+        let reason = self.vm.run();
+        self.report_stop(Some(reason));
+    }
+
+    fn reset(&mut self, _args: &[&str]) {
+        self.vm.reset();
+        self.previous_registers = self.vm.registers;
+        outln!(self, "Reset the VM");
+        self.print_location();
+    }
+
+    fn set(&mut self, args: &[&str]) {
+        let (target, value) = match args {
+            [target, value] => (*target, *value),
+            _ => {
+                errln!(self, "Usage: .set <$reg|pc|flag|remainder> <value>");
+                return;
+            }
+        };
+        let result = match target {
+            "pc" => self.resolve_location(value).map(|pc| self.vm.set_pc(pc)),
+            "flag" => parse_flag(value).map(|flag| self.vm.set_equal_flag(flag)),
+            "remainder" => parse_number(value).map(|n| self.vm.set_remainder(n as u32)),
+            _ => match register(target) {
+                Ok(("", Token::Register { reg_num })) => parse_number(value).map(|n| {
+                    self.vm.registers[reg_num as usize] = n as i32;
+                }),
+                _ => Err(format!("Can't set {}. Use a register, pc, flag or remainder", target)),
+            },
+        };
+        if let Err(e) = result {
+            errln!(self, "{}", e);
+        }
+    }
+
+    /// Handles `.assert <target> <op> <value>`. Assertions that fail, or can't be understood, are
+    /// reported and counted so that a script running them exits with an error.
+    fn assert(&mut self, args: &[&str]) {
+        let result = match args {
+            [target, op, expected] => self.check_assertion(target, op, expected),
+            _ => Err("Usage: .assert <$reg|pc|flag|remainder> <==|!=|<|<=|>|>=> <value>".to_string()),
+        };
+        match result {
+            Ok(None) => {},
+            Ok(Some(actual)) => {
+                errln!(self, "Assertion failed: {} ({} is {})", args.join(" "), args[0], actual);
+                self.failed_assertions += 1;
+            },
+            Err(e) => {
+                errln!(self, "{}", e);
+                self.failed_assertions += 1;
+            }
+        }
+    }
+
+    /// Evaluates an assertion, returning the actual value of `target` if it doesn't hold
+    fn check_assertion(&self, target: &str, op: &str, expected: &str) -> Result<Option<String>, String> {
+        let (actual, expected, shown) = match target {
+            "pc" => (self.vm.pc() as i64, self.resolve_location(expected)? as i64, self.vm.pc().to_string()),
+            "flag" => (self.vm.equal_flag() as i64, parse_flag(expected)? as i64, self.vm.equal_flag().to_string()),
+            "remainder" => (self.vm.remainder() as i64, parse_number(expected)? as u32 as i64, self.vm.remainder().to_string()),
+            _ => match register(target) {
+                Ok(("", Token::Register { reg_num })) => {
+                    let value = self.vm.registers[reg_num as usize];
+                    (value as i64, parse_number(expected)? as i32 as i64, value.to_string())
                 },
-                ".symbols" => {
-                    println!("Listing symbols table:");
-                    println!("{:#?}", self.asm.symbols);
-                    println!("End of Symbols Listing");
+                _ => return Err(format!("Can't check {}. Use a register, pc, flag or remainder", target)),
+            },
+        };
+        let holds = match op {
+            "==" => actual == expected,
+            "!=" => actual != expected,
+            "<" => actual < expected,
+            "<=" => actual <= expected,
+            ">" => actual > expected,
+            ">=" => actual >= expected,
+            _ => return Err(format!("Unknown comparison {}. Use ==, !=, <, <=, > or >=", op)),
+        };
+        Ok(if holds { None } else { Some(shown) })
+    }
+
+    fn program(&mut self, _args: &[&str]) {
+        outln!(self, "Listing instructions currently in the VM's program vector:");
+        out!(self, "{}", views::hexdump(self.vm.program(), 0));
+        outln!(self, "End of Program Listing");
+    }
+
+    fn registers(&mut self, _args: &[&str]) {
+        outln!(self, "Listing registers and all contents:");
+        out!(self, "{}", views::register_grid(&self.vm.registers, &self.previous_registers, self.color));
+        outln!(self, "End of Register Listing");
+    }
+
+    fn flags(&mut self, _args: &[&str]) {
+        out!(self, "{}", views::flags(self.vm.equal_flag(), self.vm.remainder()));
+    }
+
+    /// Handles `.mem heap|ro [addr] [len]`, dumping 64 bytes from the start by default
+    fn mem(&mut self, args: &[&str]) {
+        let memory = match args.first() {
+            Some(&"heap") => self.vm.heap(),
+            Some(&"ro") => self.vm.ro_data(),
+            _ => {
+                errln!(self, "Usage: .mem heap|ro [addr] [len]");
+                return;
+            }
+        };
+        let (start, length) = match (parse_usize(args.get(1), 0), parse_usize(args.get(2), 64)) {
+            (Ok(start), Ok(length)) => (start, length),
+            (Err(e), _) | (_, Err(e)) => {
+                errln!(self, "{}", e);
+                return;
+            }
+        };
+        if start >= memory.len() {
+            outln!(self, "Address {} is outside the {} bytes in use", start, memory.len());
+            return;
+        }
+        let end = start.saturating_add(length).min(memory.len());
+        out!(self, "{}", views::hexdump(&memory[start..end], start));
+    }
+
+    /// Handles `.disasm [addr] [count]`. Without an address the listing starts a few
+    /// instructions before the PC so that it is shown in context.
+    fn disasm(&mut self, args: &[&str]) {
+        const CONTEXT: usize = 4;
+        let pc = self.vm.pc();
+        let start = match args.first() {
+            Some(location) => match self.resolve_location(location) {
+                Ok(address) => address,
+                Err(e) => {
+                    errln!(self, "{}", e);
+                    return;
+                }
+            },
+            None if pc >= CODE_START => pc - (CONTEXT * INSTRUCTION_LENGTH).min((pc - CODE_START) / INSTRUCTION_LENGTH * INSTRUCTION_LENGTH),
+            None => pc,
+        };
+        let count = match parse_usize(args.get(1), 2 * CONTEXT + 1) {
+            Ok(count) => count,
+            Err(e) => {
+                errln!(self, "{}", e);
+                return;
+            }
+        };
+
+        for address in (start..).step_by(INSTRUCTION_LENGTH).take(count) {
+            let instruction = match self.vm.instruction_at(address) {
+                Some(instruction) => instruction,
+                None => break,
+            };
+            let marker = if address == pc { "=>" } else { "  " };
+            let breakpoint = if self.vm.breakpoints().contains(&address) { "*" } else { " " };
+            outln!(self, "{}{} {:<16} {}", marker, breakpoint, self.describe_address(address), instruction);
+        }
+    }
+
+    fn symbols(&mut self, _args: &[&str]) {
+        outln!(self, "Listing symbols table:");
+        outln!(self, "{:#?}", self.asm.symbols);
+        outln!(self, "End of Symbols Listing");
+    }
+
+    fn clear_program(&mut self, _args: &[&str]) {
+        outln!(self, "Clearing program contents");
+        self.vm.clear_program();
+        self.asm = Assembler::new();
+    }
+
+    fn clear_registers(&mut self, _args: &[&str]) {
+        outln!(self, "Resetting all registers to 0");
+        for i in 0..self.vm.registers.len() {
+            self.vm.registers[i] = 0;
+        }
+    }
+
+    fn set_breakpoint(&mut self, args: &[&str]) {
+        match args.first() {
+            Some(location) => self.add_breakpoint(location),
+            None => {
+                outln!(self, "Breakpoints:");
+                for pc in self.vm.breakpoints() {
+                    outln!(self, "{}", self.describe_address(*pc));
+                }
+                for label in &self.pending_breakpoints {
+                    outln!(self, "@{} (not assembled yet)", label);
+                }
+                outln!(self, "End of Breakpoint Listing");
+            }
+        }
+    }
+
+    fn clear_breakpoints(&mut self, _args: &[&str]) {
+        outln!(self, "Removing all breakpoints");
+        self.vm.clear_breakpoints();
+        self.pending_breakpoints.clear();
+    }
+
+    fn watch(&mut self, args: &[&str]) {
+        if args.is_empty() {
+            outln!(self, "Watchpoints:");
+            for (index, watchpoint) in self.vm.watchpoints().iter().enumerate() {
+                outln!(self, "{}: {:?} on {:?}, {:?}", index, watchpoint.condition, watchpoint.target, watchpoint.action);
+            }
+            outln!(self, "End of Watchpoint Listing");
+            return;
+        }
+        match parse_watchpoint(args) {
+            Ok(watchpoint) => {
+                let index = self.vm.add_watchpoint(watchpoint);
+                outln!(self, "Watchpoint {} set", index);
+            },
+            Err(e) => errln!(self, "{}", e),
+        }
+    }
+
+    fn unwatch(&mut self, args: &[&str]) {
+        match args.first().map(|n| n.parse::<usize>()) {
+            Some(Ok(index)) => match self.vm.remove_watchpoint(index) {
+                Some(_) => outln!(self, "Removed watchpoint {}", index),
+                None => errln!(self, "There is no watchpoint {}", index),
+            },
+            Some(Err(e)) => errln!(self, "Invalid watchpoint number: {}", e),
+            None => errln!(self, "Usage: .unwatch <number>"),
+        }
+    }
+
+    fn clear_watchpoints(&mut self, _args: &[&str]) {
+        outln!(self, "Removing all watchpoints");
+        self.vm.clear_watchpoints();
+    }
+
+    fn step(&mut self, args: &[&str]) {
+        let count = match parse_usize(args.first(), 1) {
+            Ok(count) => count,
+            Err(e) => {
+                errln!(self, "{}", e);
+                return;
+            }
+        };
+        self.previous_registers = self.vm.registers;
+        let reason = self.vm.step(count);
+        self.report_stop(reason);
+    }
+
+    fn next(&mut self, _args: &[&str]) {
+        self.previous_registers = self.vm.registers;
+        let reason = self.vm.step_over();
+        self.report_stop(reason);
+    }
+
+    fn continue_running(&mut self, _args: &[&str]) {
+        self.previous_registers = self.vm.registers;
+        let reason = self.vm.resume();
+        self.report_stop(Some(reason));
+    }
+
+    fn rstep(&mut self, args: &[&str]) {
+        let count = match parse_usize(args.first(), 1) {
+            Ok(count) => count,
+            Err(e) => {
+                errln!(self, "{}", e);
+                return;
+            }
+        };
+        self.previous_registers = self.vm.registers;
+        if self.vm.step_back(count) < count {
+            outln!(self, "Reached the start of the history");
+        }
+        self.print_location();
+    }
+
+    fn rcontinue(&mut self, _args: &[&str]) {
+        self.previous_registers = self.vm.registers;
+        match self.vm.reverse_continue() {
+            Some(reason) => outln!(self, "{}", reason),
+            None => outln!(self, "Reached the start of the history"),
+        }
+        self.print_location();
+    }
+
+    fn history_size(&mut self, args: &[&str]) {
+        match args.first().map(|n| n.parse::<usize>()) {
+            Some(Ok(size)) => match &self.sandbox {
+                Some(sandbox) if size > sandbox.max_history => {
+                    self.vm.set_history_size(sandbox.max_history);
+                    outln!(self, "Keeping the last {} instructions, the most a remote session can", sandbox.max_history);
                 },
-                ".clear_program" => {
-                    println!("Clearing program contents");
-                    self.vm.program.clear();
+                _ => {
+                    self.vm.set_history_size(size);
+                    outln!(self, "Keeping the last {} instructions", size);
                 },
-                ".clear_registers" => {
-                    println!("Resetting all registers to 0");
-                    for i in 0..self.vm.registers.len() {
-                        self.vm.registers[i] = 0;
-                    }
+            },
+            Some(Err(e)) => errln!(self, "Invalid history size: {}", e),
+            None => outln!(self, "Keeping the last {} instructions, {} recorded", self.vm.history_size(), self.vm.history_len()),
+        }
+    }
+
+    fn save(&mut self, args: &[&str]) {
+        match args.first() {
+            Some(path) => match self.save_snapshot(path) {
+                Ok(()) => outln!(self, "Saved the VM to {}", path),
+                Err(e) => errln!(self, "Unable to save the VM to {}: {}", path, e),
+            },
+            None => errln!(self, "Usage: .save <path>"),
+        }
+    }
+
+    fn restore(&mut self, args: &[&str]) {
+        match args.first() {
+            Some(path) => match File::open(path).and_then(|mut f| Snapshot::read_from(&mut f)) {
+                Ok(snapshot) => match self.vm.restore(&snapshot) {
+                    Ok(()) => {
+                        outln!(self, "Restored the VM from {}", path);
+                        self.print_location();
+                    },
+                    Err(fault) => errln!(self, "Unable to restore the VM from {}: {}", path, fault),
                 },
-                ".load_file" => {
-                    print!("Please enter the path to the file you wish to load: ");
-                    io::stdout().flush().expect("Unable to flush stdout");
-                    let mut tmp = String::new();
-                    stdin.read_line(&mut tmp).expect("Unable to read line from user");
-                    let tmp = tmp.trim();
-                    let filename = Path::new(&tmp);
-                    let mut f = match File::open(filename) {
-                        Ok(f) => f,
-                        Err(e) => {
-                            eprintln!("There was an error opening the file: {:?}", e);
-                            continue;
-                        }
-                    };
-                    let mut contents = String::new();
-                    f.read_to_string(&mut contents).expect("There was an error reading from the file");
-                    match self.asm.assemble(&contents) {
-                        Ok(mut assembled_program) => {
-                            println!("Sending assembled program to the VM");
-                            self.vm.program.append(&mut assembled_program);
-                            println!("{:#?}", self.vm.program);
-                            self.vm.run();
-                        },
-                        Err(errors) => {
-                            for error in errors {
-                                eprintln!("Unable to parse input: {}", error);
-                            }
-                            continue;
-                        }
-                    }
+                Err(e) => errln!(self, "Unable to restore the VM from {}: {}", path, e),
+            },
+            None => errln!(self, "Usage: .restore <path>"),
+        }
+    }
+
+    fn where_am_i(&mut self, _args: &[&str]) {
+        self.print_location();
+    }
+
+    /// Sets a breakpoint on an address or a `@label`. Labels that aren't in the symbol table yet
+    /// are remembered until a file that defines them is loaded.
+    fn add_breakpoint(&mut self, location: &str) {
+        if let Some(label) = location.strip_prefix('@') {
+            if self.asm.symbols.symbol_value(label).is_none() {
+                outln!(self, "Breakpoint on @{} will be set when it is assembled", label);
+                self.pending_breakpoints.push(label.to_string());
+                return;
+            }
+        }
+
+        match self.resolve_location(location) {
+            Ok(pc) => {
+                self.vm.add_breakpoint(pc);
+                outln!(self, "Breakpoint set at {}", self.describe_address(pc));
+            },
+            Err(e) => errln!(self, "{}", e),
+        }
+    }
+
+    /// Sets the breakpoints on labels that now have an address
+    fn set_pending_breakpoints(&mut self) {
+        let pending = std::mem::take(&mut self.pending_breakpoints);
+        for label in pending {
+            match self.asm.symbols.symbol_value(&label) {
+                Some(pc) => {
+                    self.vm.add_breakpoint(pc as usize);
+                    outln!(self, "Breakpoint set at {}", self.describe_address(pc as usize));
                 },
-                _ => {
-                    let program = match program(buffer.into()) {
-                        Ok((_, program)) => program,
-                        Err(e) => {
-                            eprintln!("Unable to parse input: {:?}", e);
-                            continue;
-                        }
-                    };
-
-                    self.vm.program.append(&mut program.to_bytes(&self.asm.symbols));
-                    self.vm.run_once();
-                }
+                None => self.pending_breakpoints.push(label),
             }
         }
     }
 
-    #[allow(dead_code)]
+    /// Turns a location, either an address or a `@label`, into an address
+    fn resolve_location(&self, location: &str) -> Result<usize, String> {
+        match location.strip_prefix('@') {
+            Some(label) => self.asm.symbols.symbol_value(label)
+                .map(|offset| offset as usize)
+                .ok_or(format!("Unknown label: @{}", label)),
+            None => location.parse::<usize>()
+                .map_err(|e| format!("Invalid address {}: {}", location, e)),
+        }
+    }
+
+    /// Describes an address relative to the closest label before it, e.g. `77 <loop+4>`
+    fn describe_address(&self, pc: usize) -> String {
+        match self.asm.symbols.label_before(pc as u32) {
+            Some(symbol) => {
+                let offset = symbol.offset().unwrap_or(0) as usize;
+                format!("{} <{}+{}>", pc, symbol.name(), pc - offset)
+            },
+            None => pc.to_string(),
+        }
+    }
+
+    /// Prints the address and instruction the VM will execute next
+    fn print_location(&mut self) {
+        let pc = self.vm.pc();
+        let location = self.describe_address(pc);
+        match self.vm.current_instruction() {
+            Some(instruction) => outln!(self, "{}: {}", location, instruction),
+            None => outln!(self, "{}: past the end of the program", location),
+        }
+    }
+
+    /// Prints why execution stopped, if it did, and where the VM is now
+    fn report_stop(&mut self, reason: Option<ExitReason>) {
+        self.print_watch_log();
+        if let Some(reason) = reason {
+            outln!(self, "{}", reason);
+        }
+        self.print_location();
+    }
+
+    fn save_snapshot(&self, path: &str) -> io::Result<()> {
+        let mut f = io::BufWriter::new(File::create(path)?);
+        self.vm.snapshot().write_to(&mut f)?;
+        f.flush()
+    }
+
+    fn print_watch_log(&mut self) {
+        for hit in self.vm.take_watch_log() {
+            outln!(self, "Watchpoint: {}", hit);
+        }
+    }
+
     /// Accepts a hexadecimal string without the prefix `0x` and returns a Vec of u8
     /// Example for a LOAD command: 01 01 03 E8
     fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, ParseIntError> {
-        let split = i.split(" ").collect::<Vec<&str>>();
+        let split = i.split_whitespace().collect::<Vec<&str>>();
         let mut results: Vec<u8> = vec![];
         for hex_string in split {
-            let byte = u8::from_str_radix(&hex_string, 16);
+            let byte = u8::from_str_radix(hex_string, 16);
             match byte {
                 Ok(result) => results.push(result),
                 Err(e) => return Err(e),
@@ -149,8 +987,380 @@ impl REPL {
     }
 }
 
+/// Decodes bytes entered in hex mode, which must be whole instructions with known opcodes
+fn decode_instructions(bytes: &[u8]) -> Result<Vec<Instruction>, String> {
+    if bytes.is_empty() || !bytes.len().is_multiple_of(INSTRUCTION_LENGTH) {
+        return Err(format!("{} bytes don't make up whole instructions of {} bytes each", bytes.len(), INSTRUCTION_LENGTH));
+    }
+    bytes.chunks(INSTRUCTION_LENGTH)
+        .enumerate()
+        .map(|(i, chunk)| match Instruction::decode(chunk) {
+            Instruction { opcode: Opcode::IGL, .. } => Err(format!("{:02X} at byte {} isn't a known opcode", chunk[0], i * INSTRUCTION_LENGTH)),
+            instruction => Ok(instruction),
+        })
+        .collect()
+}
+
+/// Reads one line without its line ending, or `None` at the end of input. No more than `limit`
+/// bytes are read looking for the end of the line, so a client can't make the REPL keep an
+/// endless line in memory; a longer line is an error.
+fn read_line<R: BufRead>(input: &mut R, limit: Option<usize>) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    let read = match limit {
+        // One more byte to leave room for the line ending
+        Some(limit) => input.take(limit as u64 + 1).read_line(&mut line)?,
+        None => input.read_line(&mut line)?,
+    };
+    if read == 0 {
+        return Ok(None);
+    }
+    let line = line.trim_end_matches(&['\r', '\n'][..]);
+    match limit {
+        Some(limit) if line.len() > limit => {
+            Err(io::Error::new(io::ErrorKind::InvalidData, format!("lines can't be longer than {} bytes", limit)))
+        },
+        _ => Ok(Some(line.to_string())),
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(HISTORY_FILE))
+}
+
+fn find_command(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// Commands that start with `name` or are only a couple of typos away from it
+fn suggest_commands(name: &str) -> Vec<&'static str> {
+    COMMANDS.iter()
+        .filter(|command| command.name.starts_with(name) || edit_distance(command.name, name) <= MAX_SUGGESTION_DISTANCE)
+        .map(|command| command.name)
+        .collect()
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Splits a command line into words on whitespace. Double quotes group words, so paths with
+/// spaces can be given as `"my file.iasm"`.
+fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            },
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            },
+            c => {
+                word.push(c);
+                in_word = true;
+            },
+        }
+    }
+    if quoted {
+        return Err("Missing closing quote".to_string());
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Parses an optional numeric command argument, using `default` when it is missing
+fn parse_usize(arg: Option<&&str>, default: usize) -> Result<usize, String> {
+    match arg {
+        Some(text) => text.parse::<usize>().map_err(|e| format!("Invalid number {}: {}", text, e)),
+        None => Ok(default),
+    }
+}
+
+/// Parses a value for `.set`, which may be negative or hexadecimal with a `0x` prefix. Values
+/// are 32 bits, so anything from `i32::MIN` to `u32::MAX` is accepted.
+fn parse_number(text: &str) -> Result<i64, String> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let magnitude = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse::<i64>(),
+    }.map_err(|e| format!("Invalid number {}: {}", text, e))?;
+    let value = if negative { -magnitude } else { magnitude };
+    if value < i32::MIN as i64 || value > u32::MAX as i64 {
+        return Err(format!("{} doesn't fit in 32 bits", text));
+    }
+    Ok(value)
+}
+
+fn parse_flag(text: &str) -> Result<bool, String> {
+    match text {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(format!("equal_flag can only be true or false, not {}", text)),
+    }
+}
+
+/// Parses the arguments of `.watch`: a target (`$register`, `flag`, `remainder` or
+/// `heap <start> <length>`) followed by optional `write`/`change` and `break`/`log` keywords.
+/// Watchpoints break on a change by default.
+fn parse_watchpoint(args: &[&str]) -> Result<Watchpoint, String> {
+    let (target, options) = match args {
+        ["flag", rest @ ..] => (WatchTarget::EqualFlag, rest),
+        ["remainder", rest @ ..] => (WatchTarget::Remainder, rest),
+        ["heap", start, length, rest @ ..] => {
+            let start = start.parse::<usize>().map_err(|e| format!("Invalid heap address {}: {}", start, e))?;
+            let length = length.parse::<usize>().map_err(|e| format!("Invalid length {}: {}", length, e))?;
+            (WatchTarget::Heap { start, length }, rest)
+        },
+        [reg, rest @ ..] => match register(reg) {
+            Ok(("", Token::Register { reg_num })) => (WatchTarget::Register(reg_num), rest),
+            _ => return Err(format!("Can't watch {}. Use a register, flag, remainder or heap <start> <length>", reg)),
+        },
+        [] => return Err("Usage: .watch <target> [write|change] [break|log]".to_string()),
+    };
+
+    let mut watchpoint = Watchpoint::new(target, WatchCondition::Change, WatchAction::Break);
+    for option in options {
+        match *option {
+            "write" => watchpoint.condition = WatchCondition::Write,
+            "change" => watchpoint.condition = WatchCondition::Change,
+            "break" => watchpoint.action = WatchAction::Break,
+            "log" => watchpoint.action = WatchAction::Log,
+            _ => return Err(format!("Unknown watchpoint option: {}", option)),
+        }
+    }
+    Ok(watchpoint)
+}
+
+/// Lets the REPL's responses, its errors and the program's output share one writer
+struct SharedWriter<W>(Arc<Mutex<W>>);
+
+impl<W> Clone for SharedWriter<W> {
+    fn clone(&self) -> Self {
+        SharedWriter(Arc::clone(&self.0))
+    }
+}
+
+impl<W: Write> Write for SharedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).flush()
+    }
+}
+
 impl Default for REPL {
     fn default() -> Self {
         Self::new()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Collects everything a REPL writes
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Output {
+        /// Everything written since the last call
+        fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+        }
+    }
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn repl() -> (REPL, Output) {
+        let output = Output::default();
+        (REPL::with_io(output.clone()), output)
+    }
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(split_args(".set  $3 42"), Ok(vec![".set".to_string(), "$3".to_string(), "42".to_string()]));
+        assert_eq!(split_args(r#".load_file "my file.iasm""#), Ok(vec![".load_file".to_string(), "my file.iasm".to_string()]));
+        assert_eq!(split_args(r#".x """#), Ok(vec![".x".to_string(), "".to_string()]));
+        assert!(split_args(r#".load_file "oops"#).is_err());
+    }
+
+    #[test]
+    fn test_commands_are_unique() {
+        for command in COMMANDS {
+            assert!(command.name.starts_with('.'));
+            assert_eq!(COMMANDS.iter().filter(|c| c.name == command.name).count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_suggest_commands() {
+        assert_eq!(suggest_commands(".contnue"), vec![".continue", ".rcontinue"]);
+        assert_eq!(suggest_commands(".restor"), vec![".restore"]);
+        assert_eq!(suggest_commands(".clear_b"), vec![".clear_breakpoints"]);
+        assert_eq!(suggest_commands(".regs"), Vec::<&str>::new());
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("42"), Ok(42));
+        assert_eq!(parse_number("-7"), Ok(-7));
+        assert_eq!(parse_number("0xff"), Ok(255));
+        assert_eq!(parse_number("0xffffffff"), Ok(u32::MAX as i64));
+        assert!(parse_number("0x100000000").is_err());
+        assert!(parse_number("twelve").is_err());
+    }
+
+    #[test]
+    fn test_decode_instructions() {
+        let load = Instruction::decode(&[1, 1, 3, 0xE8]);
+        assert_eq!(decode_instructions(&[1, 1, 3, 0xE8, 0, 0, 0, 0]), Ok(vec![load, Instruction::new(Opcode::HLT)]));
+        assert!(decode_instructions(&[1, 1, 3]).is_err());
+        assert!(decode_instructions(&[]).is_err());
+        assert_eq!(decode_instructions(&[1, 1, 3, 0xE8, 0xC8, 0, 0, 0]), Err("C8 at byte 4 isn't a known opcode".to_string()));
+    }
+
+    #[test]
+    fn test_hex_mode() {
+        let (mut repl, output) = repl();
+        repl.execute_line(".mode hex");
+        assert_eq!(output.take(), "Lines are read as hex bytes, e.g. 01 01 03 E8\n");
+        repl.execute_line("01 01 03 E8");
+        repl.execute_line("01 02 00 05  02 02 00 00");
+        assert_eq!(output.take(), "  load $1 #1000\n  load $2 #5\n  inc $2\n");
+        repl.execute_line("01 02");
+        assert_eq!(output.take(), "2 bytes don't make up whole instructions of 4 bytes each\n");
+        assert_eq!(repl.vm.registers[1], 1000);
+        assert_eq!(repl.vm.registers[2], 6);
+
+        repl.execute_line(".mode asm");
+        repl.execute_line("inc $1");
+        assert_eq!(repl.vm.registers[1], 1001);
+    }
+
+    #[test]
+    fn test_incremental_assembly() {
+        let (mut repl, output) = repl();
+        repl.execute_line("load $0 #3");
+        repl.execute_line("top: dec $0");
+        repl.execute_line("msg: .asciiz 'Done'");
+        repl.execute_line("load $1 @top");
+        assert_eq!(repl.vm.registers[0], 2);
+        assert_eq!(repl.vm.registers[1], 69);
+        assert_eq!(repl.vm.ro_data(), b"Done\0");
+        assert_eq!(repl.vm.program().len(), CODE_START + 12);
+
+        // A halt leaves the pc part way through the instruction, which mustn't affect the next line
+        repl.execute_line("hlt");
+        repl.execute_line("inc $0");
+        assert_eq!(repl.vm.registers[0], 3);
+
+        repl.execute_line("load $2 @nowhere");
+        assert_eq!(repl.vm.program().len(), CODE_START + 20);
+        assert!(output.take().ends_with("Unable to parse input: No label named nowhere has been declared\n"));
+
+        repl.execute_line(".clear_program");
+        repl.execute_line("top: inc $0");
+        assert_eq!(repl.vm.registers[0], 4);
+        assert!(repl.vm.ro_data().is_empty());
+        assert_eq!(output.take(), "Clearing program contents\n");
+    }
+
+    #[test]
+    fn test_assert_command() {
+        let (mut repl, output) = repl();
+        repl.execute_line("load $2 #7");
+        repl.execute_line(".assert $2 == 7");
+        repl.execute_line(".assert $a2 >= 0x7");
+        repl.execute_line(".assert flag == false");
+        repl.execute_line(".assert pc == 69");
+        assert_eq!(repl.failed_assertions, 0);
+        assert_eq!(output.take(), "");
+
+        repl.execute_line(".assert $2 != 7");
+        repl.execute_line(".assert $2 < -1");
+        repl.execute_line(".assert $2 =~ 7");
+        repl.execute_line(".assert $2");
+        assert_eq!(repl.failed_assertions, 4);
+        assert_eq!(output.take(), [
+            "Assertion failed: $2 != 7 ($2 is 7)",
+            "Assertion failed: $2 < -1 ($2 is 7)",
+            "Unknown comparison =~. Use ==, !=, <, <=, > or >=",
+            "Usage: .assert <$reg|pc|flag|remainder> <==|!=|<|<=|>|>=> <value>",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_run_script() {
+        let script = "# Count down from 3\n\
+                      load $0 #3\n\
+                      loop: dec $0\n\
+                      .assert $0 == 2\n\
+                      \n\
+                      .assert remainder != 0\n\
+                      .quit\n\
+                      .assert $0 == 9\n";
+        let (mut repl, output) = repl();
+        assert_eq!(repl.run_script(script.as_bytes()).unwrap(), 1);
+        assert_eq!(repl.command_buffer.len(), 5);
+        assert!(repl.done);
+        let output = output.take();
+        assert!(output.contains(">>> .assert remainder != 0\nAssertion failed: remainder != 0 (remainder is 0)\n"));
+        assert!(output.ends_with(">>> .quit\nFarewell! Have a great day\n"));
+        assert!(!output.contains("$0 == 9"));
+    }
+
+    #[test]
+    fn test_set_command() {
+        let (mut repl, output) = repl();
+        repl.execute_line(".set $t0 -5");
+        repl.execute_line(".set remainder 0x10");
+        repl.execute_line(".set flag true");
+        repl.execute_line(".set pc 69");
+        assert_eq!(repl.vm.registers[4], -5);
+        assert_eq!(repl.vm.remainder(), 16);
+        assert!(repl.vm.equal_flag());
+        assert_eq!(repl.vm.pc(), 69);
+        assert_eq!(output.take(), "");
+
+        repl.execute_line(".reset");
+        assert!(output.take().starts_with("Reset the VM\n"));
+        assert_eq!(repl.vm.registers[4], 0);
+        assert_eq!(repl.vm.pc(), CODE_START);
+    }
+}
@@ -0,0 +1,255 @@
+//! Serves REPL sessions over TCP. Every connection gets its own REPL and VM on its own thread,
+//! so sessions don't see each other's programs. Sessions are sandboxed: the VM's heap, output
+//! and the gas each line can spend are limited, as are the lengths of lines and the size of the
+//! program and its history. Commands that use the server's files are turned off, idle sessions
+//! are ended, and only so many sessions run at once. Listening anywhere but loopback needs a
+//! secret.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, atomic::{AtomicUsize, Ordering}},
+    thread,
+    time::Duration,
+};
+
+use log::{info, warn};
+
+use super::{Sandbox, REPL};
+
+/// Address `iridium serve` listens on unless told otherwise
+pub const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:2244";
+/// How many sessions can run at once unless told otherwise
+pub const DEFAULT_MAX_SESSIONS: usize = 16;
+/// The longest line read while waiting for the secret, in bytes
+const MAX_SECRET_LINE: u64 = 1024;
+/// How long a client has to send the secret
+const SECRET_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a session waits for a client unless told otherwise
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How the server treats the clients that connect
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    /// When set, a client has to send this as its first line before it gets a session
+    pub secret: Option<String>,
+    /// Limits on every session
+    pub sandbox: Sandbox,
+    /// Clients that connect while this many sessions are running are turned away
+    pub max_sessions: usize,
+    /// A session ends when its client sends nothing, or reads nothing it was sent, for this long
+    pub idle_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            secret: None,
+            sandbox: Sandbox::default(),
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+}
+
+/// Holds one of the server's sessions, giving it back when dropped
+struct SessionSlot(Arc<AtomicUsize>);
+
+impl SessionSlot {
+    fn take(active: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        if active.fetch_add(1, Ordering::SeqCst) >= max {
+            active.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(SessionSlot(Arc::clone(active)))
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Listens on `address` and serves a REPL to everyone who connects. Addresses other machines
+/// can reach are refused unless `config` has a secret.
+pub fn serve(address: &str, config: ServerConfig) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    if config.secret.is_none() && !listener.local_addr()?.ip().is_loopback() {
+        let message = format!("{} can be reached from other machines, so a secret is needed", address);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    info!("Serving REPL sessions on {}", listener.local_addr()?);
+    serve_on(listener, config)
+}
+
+/// Accepts connections on `listener` until it fails, starting a session for each one
+pub fn serve_on(listener: TcpListener, config: ServerConfig) -> io::Result<()> {
+    let config = Arc::new(config);
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        let slot = match SessionSlot::take(&active, config.max_sessions) {
+            Some(slot) => slot,
+            None => {
+                let _ = stream.write_all(b"Too many sessions, try again later\n");
+                continue;
+            }
+        };
+        let config = Arc::clone(&config);
+        thread::spawn(move || {
+            let _slot = slot;
+            let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| "unknown".to_string());
+            info!("Session started for {}", peer);
+            if let Err(e) = run_connection(stream, &config) {
+                warn!("Session for {} failed: {}", peer, e);
+            }
+            info!("Session ended for {}", peer);
+        });
+    }
+    Ok(())
+}
+
+fn run_connection(stream: TcpStream, config: &ServerConfig) -> io::Result<()> {
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut output = stream;
+    output.set_write_timeout(Some(config.idle_timeout))?;
+
+    if let Some(secret) = &config.secret {
+        output.write_all(b"Secret: ")?;
+        // Until the client is known, it can't hold on to the connection or send much
+        output.set_read_timeout(Some(SECRET_TIMEOUT))?;
+        if !secrets_match(&read_secret(&mut input)?, secret) {
+            output.write_all(b"Authentication failed\n")?;
+            return Ok(());
+        }
+    }
+    output.set_read_timeout(Some(config.idle_timeout))?;
+
+    REPL::sandboxed(output, config.sandbox.clone()).run_session(input)
+}
+
+/// Reads the line a client sends as the secret, reading no more than `MAX_SECRET_LINE` bytes
+fn read_secret<R: BufRead>(input: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    input.take(MAX_SECRET_LINE).read_line(&mut line)?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// Compares every byte whatever the outcome, so how long this takes doesn't give away how much
+/// of a guess was right
+fn secrets_match(given: &str, secret: &str) -> bool {
+    given.len() == secret.len()
+        && given.bytes().zip(secret.bytes()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use crate::vm::CODE_START;
+
+    fn start_server(secret: Option<&str>) -> std::net::SocketAddr {
+        start_server_with(ServerConfig { secret: secret.map(String::from), ..ServerConfig::default() })
+    }
+
+    fn start_server_with(config: ServerConfig) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || serve_on(listener, config));
+        address
+    }
+
+    fn session(address: std::net::SocketAddr, input: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(input.as_bytes()).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_sessions_are_independent() {
+        let address = start_server(None);
+        let first = session(address, "load $1 #42\n.assert $1 == 42\n.quit\n");
+        let second = session(address, ".assert $1 == 42\n.quit\n");
+        assert!(first.starts_with("Welcome to Iridium!"));
+        assert!(!first.contains("Assertion failed"));
+        assert!(second.contains("Assertion failed: $1 == 42 ($1 is 0)"));
+        assert!(second.ends_with(">>> Farewell! Have a great day\n"));
+    }
+
+    #[test]
+    fn test_program_output_goes_to_the_client() {
+        let address = start_server(None);
+        let output = session(address, "msg: .asciiz 'Hello there'\nprts @msg\n");
+        assert!(output.contains("Hello there"));
+    }
+
+    #[test]
+    fn test_secret() {
+        let address = start_server(Some("opensesame"));
+        assert_eq!(session(address, "guess\n"), "Secret: Authentication failed\n");
+        assert!(session(address, "opensesame\n.quit\n").contains("Welcome to Iridium!"));
+        assert!(!secrets_match("opensesam", "opensesame"));
+        let flood = "x".repeat(MAX_SECRET_LINE as usize * 4);
+        assert_eq!(read_secret(&mut flood.as_bytes()).unwrap().len(), MAX_SECRET_LINE as usize);
+        assert_eq!(read_secret(&mut &b"opensesame\r\nhlt\n"[..]).unwrap(), "opensesame");
+    }
+
+    #[test]
+    fn test_sessions_are_limited() {
+        let sandbox = Sandbox { gas_per_line: 1000, ..Sandbox::default() };
+        let address = start_server_with(ServerConfig { sandbox, ..ServerConfig::default() });
+        let output = session(address, "load $0 @spin spin: jmp $0\n.run\n.quit\n");
+        assert!(output.contains("Ran out of gas"));
+        assert!(output.ends_with(">>> Farewell! Have a great day\n"));
+
+        // Hold the only session open while another client tries to connect
+        let address = start_server_with(ServerConfig { max_sessions: 1, ..ServerConfig::default() });
+        let mut first = TcpStream::connect(address).unwrap();
+        let mut welcome = [0; 8];
+        first.read_exact(&mut welcome).unwrap();
+        let mut refused = String::new();
+        TcpStream::connect(address).unwrap().read_to_string(&mut refused).unwrap();
+        assert_eq!(refused, "Too many sessions, try again later\n");
+        first.write_all(b".quit\n").unwrap();
+        first.shutdown(std::net::Shutdown::Write).unwrap();
+        first.read_to_end(&mut vec![]).unwrap();
+    }
+
+    #[test]
+    fn test_files_and_addresses_are_protected() {
+        let address = start_server(None);
+        let output = session(address, ".load_file /etc/passwd\n.save /tmp/x\n.restore /tmp/x\n");
+        assert!(output.contains(".load_file isn't available in a remote session"));
+        assert!(output.contains(".save isn't available in a remote session"));
+        assert!(output.contains(".restore isn't available in a remote session"));
+
+        let refused = serve("0.0.0.0:0", ServerConfig::default()).unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_session_memory_is_bounded() {
+        let sandbox = Sandbox { max_line_bytes: 32, max_program_bytes: CODE_START + 8, ..Sandbox::default() };
+        let address = start_server_with(ServerConfig { sandbox, ..ServerConfig::default() });
+        let output = session(address, ".history_size 1000000\ninc $0\ninc $0\ninc $0\n.assert $0 == 2\n");
+        assert!(output.contains("Keeping the last 10000 instructions, the most a remote session can"));
+        assert!(output.contains("The program can't grow past 73 bytes in a remote session"));
+        assert!(!output.contains("Assertion failed"));
+
+        let output = session(address, &"x".repeat(33));
+        assert!(output.ends_with("Unable to read line: lines can't be longer than 32 bytes\n"));
+    }
+
+    #[test]
+    fn test_idle_sessions_end() {
+        let address = start_server_with(ServerConfig { idle_timeout: Duration::from_millis(50), ..ServerConfig::default() });
+        let mut output = String::new();
+        TcpStream::connect(address).unwrap().read_to_string(&mut output).unwrap();
+        assert!(output.starts_with("Welcome to Iridium!"));
+        assert!(output.contains("Unable to read line"));
+    }
+}
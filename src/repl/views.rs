@@ -0,0 +1,100 @@
+use std::fmt::Write;
+
+use crate::assembler::register_parsers::REGISTER_ALIASES;
+use crate::vm::REGISTER_COUNT;
+
+/// Registers shown in each column of the register grid
+const GRID_ROWS: usize = REGISTER_COUNT / 2;
+/// Bytes shown on each line of a hexdump
+const HEXDUMP_WIDTH: usize = 16;
+
+const HIGHLIGHT: &str = "\x1b[1;33m";
+const RESET: &str = "\x1b[0m";
+
+/// Formats the registers as two columns showing each one in hex, as an unsigned number and as a
+/// signed number. Registers that differ from `previous` are marked with a `*`, and also
+/// highlighted when `color` is set.
+pub fn register_grid(registers: &[i32; REGISTER_COUNT], previous: &[i32; REGISTER_COUNT], color: bool) -> String {
+    let header = format!(" {:<4}{:<4} {:>10} {:>10} {:>11}", "reg", "", "hex", "unsigned", "signed");
+    let mut grid = format!("{}   {}\n", header, header).trim_end().to_string();
+    grid.push('\n');
+
+    for row in 0..GRID_ROWS {
+        let cells: Vec<String> = [row, row + GRID_ROWS].iter()
+            .map(|&index| register_cell(index, registers[index], registers[index] != previous[index], color))
+            .collect();
+        grid.push_str(cells.join("   ").trim_end());
+        grid.push('\n');
+    }
+    grid
+}
+
+fn register_cell(index: usize, value: i32, changed: bool, color: bool) -> String {
+    let marker = if changed { '*' } else { ' ' };
+    let cell = format!("{}{:<4}{:<4} 0x{:08x} {:>10} {:>11}",
+        marker, format!("${}", index), REGISTER_ALIASES[index].0, value as u32, value as u32, value);
+    if changed && color {
+        format!("{}{}{}", HIGHLIGHT, cell, RESET)
+    } else {
+        cell
+    }
+}
+
+pub fn flags(equal_flag: bool, remainder: u32) -> String {
+    format!("equal_flag: {}\nremainder:  {} (0x{:08x})\n", equal_flag, remainder, remainder)
+}
+
+/// Formats `bytes` as a classic hexdump, sixteen bytes to a line with their printable ASCII
+/// characters alongside. `base` is the address of the first byte.
+pub fn hexdump(bytes: &[u8], base: usize) -> String {
+    let mut dump = String::new();
+    for (line, chunk) in bytes.chunks(HEXDUMP_WIDTH).enumerate() {
+        write!(dump, "{:08x} ", base + line * HEXDUMP_WIDTH).unwrap();
+        for i in 0..HEXDUMP_WIDTH {
+            if i % 8 == 0 {
+                dump.push(' ');
+            }
+            match chunk.get(i) {
+                Some(byte) => write!(dump, "{:02x} ", byte).unwrap(),
+                None => dump.push_str("   "),
+            }
+        }
+        let ascii: String = chunk.iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        writeln!(dump, " |{}|", ascii).unwrap();
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_grid() {
+        let previous = [0; REGISTER_COUNT];
+        let mut registers = previous;
+        registers[1] = -1;
+        registers[16] = 500;
+        let grid = register_grid(&registers, &previous, false);
+        let lines: Vec<&str> = grid.lines().collect();
+        assert_eq!(lines.len(), GRID_ROWS + 1);
+        assert_eq!(lines[1], " $0  a0   0x00000000          0           0   *$16 s0   0x000001f4        500         500");
+        assert_eq!(lines[2], "*$1  a1   0xffffffff 4294967295          -1    $17 s1   0x00000000          0           0");
+        assert!(lines[16].starts_with(" $15 t11  0x00000000"));
+        assert!(register_grid(&registers, &registers, false).find('*').is_none());
+
+        let colored = register_grid(&registers, &previous, true);
+        assert!(colored.contains("\x1b[1;33m*$1 "));
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let dump = hexdump(b"Hello, World!\n\0\0\x7fabc", 0x10);
+        assert_eq!(dump,
+            "00000010  48 65 6c 6c 6f 2c 20 57  6f 72 6c 64 21 0a 00 00  |Hello, World!...|\n\
+             00000020  7f 61 62 63                                       |.abc|\n");
+        assert_eq!(hexdump(&[], 0), "");
+    }
+}
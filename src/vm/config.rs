@@ -0,0 +1,50 @@
+use crate::instructions::{Opcode, OPCODE_COUNT};
+
+/// Resource limits and restrictions the VM enforces while running a program. The default
+/// configuration places no limits on anything; set the fields that matter before handing
+/// untrusted code to `VM::with_config`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VmConfig {
+    /// The largest the heap may grow to, in bytes
+    pub max_heap_bytes: Option<usize>,
+    /// The most bytes `PRTS` may print over a single run
+    pub max_output_bytes: Option<usize>,
+    /// The only opcodes the program may execute. `None` allows all of them.
+    pub allowed_opcodes: Option<Vec<Opcode>>,
+}
+
+impl VmConfig {
+    /// Flattens `allowed_opcodes` into a lookup table indexed by opcode
+    pub(crate) fn opcode_table(&self) -> [bool; OPCODE_COUNT] {
+        match &self.allowed_opcodes {
+            Some(allowed) => {
+                let mut table = [false; OPCODE_COUNT];
+                for opcode in allowed {
+                    table[*opcode as usize] = true;
+                }
+                table
+            },
+            None => [true; OPCODE_COUNT],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opcode_table() {
+        let table = VmConfig::default().opcode_table();
+        assert!(table.iter().all(|allowed| *allowed));
+
+        let config = VmConfig {
+            allowed_opcodes: Some(vec![Opcode::LOAD, Opcode::HLT]),
+            ..VmConfig::default()
+        };
+        let table = config.opcode_table();
+        assert!(table[Opcode::LOAD as usize]);
+        assert!(table[Opcode::HLT as usize]);
+        assert!(!table[Opcode::ALOC as usize]);
+    }
+}
@@ -0,0 +1,146 @@
+//! Counts how many times each instruction runs, for finding the code a program's tests never
+//! reach.
+//!
+//! Counts are saved as text, one `address count` pair per line in address order, so the counts
+//! from several runs can be merged into one file.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+use super::VM;
+
+/// Execution counts keyed by the address each instruction was fetched from
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Coverage {
+    /// Only addresses that ran are present. Files can name any address, so this isn't a
+    /// vector indexed by address.
+    counts: BTreeMap<usize, u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage { counts: BTreeMap::new() }
+    }
+
+    /// Counts one run of the instruction at `address`
+    pub fn record(&mut self, address: usize) {
+        self.add(address, 1);
+    }
+
+    fn add(&mut self, address: usize, count: u64) {
+        let total = self.counts.entry(address).or_default();
+        *total = total.saturating_add(count);
+    }
+
+    /// How many times the instruction at `address` ran
+    pub fn hits(&self, address: usize) -> u64 {
+        self.counts.get(&address).copied().unwrap_or(0)
+    }
+
+    /// The addresses that ran and how many times, in address order
+    pub fn iter(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.counts.iter().map(|(address, count)| (*address, *count))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Adds the counts of another run to these
+    pub fn merge(&mut self, other: &Coverage) {
+        for (address, count) in other.iter() {
+            self.add(address, count);
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, mut out: W) -> io::Result<()> {
+        for (address, count) in self.iter() {
+            writeln!(out, "{} {}", address, count)?;
+        }
+        out.flush()
+    }
+
+    /// Reads counts written by `write_to`. An address listed more than once has its counts
+    /// added together.
+    pub fn read_from<R: BufRead>(input: R) -> io::Result<Self> {
+        let mut coverage = Coverage::new();
+        for (n, line) in input.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace().map(str::parse::<u64>);
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(Ok(address)), Some(Ok(count)), None) if count > 0 => coverage.add(address as usize, count),
+                (Some(Ok(_)), Some(Ok(_)), None) => {},
+                _ => {
+                    let message = format!("line {} should be an address and a count: {}", n + 1, line);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+                },
+            }
+        }
+        Ok(coverage)
+    }
+}
+
+impl VM {
+    /// Starts counting the instructions executed, from zero. Counting carries on across runs
+    /// until `take_coverage`.
+    pub fn start_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    /// The counts so far, if counting
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Stops counting and returns the counts
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::CODE_START;
+
+    #[test]
+    fn test_vm_counts_instructions() {
+        let mut vm = VM::new();
+        vm.add_bytes(Assembler::new().assemble(".data\n.code\nload $0 #3\nload $1 #69\ndec $0\nneq $0 $2\njmpe $1\nhlt\n").unwrap());
+        vm.start_coverage();
+        vm.run();
+        vm.run();
+        let coverage = vm.take_coverage().unwrap();
+        assert_eq!(coverage.hits(CODE_START), 2);
+        assert_eq!(coverage.hits(CODE_START + 8), 6);
+        assert_eq!(coverage.hits(CODE_START + 20), 2);
+        assert_eq!(coverage.iter().count(), 6);
+        assert!(vm.coverage().is_none());
+    }
+
+    #[test]
+    fn test_merge_and_files() {
+        let mut first = Coverage::new();
+        first.record(65);
+        first.record(65);
+        first.record(69);
+        let mut second = Coverage::new();
+        second.record(73);
+        second.record(65);
+        first.merge(&second);
+        assert_eq!(first.iter().collect::<Vec<_>>(), vec![(65, 3), (69, 1), (73, 1)]);
+
+        let mut file = vec![];
+        first.write_to(&mut file).unwrap();
+        assert_eq!(String::from_utf8(file.clone()).unwrap(), "65 3\n69 1\n73 1\n");
+        assert_eq!(Coverage::read_from(&file[..]).unwrap(), first);
+        assert_eq!(Coverage::read_from(&b"65 1\n65 2\n"[..]).unwrap().hits(65), 3);
+        assert!(Coverage::read_from(&b"65\n"[..]).is_err());
+        let far = Coverage::read_from(&b"18446744073709551615 1\n"[..]).unwrap();
+        assert_eq!(far.iter().collect::<Vec<_>>(), vec![(usize::MAX, 1)]);
+    }
+}
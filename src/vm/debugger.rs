@@ -0,0 +1,201 @@
+use std::collections::BTreeSet;
+
+use crate::instructions::{Instruction, INSTRUCTION_LENGTH};
+use super::{VM, ExitReason};
+
+/// Execution controls used by the REPL and other debugging front ends
+impl VM {
+    /// Stops execution whenever the PC reaches `pc`. Returns false if there already was one.
+    pub fn add_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.insert(pc)
+    }
+
+    /// Returns false if there was no breakpoint at `pc`
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<usize> {
+        &self.breakpoints
+    }
+
+    /// Executes up to `count` instructions. Returns early, with the reason, if the program stops
+    /// or arrives at a breakpoint other than the one it started on.
+    pub fn step(&mut self, count: usize) -> Option<ExitReason> {
+        for n in 0..count {
+            if n > 0 && self.at_breakpoint() {
+                return Some(ExitReason::Breakpoint { pc: self.pc });
+            }
+            if let Some(reason) = self.execute_instruction() {
+                return Some(reason);
+            }
+        }
+        None
+    }
+
+    /// Executes the next instruction, treating a call as a single step. There is no `CALL`
+    /// instruction yet, so for now this is the same as `step(1)`.
+    pub fn step_over(&mut self) -> Option<ExitReason> {
+        self.step(1)
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Moves the PC, for example to skip over an instruction. Nothing checks that an
+    /// instruction starts there.
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    /// The instruction the PC is pointing at, if it is inside the program
+    pub fn current_instruction(&self) -> Option<Instruction> {
+        if self.pc < self.program.len() {
+            Some(self.fetch())
+        } else {
+            None
+        }
+    }
+
+    /// Decodes the instruction at `address`, if a whole one fits inside the program
+    pub fn instruction_at(&self, address: usize) -> Option<Instruction> {
+        let end = address.checked_add(INSTRUCTION_LENGTH)?;
+        self.program.get(address..end).map(Instruction::decode)
+    }
+
+    pub fn equal_flag(&self) -> bool {
+        self.equal_flag
+    }
+
+    pub fn remainder(&self) -> u32 {
+        self.remainder
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    pub fn ro_data(&self) -> &[u8] {
+        &self.ro_data
+    }
+
+    /// Overwrites part of the program, for example to patch an instruction. Returns false, and
+    /// changes nothing, unless all of `bytes` lands inside the program.
+    pub fn write_program(&mut self, address: usize, bytes: &[u8]) -> bool {
+        if !overwrite(&mut self.program, address, bytes) {
+            return false;
+        }
+        self.predecode_program();
+        true
+    }
+
+    /// Overwrites part of the heap. Returns false, and changes nothing, unless all of `bytes`
+    /// lands inside the heap.
+    pub fn write_heap(&mut self, address: usize, bytes: &[u8]) -> bool {
+        overwrite(&mut self.heap, address, bytes)
+    }
+
+    /// Overwrites part of the read-only data. Returns false, and changes nothing, unless all of
+    /// `bytes` lands inside it.
+    pub fn write_ro_data(&mut self, address: usize, bytes: &[u8]) -> bool {
+        overwrite(&mut self.ro_data, address, bytes)
+    }
+
+    pub(super) fn at_breakpoint(&self) -> bool {
+        !self.breakpoints.is_empty() && self.breakpoints.contains(&self.pc)
+    }
+}
+
+fn overwrite(memory: &mut [u8], address: usize, bytes: &[u8]) -> bool {
+    let target = address.checked_add(bytes.len()).and_then(|end| memory.get_mut(address..end));
+    match target {
+        Some(target) => {
+            target.copy_from_slice(bytes);
+            true
+        },
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{test::vm_with_code, CODE_START};
+
+    fn loop_vm() -> VM {
+        vm_with_code(vec![
+            1, 0, 0, 3,  // Load 3 => r0
+            1, 2, 0, 73, // Load 73 => r2
+            3, 0, 0, 0,  // DEC r0 (pc = 73)
+            12, 0, 1, 0, // NEQ r0 != r1
+            17, 2, 0, 0, // JMPE to r2
+            0]) // Halt
+    }
+
+    #[test]
+    fn test_breakpoint_and_continue() {
+        let mut vm = loop_vm();
+        assert!(vm.add_breakpoint(73));
+        assert!(!vm.add_breakpoint(73));
+
+        assert_eq!(vm.run(), ExitReason::Breakpoint { pc: 73 });
+        assert_eq!(vm.registers[0], 3);
+        assert_eq!(vm.resume(), ExitReason::Breakpoint { pc: 73 });
+        assert_eq!(vm.registers[0], 2);
+
+        assert!(vm.remove_breakpoint(73));
+        assert_eq!(vm.resume(), ExitReason::Halted);
+        assert_eq!(vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_breakpoint_at_entry() {
+        let mut vm = loop_vm();
+        vm.add_breakpoint(CODE_START);
+        assert_eq!(vm.run(), ExitReason::Breakpoint { pc: CODE_START });
+        assert_eq!(vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_step() {
+        let mut vm = loop_vm();
+        assert_eq!(vm.step(2), None);
+        assert_eq!(vm.pc(), 73);
+        assert_eq!(vm.current_instruction(), Some(Instruction::decode(&[3, 0, 0, 0])));
+
+        vm.add_breakpoint(77);
+        assert_eq!(vm.step(10), Some(ExitReason::Breakpoint { pc: 77 }));
+        assert_eq!(vm.step_over(), None);
+        assert_eq!(vm.pc(), 81);
+        assert_eq!(vm.step(100), Some(ExitReason::Breakpoint { pc: 77 }));
+        vm.clear_breakpoints();
+        assert_eq!(vm.step(100), Some(ExitReason::Halted));
+        assert_eq!(vm.current_instruction(), None);
+    }
+
+    #[test]
+    fn test_instruction_at() {
+        let vm = loop_vm();
+        assert_eq!(vm.instruction_at(77), Some(Instruction::decode(&[12, 0, 1, 0])));
+        assert_eq!(vm.instruction_at(85), None);
+        assert_eq!(vm.instruction_at(usize::MAX), None);
+    }
+
+    #[test]
+    fn test_write_program() {
+        let mut vm = loop_vm();
+        // Load 7 into r0 instead of 3 and turn the jump back into a NOP
+        assert!(vm.write_program(CODE_START + 3, &[7]));
+        assert!(vm.write_program(81, &[21, 0, 0, 0]));
+        assert!(!vm.write_program(85, &[0, 0]));
+        assert!(!vm.write_heap(0, &[1]));
+        assert_eq!(vm.instruction_at(CODE_START), Some(Instruction::decode(&[1, 0, 0, 7])));
+        assert_eq!(vm.run(), ExitReason::Halted);
+        assert_eq!(vm.registers[0], 6);
+    }
+}
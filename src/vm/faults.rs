@@ -0,0 +1,34 @@
+use std::fmt;
+use std::error::Error;
+
+use crate::instructions::Opcode;
+
+/// Errors that stop the VM part way through an instruction. The PC is left pointing at the
+/// instruction that caused the fault.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum VmFault {
+    OpcodeNotAllowed { opcode: Opcode },
+    InvalidRegister { register: u8 },
+    DivideByZero,
+    NegativeAllocation { bytes: i32 },
+    HeapLimitExceeded { requested: usize, limit: usize },
+    OutputLimitExceeded { limit: usize },
+    InvalidStringOffset { offset: usize },
+}
+
+impl fmt::Display for VmFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmFault::OpcodeNotAllowed { opcode } => write!(f, "Opcode {:?} is not allowed by the VM configuration", opcode),
+            VmFault::InvalidRegister { register } => write!(f, "Register {} does not exist", register),
+            VmFault::DivideByZero => f.write_str("Attempted to divide by zero"),
+            VmFault::NegativeAllocation { bytes } => write!(f, "Attempted to allocate a negative number of bytes: {}", bytes),
+            VmFault::HeapLimitExceeded { requested, limit } => write!(f,
+                "Heap would grow to {} bytes, which is over the limit of {} bytes", requested, limit),
+            VmFault::OutputLimitExceeded { limit } => write!(f, "Program printed more than the limit of {} bytes", limit),
+            VmFault::InvalidStringOffset { offset } => write!(f, "No null terminated string at read-only offset {}", offset),
+        }
+    }
+}
+
+impl Error for VmFault {}
@@ -0,0 +1,76 @@
+use crate::instructions::{Opcode, Instruction, OPCODE_COUNT};
+
+/// The amount of gas charged for each instruction. Costs depend only on the instruction and the
+/// registers it reads, so a program given the same budget always stops at the same point.
+///
+/// The default table charges 1 for every instruction, which makes the gas budget an instruction
+/// budget. `ALOC` can additionally be charged for every byte it asks for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostTable {
+    costs: [u64; OPCODE_COUNT],
+    aloc_byte_cost: u64,
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CostTable {
+    pub fn new() -> Self {
+        CostTable {
+            costs: [1; OPCODE_COUNT],
+            aloc_byte_cost: 0,
+        }
+    }
+
+    /// Sets the flat cost of executing `opcode`
+    pub fn set_cost(&mut self, opcode: Opcode, cost: u64) {
+        self.costs[opcode as usize] = cost;
+    }
+
+    /// Sets the extra cost charged for each byte an `ALOC` requests
+    pub fn set_aloc_byte_cost(&mut self, cost: u64) {
+        self.aloc_byte_cost = cost;
+    }
+
+    pub fn cost(&self, opcode: Opcode) -> u64 {
+        self.costs[opcode as usize]
+    }
+
+    /// Works out what executing `instruction` costs with the given register contents
+    pub fn instruction_cost(&self, instruction: &Instruction, registers: &[i32]) -> u64 {
+        let mut cost = self.cost(instruction.opcode);
+        if instruction.opcode == Opcode::ALOC {
+            // A bad register is reported as a fault when the instruction runs, so charge nothing extra
+            let bytes = registers.get(instruction.operands[0] as usize).copied().unwrap_or(0).max(0) as u64;
+            cost = cost.saturating_add(bytes.saturating_mul(self.aloc_byte_cost));
+        }
+        cost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_costs() {
+        let costs = CostTable::new();
+        assert_eq!(costs.cost(Opcode::HLT), 1);
+        assert_eq!(costs.cost(Opcode::IGL), 1);
+        let aloc = Instruction::decode(&[19, 0, 0, 0]);
+        assert_eq!(costs.instruction_cost(&aloc, &[4096]), 1);
+    }
+
+    #[test]
+    fn test_aloc_charged_by_bytes() {
+        let mut costs = CostTable::new();
+        costs.set_cost(Opcode::ALOC, 10);
+        costs.set_aloc_byte_cost(2);
+        let aloc = Instruction::decode(&[19, 1, 0, 0]);
+        assert_eq!(costs.instruction_cost(&aloc, &[0, 100]), 210);
+        assert_eq!(costs.instruction_cost(&aloc, &[0, -100]), 10);
+    }
+}
@@ -0,0 +1,161 @@
+use super::{VM, ExitReason};
+use super::state_change::StateChange;
+
+/// What is needed to undo one executed instruction
+#[derive(Debug, PartialEq, Clone)]
+pub(super) struct HistoryEntry {
+    /// Where the instruction was fetched from
+    pc: usize,
+    /// Gas left before the instruction was charged for
+    gas: Option<u64>,
+    changes: Vec<StateChange>,
+}
+
+/// Reverse execution. While the history size is above zero the VM keeps an undo log of the
+/// last instructions it executed, which can then be stepped back through.
+impl VM {
+    /// Keeps the state changes of up to `size` instructions so they can be undone. A size of 0,
+    /// the default, turns the history off. Shrinking it forgets the oldest entries.
+    pub fn set_history_size(&mut self, size: usize) {
+        self.history_size = size;
+        while self.history.len() > size {
+            self.history.pop_front();
+        }
+        self.update_recording();
+    }
+
+    pub fn history_size(&self) -> usize {
+        self.history_size
+    }
+
+    /// How many instructions can currently be stepped back over
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    /// Undoes up to `count` instructions, most recent first. Returns how many were undone, which
+    /// is less than `count` if the history ran out.
+    pub fn step_back(&mut self, count: usize) -> usize {
+        for n in 0..count {
+            if !self.undo() {
+                return n;
+            }
+        }
+        count
+    }
+
+    /// Undoes instructions until the PC arrives at a breakpoint, which is reported as the reason
+    /// for stopping. Returns `None` if the start of the history was reached first.
+    pub fn reverse_continue(&mut self) -> Option<ExitReason> {
+        while self.undo() {
+            if self.at_breakpoint() {
+                return Some(ExitReason::Breakpoint { pc: self.pc });
+            }
+        }
+        None
+    }
+
+    /// Saves the changes made by the instruction that just ran at `pc`
+    pub(super) fn record_history(&mut self, pc: usize, gas: Option<u64>) {
+        if self.history.len() == self.history_size {
+            self.history.pop_front();
+        }
+        let changes = std::mem::take(&mut self.changes);
+        self.history.push_back(HistoryEntry { pc, gas, changes });
+    }
+
+    fn undo(&mut self) -> bool {
+        let entry = match self.history.pop_back() {
+            Some(entry) => entry,
+            None => return false,
+        };
+        for change in entry.changes.iter().rev() {
+            match *change {
+                StateChange::Register { index, old, .. } => self.registers[index as usize] = old,
+                StateChange::EqualFlag { old, .. } => self.equal_flag = old,
+                StateChange::Remainder { old, .. } => self.remainder = old,
+                // The heap only ever grows, so truncating it restores it exactly
+                StateChange::HeapResize { old_len, .. } => self.heap.resize(old_len, 0),
+            }
+        }
+        self.pc = entry.pc;
+        self.gas = entry.gas;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{test::vm_with_code, CODE_START};
+
+    fn countdown_vm() -> VM {
+        let mut vm = vm_with_code(vec![
+            1, 0, 0, 3,  // Load 3 => r0
+            1, 2, 0, 73, // Load 73 => r2
+            3, 0, 0, 0,  // DEC r0 (pc = 73)
+            19, 0, 0, 0, // ALOC r0 bytes
+            12, 0, 1, 0, // NEQ r0 != r1
+            17, 2, 0, 0, // JMPE to r2
+            0]); // Halt
+        vm.set_history_size(100);
+        vm
+    }
+
+    #[test]
+    fn test_step_back_to_start() {
+        let mut vm = countdown_vm();
+        vm.set_gas(100);
+        assert_eq!(vm.run(), ExitReason::Halted);
+        assert_eq!(vm.heap.len(), 3);
+        assert_eq!(vm.gas(), Some(85));
+        assert_eq!(vm.history_len(), 15);
+
+        assert_eq!(vm.step_back(2), 2);
+        assert_eq!(vm.pc(), 85);
+        assert!(!vm.equal_flag);
+        assert_eq!(vm.gas(), Some(87));
+
+        assert_eq!(vm.step_back(100), 13);
+        assert_eq!(vm.pc(), CODE_START);
+        assert_eq!(vm.registers, [0; 32]);
+        assert!(vm.heap.is_empty());
+        assert_eq!(vm.gas(), Some(100));
+
+        assert_eq!(vm.resume(), ExitReason::Halted);
+        assert_eq!(vm.heap.len(), 3);
+    }
+
+    #[test]
+    fn test_reverse_continue() {
+        let mut vm = countdown_vm();
+        assert_eq!(vm.run(), ExitReason::Halted);
+        vm.add_breakpoint(77);
+        assert_eq!(vm.reverse_continue(), Some(ExitReason::Breakpoint { pc: 77 }));
+        assert_eq!(vm.registers[0], 0);
+        assert_eq!(vm.reverse_continue(), Some(ExitReason::Breakpoint { pc: 77 }));
+        assert_eq!(vm.registers[0], 1);
+        vm.clear_breakpoints();
+        assert_eq!(vm.reverse_continue(), None);
+        assert_eq!(vm.pc(), CODE_START);
+    }
+
+    #[test]
+    fn test_history_size_limit() {
+        let mut vm = countdown_vm();
+        vm.set_history_size(4);
+        assert_eq!(vm.run(), ExitReason::Halted);
+        assert_eq!(vm.history_len(), 4);
+        assert_eq!(vm.step_back(10), 4);
+        assert_eq!(vm.pc(), 77);
+
+        vm.set_history_size(0);
+        assert_eq!(vm.resume(), ExitReason::Halted);
+        assert_eq!(vm.history_len(), 0);
+        assert_eq!(vm.step_back(1), 0);
+    }
+}
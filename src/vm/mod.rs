@@ -0,0 +1,1074 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::io::{self, Write};
+
+use log::debug;
+
+use crate::instructions::{Opcode, Instruction, INSTRUCTION_LENGTH};
+use crate::assembler::{PIE_HEADER_PREFIX, PIE_HEADER_LENGTH};
+
+pub mod gas;
+pub mod config;
+pub mod faults;
+pub mod debugger;
+pub mod state_change;
+pub mod watchpoints;
+pub mod trace;
+pub mod history;
+pub mod snapshot;
+pub mod coverage;
+pub mod profile;
+
+use crate::instructions::OPCODE_COUNT;
+use self::gas::CostTable;
+use self::config::VmConfig;
+use self::faults::VmFault;
+use self::state_change::StateChange;
+use self::watchpoints::{Watchpoint, WatchHit};
+use self::trace::TraceWriter;
+use self::history::HistoryEntry;
+use self::coverage::Coverage;
+use self::profile::Profile;
+
+/// Number of general purpose registers in the VM
+pub const REGISTER_COUNT: usize = 32;
+
+/// Offset of the first instruction, directly after the PIE header
+pub const CODE_START: usize = PIE_HEADER_LENGTH + 1;
+
+/// Why the VM stopped executing
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ExitReason {
+    /// A `HLT` instruction was executed
+    Halted,
+    /// The PC moved past the end of the program
+    EndOfProgram,
+    /// An opcode the VM doesn't know was encountered
+    IllegalInstruction,
+    /// The program doesn't start with the PIE header
+    InvalidHeader,
+    /// There wasn't enough gas left for the next instruction, which has not been executed.
+    /// Add more with `add_gas` and call `resume` to carry on.
+    OutOfGas,
+    /// The instruction at the PC broke a rule of the VM or its configuration
+    Fault(VmFault),
+    /// The PC reached a breakpoint. The instruction there hasn't been executed yet.
+    Breakpoint { pc: usize },
+    /// A watchpoint with `WatchAction::Break` was triggered. The instruction that triggered it
+    /// has been executed.
+    Watchpoint(WatchHit),
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitReason::Halted => f.write_str("Program halted"),
+            ExitReason::EndOfProgram => f.write_str("Reached the end of the program"),
+            ExitReason::IllegalInstruction => f.write_str("Illegal instruction encountered"),
+            ExitReason::InvalidHeader => f.write_str("Program header was incorrect"),
+            ExitReason::OutOfGas => f.write_str("Ran out of gas"),
+            ExitReason::Fault(fault) => write!(f, "Fault: {}", fault),
+            ExitReason::Breakpoint { pc } => write!(f, "Stopped at breakpoint {}", pc),
+            ExitReason::Watchpoint(hit) => write!(f, "Watchpoint: {}", hit),
+        }
+    }
+}
+
+pub struct VM {
+    pub registers: [i32; REGISTER_COUNT],
+    pc: usize,
+    program: Vec<u8>,
+    /// The code section decoded ahead of time. Entry `n` is the instruction at
+    /// `CODE_START + n * INSTRUCTION_LENGTH`.
+    decoded: Vec<Instruction>,
+    /// Whether to dispatch from `decoded` or decode each instruction from `program` as it runs
+    predecode: bool,
+    heap: Vec<u8>,
+    remainder: u32,
+    equal_flag: bool,
+    ro_data: Vec<u8>,
+    /// Gas left to spend, or `None` if execution isn't metered
+    gas: Option<u64>,
+    /// What each instruction costs when execution is metered
+    costs: CostTable,
+    /// Limits enforced while running
+    config: VmConfig,
+    /// `config.allowed_opcodes` as a lookup table indexed by opcode
+    allowed_opcodes: [bool; OPCODE_COUNT],
+    /// Bytes printed by `PRTS` during the current run
+    output_bytes: usize,
+    /// Where `PRTS` prints to
+    output: Box<dyn Write + Send>,
+    /// Addresses execution stops at before running the instruction there
+    breakpoints: BTreeSet<usize>,
+    /// Watched registers, flags and heap ranges
+    watchpoints: Vec<Watchpoint>,
+    /// Hits of logging watchpoints that haven't been collected yet
+    watch_log: Vec<WatchHit>,
+    /// Where executed instructions are traced to, if anywhere
+    trace: Option<TraceWriter<Box<dyn Write + Send>>>,
+    /// Undo log of the most recent instructions, oldest first
+    history: VecDeque<HistoryEntry>,
+    /// How many instructions `history` holds at most. 0 turns it off.
+    history_size: usize,
+    /// Whether state changes are recorded into `changes`
+    record_changes: bool,
+    /// The changes made by the last instruction, while `record_changes` is set
+    changes: Vec<StateChange>,
+    /// How many times each instruction has run, if counting
+    coverage: Option<Coverage>,
+    /// Counts and costs of every instruction run, if profiling
+    profile: Option<Profile>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    pub fn new() -> Self {
+        Self::with_config(VmConfig::default())
+    }
+
+    /// Creates a VM that enforces the limits in `config`
+    pub fn with_config(config: VmConfig) -> Self {
+        VM {
+            registers: [0; REGISTER_COUNT],
+            program: vec![],
+            decoded: vec![],
+            predecode: true,
+            ro_data: vec![],
+            heap: vec![],
+            pc: CODE_START,
+            remainder: 0,
+            equal_flag: false,
+            gas: None,
+            costs: CostTable::new(),
+            allowed_opcodes: config.opcode_table(),
+            config,
+            output_bytes: 0,
+            output: Box::new(io::stdout()),
+            breakpoints: BTreeSet::new(),
+            watchpoints: vec![],
+            watch_log: vec![],
+            trace: None,
+            history: VecDeque::new(),
+            history_size: 0,
+            record_changes: false,
+            changes: vec![],
+            coverage: None,
+            profile: None,
+        }
+    }
+
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    /// Sends what the program prints somewhere other than standard output
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.output = output;
+    }
+
+    /// Runs the program from the first instruction until it stops
+    pub fn run(&mut self) -> ExitReason {
+        if !self.verify_header() {
+            eprintln!("Header was incorrect");
+            return ExitReason::InvalidHeader;
+        }
+
+        // If the header is valid, we need to change the PC to be at bit 65.
+        self.pc = CODE_START;
+        self.output_bytes = 0;
+        self.history.clear();
+        self.predecode_program();
+        self.run_until_stopped()
+    }
+
+    /// Carries on running from the current PC until the program stops, for example after
+    /// topping up the gas of a VM that ran out. A breakpoint at the current PC is stepped over.
+    pub fn resume(&mut self) -> ExitReason {
+        if let Some(reason) = self.execute_instruction() {
+            return reason;
+        }
+        self.run_until_stopped()
+    }
+
+    fn run_until_stopped(&mut self) -> ExitReason {
+        loop {
+            if self.at_breakpoint() {
+                return ExitReason::Breakpoint { pc: self.pc };
+            }
+            if let Some(reason) = self.execute_instruction() {
+                return reason;
+            }
+        }
+    }
+
+    /// Executes a single instruction, returning the reason if it stopped the program
+    pub fn run_once(&mut self) -> Option<ExitReason> {
+        self.execute_instruction()
+    }
+
+    /// Limits how much gas the VM may spend from now on. Each instruction is charged according
+    /// to the cost table before it runs.
+    pub fn set_gas(&mut self, gas: u64) {
+        self.gas = Some(gas);
+    }
+
+    /// Adds to the remaining gas. Does nothing if execution isn't metered.
+    pub fn add_gas(&mut self, gas: u64) {
+        if let Some(remaining) = self.gas {
+            self.gas = Some(remaining.saturating_add(gas));
+        }
+    }
+
+    /// Removes the gas limit
+    pub fn clear_gas(&mut self) {
+        self.gas = None;
+    }
+
+    /// The gas left, or `None` if execution isn't metered
+    pub fn gas(&self) -> Option<u64> {
+        self.gas
+    }
+
+    pub fn set_cost_table(&mut self, costs: CostTable) {
+        self.costs = costs;
+    }
+
+    /// Puts the machine back the way it was before the program started, keeping the program,
+    /// configuration, gas, breakpoints and watchpoints
+    pub fn reset(&mut self) {
+        self.registers = [0; REGISTER_COUNT];
+        self.pc = CODE_START;
+        self.heap.clear();
+        self.remainder = 0;
+        self.equal_flag = false;
+        self.output_bytes = 0;
+        self.history.clear();
+        self.changes.clear();
+        self.watch_log.clear();
+    }
+
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    pub fn add_byte(&mut self, byte: u8) {
+        self.program.push(byte);
+        self.decode_new_instructions();
+    }
+
+    pub fn add_bytes(&mut self, mut bytes: Vec<u8>) {
+        self.program.append(&mut bytes);
+        self.decode_new_instructions();
+    }
+
+    /// Appends constants to the read-only section that `PRTS` reads strings from
+    pub fn add_ro_data(&mut self, bytes: &[u8]) {
+        self.ro_data.extend_from_slice(bytes);
+    }
+
+    pub fn clear_program(&mut self) {
+        self.program.clear();
+        self.ro_data.clear();
+        self.decoded.clear();
+        self.history.clear();
+    }
+
+    /// Chooses between dispatching from the predecoded instruction table (the default) and
+    /// decoding every instruction from the raw bytes as it is executed.
+    pub fn set_predecode(&mut self, enabled: bool) {
+        self.predecode = enabled;
+    }
+
+    /// Rebuilds the decoded instruction table from scratch
+    fn predecode_program(&mut self) {
+        self.decoded.clear();
+        self.decode_new_instructions();
+    }
+
+    /// Decodes any complete instructions at the end of the program that aren't in the table yet
+    fn decode_new_instructions(&mut self) {
+        loop {
+            let start = CODE_START + self.decoded.len() * INSTRUCTION_LENGTH;
+            let end = start + INSTRUCTION_LENGTH;
+            if end > self.program.len() {
+                break;
+            }
+            self.decoded.push(Instruction::decode(&self.program[start..end]));
+        }
+    }
+
+    /// Returns the instruction at the current PC. Jumps can land anywhere, so if the PC isn't at
+    /// the start of a decoded instruction it is decoded from the program bytes instead.
+    fn fetch(&self) -> Instruction {
+        if self.predecode && self.pc >= CODE_START && (self.pc - CODE_START).is_multiple_of(INSTRUCTION_LENGTH) {
+            if let Some(instruction) = self.decoded.get((self.pc - CODE_START) / INSTRUCTION_LENGTH) {
+                return *instruction;
+            }
+        }
+        Instruction::decode(&self.program[self.pc..])
+    }
+
+    fn execute_instruction(&mut self) -> Option<ExitReason> {
+        // If our program counter has exceeded the length of the program itself,
+        // something has gong awry
+        if self.pc >= self.program.len() {
+            return Some(ExitReason::EndOfProgram);
+        }
+
+        let instruction = self.fetch();
+        if !self.allowed_opcodes[instruction.opcode as usize] {
+            if self.trace.is_some() {
+                self.write_trace(self.pc, &instruction, true);
+            }
+            return Some(ExitReason::Fault(VmFault::OpcodeNotAllowed { opcode: instruction.opcode }));
+        }
+        let gas_before = self.gas;
+        if !self.charge(&instruction) {
+            return Some(ExitReason::OutOfGas);
+        }
+
+        let start = self.pc;
+        // The cost depends on registers the instruction may change, so it's worked out first
+        let cost = self.profile.as_ref().map(|_| self.costs.instruction_cost(&instruction, &self.registers));
+        if self.record_changes {
+            self.changes.clear();
+        }
+        match self.execute(instruction, start) {
+            Ok(reason) => {
+                if let Some(coverage) = self.coverage.as_mut() {
+                    coverage.record(start);
+                }
+                if let (Some(profile), Some(cost)) = (self.profile.as_mut(), cost) {
+                    profile.record(start, cost);
+                }
+                if self.trace.is_some() {
+                    self.write_trace(start, &instruction, false);
+                }
+                let reason = match reason {
+                    None if !self.watchpoints.is_empty() => self.check_watchpoints(start),
+                    reason => reason,
+                };
+                if self.history_size > 0 {
+                    self.record_history(start, gas_before);
+                }
+                reason
+            },
+            Err(fault) => {
+                // Nothing changed, so the instruction can be run again once the fault is dealt with
+                self.pc = start;
+                self.gas = gas_before;
+                if self.trace.is_some() {
+                    self.write_trace(start, &instruction, true);
+                }
+                Some(ExitReason::Fault(fault))
+            }
+        }
+    }
+
+    /// Carries out a single instruction that started at `start`. Everything that can fault is
+    /// checked before any state is changed.
+    fn execute(&mut self, instruction: Instruction, start: usize) -> Result<Option<ExitReason>, VmFault> {
+        // Unless the instruction jumps somewhere else, carry on with the next one
+        self.pc = start + INSTRUCTION_LENGTH;
+
+        let op = instruction.opcode;
+        let operands = instruction.operands;
+        match op {
+            Opcode::HLT => {
+                let _ = writeln!(self.output, "HLT encountered");
+                self.pc = start + 1;
+                return Ok(Some(ExitReason::Halted));
+            },
+            Opcode::LOAD => {
+                let number = instruction.wide_operand(1);
+                self.set_register(operands[0], number as i32)?; // Our registers are i32s so we need to cast it. We'll cover that later.
+            },
+            Opcode::INC => {
+                let value = self.register(operands[0])?;
+                self.set_register(operands[0], value.wrapping_add(1))?;
+            },
+            Opcode::DEC => {
+                let value = self.register(operands[0])?;
+                self.set_register(operands[0], value.wrapping_sub(1))?;
+            },
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => {
+                let reg1 = self.register(operands[0])?;
+                let reg2 = self.register(operands[1])?;
+                self.register(operands[2])?;
+                let result = match op {
+                    Opcode::ADD => reg1.wrapping_add(reg2),
+                    Opcode::SUB => reg1.wrapping_sub(reg2),
+                    Opcode::MUL => reg1.wrapping_mul(reg2),
+                    Opcode::DIV => {
+                        if reg2 == 0 {
+                            return Err(VmFault::DivideByZero);
+                        }
+                        self.set_remainder(reg1.wrapping_rem(reg2) as u32);
+                        reg1.wrapping_div(reg2)
+                    },
+                    _ => { -100 } // Impossible to reach
+                };
+                self.set_register(operands[2], result)?;
+            },
+            Opcode::JMP => {
+                let target = self.register(operands[0])?;
+                self.pc = target as usize;
+            },
+            // Relative jumps are measured from just after the register operand
+            Opcode::JMPF => {
+                let amount = self.register(operands[0])?;
+                self.pc = (start + 2).wrapping_add(amount as usize);
+            },
+            Opcode::JMPB => {
+                let amount = self.register(operands[0])?;
+                self.pc = (start + 2).wrapping_sub(amount as usize);
+            },
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTE | Opcode::LTE => {
+                let reg1 = self.register(operands[0])?;
+                let reg2 = self.register(operands[1])?;
+                let flag = match op {
+                    Opcode::EQ => { reg1 == reg2 },
+                    Opcode::NEQ => { reg1 != reg2 },
+                    Opcode::GT => { reg1 > reg2 },
+                    Opcode::LT => { reg1 < reg2 },
+                    Opcode::GTE => { reg1 >= reg2 },
+                    Opcode::LTE => { reg1 <= reg2 },
+                    _ => { false } // Can't reach this point
+                };
+                self.set_equal_flag(flag);
+            },
+            Opcode::JMPE => {
+                let target = self.register(operands[0])?;
+                if self.equal_flag {
+                    self.pc = target as usize;
+                }
+            },
+            Opcode::DJMPE => {
+                let destination = instruction.wide_operand(0);
+                if self.equal_flag {
+                    self.pc = destination as usize;
+                }
+            },
+            Opcode::ALOC => {
+                let bytes = self.register(operands[0])?;
+                if bytes < 0 {
+                    return Err(VmFault::NegativeAllocation { bytes });
+                }
+                let new_end = self.heap.len() + bytes as usize;
+                if let Some(limit) = self.config.max_heap_bytes {
+                    if new_end > limit {
+                        return Err(VmFault::HeapLimitExceeded { requested: new_end, limit });
+                    }
+                }
+                self.resize_heap(new_end);
+            },
+            Opcode::PRTS => {
+                // Takes one operand either a starting index in the RO section of memory
+                // Or a symbol (in the form of @symbol_name) which will look up the offset in the symbol table.
+                // The instruction reads each byte and prints it, until it comes to NULL
+                let starting_offset = instruction.wide_operand(0) as usize;
+                let length = self.ro_data.get(starting_offset..)
+                    .and_then(|rest| rest.iter().position(|b| *b == 0))
+                    .ok_or(VmFault::InvalidStringOffset { offset: starting_offset })?;
+
+                if let Some(limit) = self.config.max_output_bytes {
+                    if self.output_bytes + length > limit {
+                        return Err(VmFault::OutputLimitExceeded { limit });
+                    }
+                }
+                self.output_bytes += length;
+
+                let result = std::str::from_utf8(&self.ro_data[starting_offset..starting_offset + length]);
+                // Nothing the program can do about output that can't be written, so it carries on
+                let _ = match result {
+                    Ok(s) => write!(self.output, "{}", s),
+                    Err(e) => writeln!(self.output, "Error decoding string for PTRS instruction: {:#?}", e),
+                };
+            },
+            Opcode::NOP => {},
+            Opcode::IGL => {
+                let _ = writeln!(self.output, "Illegal Instruction encountered");
+                self.pc = start + 1;
+                return Ok(Some(ExitReason::IllegalInstruction));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn register(&self, index: u8) -> Result<i32, VmFault> {
+        self.registers.get(index as usize)
+            .copied()
+            .ok_or(VmFault::InvalidRegister { register: index })
+    }
+
+    // Instructions change state only through the setters below, so that the changes can be
+    // recorded when something is watching. The flag and remainder setters are also how
+    // debuggers change them.
+
+    fn set_register(&mut self, index: u8, value: i32) -> Result<(), VmFault> {
+        match self.registers.get_mut(index as usize) {
+            Some(register) => {
+                let old = *register;
+                *register = value;
+                if self.record_changes {
+                    self.changes.push(StateChange::Register { index, old, new: value });
+                }
+                Ok(())
+            },
+            None => Err(VmFault::InvalidRegister { register: index }),
+        }
+    }
+
+    pub fn set_equal_flag(&mut self, value: bool) {
+        if self.record_changes {
+            self.changes.push(StateChange::EqualFlag { old: self.equal_flag, new: value });
+        }
+        self.equal_flag = value;
+    }
+
+    pub fn set_remainder(&mut self, value: u32) {
+        if self.record_changes {
+            self.changes.push(StateChange::Remainder { old: self.remainder, new: value });
+        }
+        self.remainder = value;
+    }
+
+    fn resize_heap(&mut self, len: usize) {
+        if self.record_changes {
+            self.changes.push(StateChange::HeapResize { old_len: self.heap.len(), new_len: len });
+        }
+        self.heap.resize(len, 0);
+    }
+
+    /// Turns change recording on while anything needs it
+    fn update_recording(&mut self) {
+        self.record_changes = !self.watchpoints.is_empty() || self.trace.is_some() || self.history_size > 0;
+        if !self.record_changes {
+            self.changes.clear();
+        }
+    }
+
+    /// Takes the cost of `instruction` out of the remaining gas. Returns false, without
+    /// charging anything, if there isn't enough left.
+    fn charge(&mut self, instruction: &Instruction) -> bool {
+        let remaining = match self.gas {
+            Some(remaining) => remaining,
+            None => return true,
+        };
+
+        let cost = self.costs.instruction_cost(instruction, &self.registers);
+        if cost > remaining {
+            return false;
+        }
+        self.gas = Some(remaining - cost);
+        true
+    }
+
+    fn verify_header(&self) -> bool {
+        debug!("Verifying header of {} byte program", self.program.len());
+        if self.program.len() < PIE_HEADER_PREFIX.len() || self.program[0..4] != PIE_HEADER_PREFIX {
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    pub(crate) fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
+        let mut prepension = PIE_HEADER_PREFIX.to_vec();
+
+        while prepension.len() <= PIE_HEADER_LENGTH {
+            prepension.push(0);
+        }
+
+        prepension.append(&mut b);
+        prepension
+    }
+
+    /// A VM loaded with `code`, which starts at `CODE_START`
+    pub(crate) fn vm_with_code(code: Vec<u8>) -> VM {
+        let mut vm = VM::new();
+        vm.add_bytes(prepend_header(code));
+        vm
+    }
+
+    #[test]
+    fn test_create_vm() {
+        let test_vm = VM::new();
+        assert_eq!(test_vm.registers[0], 0)
+    }
+
+    #[test]
+    fn test_opcode_hlt() {
+        let mut test_vm = VM::new();
+        let test_bytes = vec![0,0,0,0];
+        test_vm.program = prepend_header(test_bytes);
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 66);
+    }
+
+    #[test]
+    fn test_load_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![1, 0, 1, 244]);
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 500);
+    }
+
+    #[test]
+    fn test_inc_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 100;
+        test_vm.program = prepend_header(vec![2, 0, 0, 0]);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 101);
+    }
+
+    #[test]
+    fn test_add_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![
+            1, 0, 0, 1, // Load 1 into r0
+            1, 1, 0, 1, // Load 1 into r1
+            4, 0, 1, 2, // Add r0 and r1 and store in r2
+            0]);
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], 2);
+    }
+
+    #[test]
+    fn test_sub_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![
+            1, 0, 0, 4, // Load 4 => r0
+            1, 1, 0, 1, // Load 1 => r1
+            5, 0, 1, 2, // SUB r0 - r1 => r2
+            0]); // Halt
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], 3);
+    }
+
+    #[test]
+    fn test_mul_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![
+            1, 0, 0, 4, // Load 4 => r0
+            1, 1, 0, 2, // Load 2 => r1
+            6, 0, 1, 2, // MUL r0 * r1 => r2
+            0]); // Halt
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], 8);
+    }
+
+    #[test]
+    fn test_div_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![
+            1, 0, 0, 5, // Load 4 => r0
+            1, 1, 0, 2, // Load 2 => r1
+            7, 0, 1, 2, // DIV r0 - r1 => r2
+            0]); // Halt
+        test_vm.run();
+        assert_eq!(test_vm.registers[2], 2);
+        assert_eq!(test_vm.remainder, 1);
+    }
+
+    #[test]
+    fn test_jmp_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![
+            1, 0, 0, 1, // Load 1 into r0
+            8, 0, 0, 0,// JMP from r0 (pc = 1)
+        0]); // Halt
+        test_vm.run_once();
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 1);
+    }
+
+    #[test]
+    fn test_jmpf_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![
+            1, 0, 0, 2, // Load 1 into r0
+            9, 0, 0, 0,// JMPF from r0 (pc = 8)
+            0]); // Halt
+        test_vm.run_once();
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 73);
+    }
+
+    #[test]
+    fn test_jmpb_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![
+            1,  0, 0, 2, // Load 1 into r0
+            10, 0, 0, 0,// JMPB from r0 (pc = 4)
+            0]); // Halt
+        test_vm.run_once();
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 69);
+    }
+
+    #[test]
+    fn test_eq_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = 10;
+        test_vm.program = prepend_header(vec![
+            11, 0, 1, 0, // EQ r0 == r1 (ignore last 0)
+            11, 0, 1, 0, // EQ r0 == r1 (ignore last 0)
+        0]); // Halt
+        test_vm.run_once();
+        assert!(test_vm.equal_flag);
+        test_vm.registers[1] = 20;
+        test_vm.run_once();
+        assert!(!test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_neq_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = 10;
+        test_vm.program = prepend_header(vec![
+            12, 0, 1, 0, // NEQ r0 != r1 (ignore last 0)
+            12, 0, 1, 0, // NEQ r0 != r1 (ignore last 0)
+            0]); // Halt
+        test_vm.run_once();
+        assert!(!test_vm.equal_flag);
+        test_vm.registers[1] = 20;
+        test_vm.run_once();
+        assert!(test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_gt_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 20;
+        test_vm.registers[1] = 10;
+        test_vm.program = prepend_header(vec![
+            13, 0, 1, 0, // EQ r0 == r1 (ignore last 0)
+            13, 0, 1, 0, // EQ r0 == r1 (ignore last 0)
+            0]); // Halt
+        test_vm.run_once();
+        assert!(test_vm.equal_flag);
+        test_vm.registers[1] = 20;
+        test_vm.run_once();
+        assert!(!test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_lt_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = 10;
+        test_vm.program = prepend_header(vec![
+            14, 0, 1, 0, // EQ r0 == r1 (ignore last 0)
+            14, 0, 1, 0, // EQ r0 == r1 (ignore last 0)
+            0]); // Halt
+        test_vm.run_once();
+        assert!(!test_vm.equal_flag);
+        test_vm.registers[1] = 20;
+        test_vm.run_once();
+        assert!(test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_gtq_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 20;
+        test_vm.registers[1] = 10;
+        test_vm.program = prepend_header(vec![
+            15, 0, 1, 0, // EQ r0 == r1 (ignore last 0)
+            15, 0, 1, 0, // EQ r0 == r1 (ignore last 0)
+            15, 0, 1, 0, // EQ r0 == r1 (ignore last 0)
+            0]); // Halt
+        test_vm.run_once();
+        assert!(test_vm.equal_flag);
+        test_vm.registers[1] = 20;
+        test_vm.run_once();
+        assert!(test_vm.equal_flag);
+        test_vm.registers[1] = 30;
+        test_vm.run_once();
+        assert!(!test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_ltq_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = 10;
+        test_vm.program = prepend_header(vec![
+            16, 0, 1, 0, // EQ r0 == r1 (ignore last 0)
+            16, 0, 1, 0, // EQ r0 == r1 (ignore last 0)
+            16, 0, 1, 0, // EQ r0 == r1 (ignore last 0)
+            0]); // Halt
+        test_vm.run_once();
+        assert!(test_vm.equal_flag);
+        test_vm.registers[1] = 20;
+        test_vm.run_once();
+        assert!(test_vm.equal_flag);
+        test_vm.registers[1] = 0;
+        test_vm.run_once();
+        assert!(!test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_jmpe_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.equal_flag = true;
+        test_vm.program = prepend_header(vec![
+            17, 0, 0, 0,
+            17, 0, 0, 0,
+            17, 0, 0, 0,
+        ]);
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 7);
+    }
+
+    #[test]
+    fn test_jmpe_opcode_not_taken() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.program = prepend_header(vec![17, 0, 0, 0]);
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 69);
+    }
+
+    #[test]
+    fn test_inc_dec_consume_whole_instruction() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![
+            2, 0, 0, 0, // INC r0
+            2, 0, 0, 0, // INC r0
+            3, 1, 0, 0, // DEC r1
+            0]); // Halt
+        test_vm.run();
+        assert_eq!(test_vm.registers[0], 2);
+        assert_eq!(test_vm.registers[1], -1);
+    }
+
+    #[test]
+    fn test_aloc_prts_consume_whole_instruction() {
+        let mut test_vm = VM::new();
+        test_vm.set_output(Box::new(std::io::sink()));
+        test_vm.registers[0] = 8;
+        test_vm.ro_data = b"Hi\0".to_vec();
+        test_vm.program = prepend_header(vec![
+            19, 0, 0, 0, // ALOC r0 bytes
+            20, 0, 0, 0, // PRTS offset 0
+            2, 1, 0, 0,  // INC r1
+            0]); // Halt
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 69);
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 73);
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 77);
+        assert_eq!(test_vm.heap.len(), 8);
+        assert_eq!(test_vm.registers[1], 1);
+    }
+
+    #[test]
+    fn test_predecoded_matches_byte_decoding() {
+        let bytes = prepend_header(vec![
+            1, 0, 0, 10, // Load 10 => r0
+            1, 1, 0, 0,  // Load 0 => r1
+            1, 2, 0, 77, // Load 77 => r2
+            3, 0, 0, 0,  // DEC r0 (pc = 77)
+            12, 0, 1, 0, // NEQ r0 != r1
+            17, 2, 0, 0, // JMPE to r2
+            0]); // Halt
+        let mut results = vec![];
+        for predecode in &[true, false] {
+            let mut test_vm = VM::new();
+            test_vm.set_predecode(*predecode);
+            test_vm.add_bytes(bytes.clone());
+            test_vm.run();
+            results.push((test_vm.pc, test_vm.registers));
+        }
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[0].1[0], 0);
+    }
+
+    #[test]
+    fn test_decode_appended_instructions() {
+        let mut test_vm = VM::new();
+        test_vm.add_bytes(prepend_header(vec![1, 0, 0]));
+        assert!(test_vm.decoded.is_empty());
+        test_vm.add_byte(7);
+        assert_eq!(test_vm.decoded, vec![Instruction::decode(&[1, 0, 0, 7])]);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 7);
+
+        test_vm.clear_program();
+        assert!(test_vm.decoded.is_empty());
+    }
+
+    #[test]
+    fn test_run_exit_reasons() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![0, 0, 0, 0]);
+        assert_eq!(test_vm.run(), ExitReason::Halted);
+
+        test_vm.program = prepend_header(vec![1, 0, 0, 1]);
+        assert_eq!(test_vm.run(), ExitReason::EndOfProgram);
+
+        test_vm.program = prepend_header(vec![200, 0, 0, 0]);
+        assert_eq!(test_vm.run(), ExitReason::IllegalInstruction);
+
+        test_vm.program = vec![0, 0, 0, 0];
+        assert_eq!(test_vm.run(), ExitReason::InvalidHeader);
+    }
+
+    #[test]
+    fn test_out_of_gas_and_resume() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![
+            1, 0, 0, 69, // Load 69 => r0
+            2, 1, 0, 0,  // INC r1 (pc = 69)
+            8, 0, 0, 0,  // JMP to r0
+        ]);
+        test_vm.set_gas(21);
+        assert_eq!(test_vm.run(), ExitReason::OutOfGas);
+        assert_eq!(test_vm.registers[1], 10);
+        assert_eq!(test_vm.pc, 69);
+        assert_eq!(test_vm.gas(), Some(0));
+
+        test_vm.add_gas(4);
+        assert_eq!(test_vm.resume(), ExitReason::OutOfGas);
+        assert_eq!(test_vm.registers[1], 12);
+    }
+
+    #[test]
+    fn test_gas_cost_table() {
+        let mut test_vm = VM::new();
+        let mut costs = CostTable::new();
+        costs.set_cost(Opcode::ALOC, 5);
+        costs.set_aloc_byte_cost(1);
+        test_vm.set_cost_table(costs);
+        test_vm.registers[0] = 100;
+        test_vm.program = prepend_header(vec![
+            19, 0, 0, 0, // ALOC r0 bytes
+            19, 0, 0, 0, // ALOC r0 bytes
+            0]);
+        test_vm.set_gas(200);
+        assert_eq!(test_vm.run(), ExitReason::OutOfGas);
+        assert_eq!(test_vm.heap.len(), 100);
+        assert_eq!(test_vm.gas(), Some(95));
+    }
+
+    #[test]
+    fn test_heap_limit() {
+        let config = VmConfig { max_heap_bytes: Some(1024), ..VmConfig::default() };
+        let mut test_vm = VM::with_config(config);
+        test_vm.registers[0] = 1000;
+        test_vm.program = prepend_header(vec![
+            19, 0, 0, 0, // ALOC r0 bytes
+            19, 0, 0, 0, // ALOC r0 bytes
+            0]);
+        let reason = test_vm.run();
+        assert_eq!(reason, ExitReason::Fault(VmFault::HeapLimitExceeded { requested: 2000, limit: 1024 }));
+        assert_eq!(test_vm.heap.len(), 1000);
+        assert_eq!(test_vm.pc, 69);
+    }
+
+    #[test]
+    fn test_negative_allocation() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -1;
+        test_vm.program = prepend_header(vec![19, 0, 0, 0]);
+        assert_eq!(test_vm.run(), ExitReason::Fault(VmFault::NegativeAllocation { bytes: -1 }));
+        assert!(test_vm.heap.is_empty());
+    }
+
+    #[test]
+    fn test_output_limit() {
+        let config = VmConfig { max_output_bytes: Some(8), ..VmConfig::default() };
+        let mut test_vm = VM::with_config(config);
+        test_vm.ro_data = b"Hello\0".to_vec();
+        test_vm.program = prepend_header(vec![
+            20, 0, 0, 0, // PRTS offset 0
+            20, 0, 0, 0, // PRTS offset 0
+            0]);
+        assert_eq!(test_vm.run(), ExitReason::Fault(VmFault::OutputLimitExceeded { limit: 8 }));
+        assert_eq!(test_vm.output_bytes, 5);
+
+        // The count starts again with every run
+        assert_eq!(test_vm.run(), ExitReason::Fault(VmFault::OutputLimitExceeded { limit: 8 }));
+    }
+
+    #[test]
+    fn test_allowed_opcodes() {
+        let config = VmConfig { allowed_opcodes: Some(vec![Opcode::LOAD, Opcode::HLT]), ..VmConfig::default() };
+        let mut test_vm = VM::with_config(config);
+        test_vm.program = prepend_header(vec![
+            1, 0, 0, 1,  // Load 1 => r0
+            19, 0, 0, 0, // ALOC r0 bytes
+            0]);
+        assert_eq!(test_vm.run(), ExitReason::Fault(VmFault::OpcodeNotAllowed { opcode: Opcode::ALOC }));
+        assert_eq!(test_vm.registers[0], 1);
+        assert_eq!(test_vm.pc, 69);
+    }
+
+    #[test]
+    fn test_runtime_faults() {
+        let mut test_vm = VM::new();
+        test_vm.program = prepend_header(vec![1, 200, 0, 1]);
+        assert_eq!(test_vm.run(), ExitReason::Fault(VmFault::InvalidRegister { register: 200 }));
+
+        test_vm.program = prepend_header(vec![7, 0, 1, 2]);
+        test_vm.set_gas(5);
+        test_vm.start_coverage();
+        assert_eq!(test_vm.run(), ExitReason::Fault(VmFault::DivideByZero));
+        assert_eq!(test_vm.gas(), Some(5));
+        assert!(test_vm.take_coverage().unwrap().is_empty());
+
+        test_vm.program = prepend_header(vec![20, 0, 3, 0]);
+        assert_eq!(test_vm.run(), ExitReason::Fault(VmFault::InvalidStringOffset { offset: 3 }));
+    }
+
+    #[test]
+    fn test_igl_opcode() {
+        let mut test_vm = VM::new();
+        let test_bytes = prepend_header(vec![200,0,0,0]);
+        test_vm.program = test_bytes;
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 66);
+    }
+
+    #[test]
+    fn test_aloc_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 1024;
+        test_vm.program = prepend_header(vec![19, 0, 0, 0]);
+        test_vm.run_once();
+        assert_eq!(test_vm.heap.len(), 1024);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut test_vm = VM::new();
+        test_vm.add_bytes(prepend_header(vec![1, 0, 0, 9, 19, 0, 0, 0, 11, 0, 0, 0, 0]));
+        test_vm.add_breakpoint(CODE_START);
+        assert_eq!(test_vm.resume(), ExitReason::Halted);
+        assert!(test_vm.equal_flag);
+        test_vm.reset();
+        assert_eq!(test_vm.registers, [0; REGISTER_COUNT]);
+        assert_eq!(test_vm.pc, CODE_START);
+        assert!(test_vm.heap.is_empty());
+        assert!(!test_vm.equal_flag);
+        assert_eq!(test_vm.run(), ExitReason::Breakpoint { pc: CODE_START });
+    }
+}
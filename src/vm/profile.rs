@@ -0,0 +1,124 @@
+//! Exact profiling: every instruction executed is counted, along with the gas it cost under
+//! the VM's cost table, which is the VM's measure of how much work an instruction is. Gas is
+//! counted whether or not execution is metered.
+//!
+//! There are no calls yet, so a profile is flat. Reports group it by the label each
+//! instruction falls under instead of by call stack.
+
+use std::collections::BTreeMap;
+use std::ops::AddAssign;
+
+use super::VM;
+
+/// How many times an instruction ran and what it cost altogether
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Samples {
+    pub count: u64,
+    pub cost: u64,
+}
+
+impl AddAssign for Samples {
+    fn add_assign(&mut self, other: Samples) {
+        self.count = self.count.saturating_add(other.count);
+        self.cost = self.cost.saturating_add(other.cost);
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Profile {
+    /// Keyed by the address each instruction was fetched from. Only addresses that ran are
+    /// present.
+    samples: BTreeMap<usize, Samples>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Profile { samples: BTreeMap::new() }
+    }
+
+    /// Counts one run of the instruction at `address`, which cost `cost`
+    pub fn record(&mut self, address: usize, cost: u64) {
+        *self.samples.entry(address).or_default() += Samples { count: 1, cost };
+    }
+
+    pub fn samples(&self, address: usize) -> Samples {
+        self.samples.get(&address).copied().unwrap_or_default()
+    }
+
+    /// The addresses that ran and their samples, in address order
+    pub fn iter(&self) -> impl Iterator<Item = (usize, Samples)> + '_ {
+        self.samples.iter().map(|(address, samples)| (*address, *samples))
+    }
+
+    /// Everything the program ran
+    pub fn total(&self) -> Samples {
+        let mut total = Samples::default();
+        for (_, samples) in self.iter() {
+            total += samples;
+        }
+        total
+    }
+
+    /// Adds the samples of another run to these
+    pub fn merge(&mut self, other: &Profile) {
+        for (address, samples) in other.iter() {
+            *self.samples.entry(address).or_default() += samples;
+        }
+    }
+}
+
+impl VM {
+    /// Starts profiling from scratch. Profiling carries on across runs until `take_profile`.
+    pub fn start_profile(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    /// The profile so far, if profiling
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Stops profiling and returns the profile
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        self.profile.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::instructions::Opcode;
+    use crate::vm::{gas::CostTable, CODE_START};
+
+    #[test]
+    fn test_vm_profiles_instructions() {
+        let mut costs = CostTable::new();
+        costs.set_cost(Opcode::DEC, 5);
+        let mut vm = VM::new();
+        vm.set_cost_table(costs);
+        vm.add_bytes(Assembler::new().assemble(".data\n.code\nload $0 #3\nload $1 #69\ndec $0\nneq $0 $2\njmpe $1\nhlt\n").unwrap());
+        vm.start_profile();
+        vm.run();
+        let profile = vm.take_profile().unwrap();
+        assert_eq!(profile.samples(CODE_START + 8), Samples { count: 3, cost: 15 });
+        assert_eq!(profile.total(), Samples { count: 14, cost: 26 });
+        assert!(vm.profile().is_none());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut first = Profile::new();
+        first.record(65, 1);
+        let mut second = Profile::new();
+        second.record(65, 2);
+        second.record(69, 1);
+        second.record(usize::MAX, 1);
+        first.merge(&second);
+        assert_eq!(first.iter().collect::<Vec<_>>(), vec![
+            (65, Samples { count: 2, cost: 3 }),
+            (69, Samples { count: 1, cost: 1 }),
+            (usize::MAX, Samples { count: 1, cost: 1 }),
+        ]);
+    }
+}
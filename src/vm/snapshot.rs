@@ -0,0 +1,245 @@
+//! Saving and restoring the complete state of a VM.
+//!
+//! A snapshot file starts with `SNAPSHOT_MAGIC`, `SNAPSHOT_VERSION` and the number of registers,
+//! one byte each after the magic. The machine state follows, with all numbers big-endian: the pc
+//! (`u64`), `remainder` (`u32`), `equal_flag` (one byte), the gas left (a byte that is 1 if
+//! execution is metered, then a `u64`), the bytes printed so far (`u64`) and every register
+//! (`i32`). Last come the program, heap and read-only data, each as a `u64` length followed by
+//! that many bytes.
+
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::faults::VmFault;
+use super::{VM, REGISTER_COUNT};
+
+/// Identifies a file as an Iridium snapshot
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"IRSN";
+/// Bumped whenever the layout changes
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+/// Everything needed to carry on running a program later. Breakpoints, watchpoints and the
+/// VM's configuration belong to whoever is running it and are not part of a snapshot.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Snapshot {
+    pub registers: [i32; REGISTER_COUNT],
+    pub pc: usize,
+    pub program: Vec<u8>,
+    pub heap: Vec<u8>,
+    pub ro_data: Vec<u8>,
+    pub remainder: u32,
+    pub equal_flag: bool,
+    pub gas: Option<u64>,
+    pub output_bytes: usize,
+}
+
+impl Snapshot {
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&SNAPSHOT_MAGIC)?;
+        out.write_u8(SNAPSHOT_VERSION)?;
+        out.write_u8(REGISTER_COUNT as u8)?;
+
+        out.write_u64::<BigEndian>(self.pc as u64)?;
+        out.write_u32::<BigEndian>(self.remainder)?;
+        out.write_u8(self.equal_flag as u8)?;
+        out.write_u8(self.gas.is_some() as u8)?;
+        out.write_u64::<BigEndian>(self.gas.unwrap_or(0))?;
+        out.write_u64::<BigEndian>(self.output_bytes as u64)?;
+        for register in self.registers.iter() {
+            out.write_i32::<BigEndian>(*register)?;
+        }
+
+        for section in [&self.program, &self.heap, &self.ro_data] {
+            out.write_u64::<BigEndian>(section.len() as u64)?;
+            out.write_all(section)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err(invalid_data("not an Iridium snapshot".to_string()));
+        }
+        let version = input.read_u8()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid_data(format!("unsupported snapshot version {}", version)));
+        }
+        let register_count = input.read_u8()? as usize;
+        if register_count != REGISTER_COUNT {
+            return Err(invalid_data(format!("snapshot has {} registers, expected {}", register_count, REGISTER_COUNT)));
+        }
+
+        let pc = input.read_u64::<BigEndian>()? as usize;
+        let remainder = input.read_u32::<BigEndian>()?;
+        let equal_flag = input.read_u8()? != 0;
+        let metered = input.read_u8()? != 0;
+        let gas = input.read_u64::<BigEndian>()?;
+        let output_bytes = input.read_u64::<BigEndian>()? as usize;
+        let mut registers = [0; REGISTER_COUNT];
+        for register in registers.iter_mut() {
+            *register = input.read_i32::<BigEndian>()?;
+        }
+
+        Ok(Snapshot {
+            registers,
+            pc,
+            program: read_section(input)?,
+            heap: read_section(input)?,
+            ro_data: read_section(input)?,
+            remainder,
+            equal_flag,
+            gas: if metered { Some(gas) } else { None },
+            output_bytes,
+        })
+    }
+}
+
+fn read_section<R: Read>(input: &mut R) -> io::Result<Vec<u8>> {
+    let length = input.read_u64::<BigEndian>()?;
+    let mut section = vec![];
+    // Reading through `take` means a corrupt length can't make us allocate more than is there
+    input.take(length).read_to_end(&mut section)?;
+    if section.len() as u64 != length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "snapshot section is truncated"));
+    }
+    Ok(section)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl VM {
+    /// Captures the machine state so it can be restored later
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            pc: self.pc,
+            program: self.program.clone(),
+            heap: self.heap.clone(),
+            ro_data: self.ro_data.clone(),
+            remainder: self.remainder,
+            equal_flag: self.equal_flag,
+            gas: self.gas,
+            output_bytes: self.output_bytes,
+        }
+    }
+
+    /// Replaces the machine state with `snapshot`. Call `resume` to carry on from where the
+    /// snapshot was taken. The undo history and watchpoint log don't apply to the new state and
+    /// are cleared. A snapshot whose heap is over the configured limit is refused and the state
+    /// is left as it was.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), VmFault> {
+        if let Some(limit) = self.config.max_heap_bytes {
+            if snapshot.heap.len() > limit {
+                return Err(VmFault::HeapLimitExceeded { requested: snapshot.heap.len(), limit });
+            }
+        }
+        self.registers = snapshot.registers;
+        self.pc = snapshot.pc;
+        self.program = snapshot.program.clone();
+        self.heap = snapshot.heap.clone();
+        self.ro_data = snapshot.ro_data.clone();
+        self.remainder = snapshot.remainder;
+        self.equal_flag = snapshot.equal_flag;
+        self.gas = snapshot.gas;
+        self.output_bytes = snapshot.output_bytes;
+        self.history.clear();
+        self.changes.clear();
+        self.watch_log.clear();
+        self.predecode_program();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{config::VmConfig, test::vm_with_code, ExitReason};
+    use crate::vm::watchpoints::{WatchAction, WatchCondition, WatchTarget, Watchpoint};
+
+    fn countdown_vm() -> VM {
+        vm_with_code(vec![
+            1, 0, 0, 50, // Load 50 => r0
+            1, 2, 0, 73, // Load 73 => r2
+            3, 0, 0, 0,  // DEC r0 (pc = 73)
+            19, 0, 0, 0, // ALOC r0 bytes
+            12, 0, 1, 0, // NEQ r0 != r1
+            17, 2, 0, 0, // JMPE to r2
+            0]) // Halt
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut vm = countdown_vm();
+        vm.set_gas(40);
+        assert_eq!(vm.run(), ExitReason::OutOfGas);
+        let snapshot = vm.snapshot();
+
+        let mut bytes = vec![];
+        snapshot.write_to(&mut bytes).unwrap();
+        assert_eq!(&bytes[0..6], b"IRSN\x01\x20");
+        assert_eq!(Snapshot::read_from(&mut &bytes[..]).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn test_restore_carries_on() {
+        let mut expected = countdown_vm();
+        assert_eq!(expected.run(), ExitReason::Halted);
+
+        let mut vm = countdown_vm();
+        vm.set_gas(101);
+        assert_eq!(vm.run(), ExitReason::OutOfGas);
+        let snapshot = vm.snapshot();
+
+        let mut restored = VM::new();
+        restored.restore(&snapshot).unwrap();
+        restored.add_gas(1000);
+        assert_eq!(restored.resume(), ExitReason::Halted);
+        assert_eq!(restored.registers, expected.registers);
+        assert_eq!(restored.pc(), expected.pc());
+        assert_eq!(restored.heap, expected.heap);
+        assert_eq!(restored.gas(), Some(1101 - 203));
+
+        // Nothing recorded while running the old state survives a restore
+        vm.set_history_size(10);
+        vm.add_watchpoint(Watchpoint::new(WatchTarget::Register(0), WatchCondition::Write, WatchAction::Log));
+        vm.add_gas(20);
+        assert_eq!(vm.resume(), ExitReason::OutOfGas);
+        vm.restore(&snapshot).unwrap();
+        assert_eq!(vm.history_len(), 0);
+        assert!(vm.take_watch_log().is_empty());
+    }
+
+    #[test]
+    fn test_restore_checks_heap_limit() {
+        let mut vm = countdown_vm();
+        assert_eq!(vm.run(), ExitReason::Halted);
+        let snapshot = vm.snapshot();
+
+        let mut limited = VM::with_config(VmConfig { max_heap_bytes: Some(16), ..VmConfig::default() });
+        let requested = snapshot.heap.len();
+        assert_eq!(limited.restore(&snapshot), Err(VmFault::HeapLimitExceeded { requested, limit: 16 }));
+        assert!(limited.heap.is_empty());
+    }
+
+    #[test]
+    fn test_bad_snapshots() {
+        let mut bytes = vec![];
+        countdown_vm().snapshot().write_to(&mut bytes).unwrap();
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = 9;
+        assert!(Snapshot::read_from(&mut &wrong_version[..]).is_err());
+
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'E';
+        assert!(Snapshot::read_from(&mut &wrong_magic[..]).is_err());
+
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(Snapshot::read_from(&mut &truncated[..]).is_err());
+    }
+}
@@ -0,0 +1,36 @@
+use std::fmt;
+
+/// A single change an instruction made to the VM's state. The VM only records these while
+/// something, such as a watchpoint, needs them.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum StateChange {
+    Register { index: u8, old: i32, new: i32 },
+    EqualFlag { old: bool, new: bool },
+    Remainder { old: u32, new: u32 },
+    /// The heap grew (or shrank) from `old_len` to `new_len` bytes. New bytes are zeroed.
+    HeapResize { old_len: usize, new_len: usize },
+}
+
+impl StateChange {
+    /// Whether the value actually changed, as opposed to being written with the value it
+    /// already had
+    pub fn is_change(&self) -> bool {
+        match self {
+            StateChange::Register { old, new, .. } => old != new,
+            StateChange::EqualFlag { old, new } => old != new,
+            StateChange::Remainder { old, new } => old != new,
+            StateChange::HeapResize { old_len, new_len } => old_len != new_len,
+        }
+    }
+}
+
+impl fmt::Display for StateChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateChange::Register { index, old, new } => write!(f, "${}: {} -> {}", index, old, new),
+            StateChange::EqualFlag { old, new } => write!(f, "equal_flag: {} -> {}", old, new),
+            StateChange::Remainder { old, new } => write!(f, "remainder: {} -> {}", old, new),
+            StateChange::HeapResize { old_len, new_len } => write!(f, "heap: {} -> {} bytes", old_len, new_len),
+        }
+    }
+}
@@ -0,0 +1,339 @@
+//! Per-instruction execution traces.
+//!
+//! A trace file starts with `TRACE_MAGIC` and `TRACE_VERSION`, followed by one record for every
+//! instruction executed, including one that faulted. All numbers are big-endian:
+//!
+//! | Field        | Size    | Notes                                               |
+//! |--------------|---------|-----------------------------------------------------|
+//! | pc           | 4 bytes | Address the instruction was fetched from            |
+//! | instruction  | 4 bytes | The decoded instruction, as `Instruction::to_bytes` |
+//! | status       | 1 byte  | 0 if the instruction finished, 1 if it faulted      |
+//! | change count | 2 bytes | Number of state changes that follow                 |
+//! | changes      | varies  | A tag byte, then the fields of the `StateChange`    |
+//!
+//! Change tags are 0 for a register (index `u8`, old and new `i32`), 1 for `equal_flag` (old and
+//! new as one byte each), 2 for `remainder` (old and new `u32`) and 3 for a heap resize (old and
+//! new length as `u64`). A faulting instruction changes nothing, so its change count is always 0.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use log::error;
+
+use super::state_change::StateChange;
+use super::VM;
+use crate::instructions::{Instruction, INSTRUCTION_LENGTH};
+
+/// Identifies a file as an Iridium trace
+pub const TRACE_MAGIC: [u8; 4] = *b"IRTR";
+/// Bumped whenever the record layout changes
+pub const TRACE_VERSION: u8 = 2;
+
+const FINISHED: u8 = 0;
+const FAULTED: u8 = 1;
+
+const REGISTER_TAG: u8 = 0;
+const EQUAL_FLAG_TAG: u8 = 1;
+const REMAINDER_TAG: u8 = 2;
+const HEAP_RESIZE_TAG: u8 = 3;
+
+/// One executed instruction and everything it changed
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceRecord {
+    pub pc: usize,
+    pub instruction: Instruction,
+    pub changes: Vec<StateChange>,
+    /// The instruction faulted instead of finishing
+    pub faulted: bool,
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>6}: {}", self.pc, self.instruction)?;
+        for (i, change) in self.changes.iter().enumerate() {
+            let separator = if i == 0 { "  " } else { ", " };
+            write!(f, "{}{}", separator, change)?;
+        }
+        if self.faulted {
+            f.write_str("  (fault)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes trace records to any `Write`
+pub struct TraceWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Writes the trace header and returns a writer ready for records
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(&TRACE_MAGIC)?;
+        out.write_u8(TRACE_VERSION)?;
+        Ok(TraceWriter { out })
+    }
+
+    pub fn write_record(&mut self, pc: usize, instruction: &Instruction, changes: &[StateChange]) -> io::Result<()> {
+        self.out.write_u32::<BigEndian>(pc as u32)?;
+        self.out.write_all(&instruction.to_bytes())?;
+        self.out.write_u8(FINISHED)?;
+        self.out.write_u16::<BigEndian>(changes.len() as u16)?;
+        for change in changes {
+            match *change {
+                StateChange::Register { index, old, new } => {
+                    self.out.write_u8(REGISTER_TAG)?;
+                    self.out.write_u8(index)?;
+                    self.out.write_i32::<BigEndian>(old)?;
+                    self.out.write_i32::<BigEndian>(new)?;
+                },
+                StateChange::EqualFlag { old, new } => {
+                    self.out.write_u8(EQUAL_FLAG_TAG)?;
+                    self.out.write_u8(old as u8)?;
+                    self.out.write_u8(new as u8)?;
+                },
+                StateChange::Remainder { old, new } => {
+                    self.out.write_u8(REMAINDER_TAG)?;
+                    self.out.write_u32::<BigEndian>(old)?;
+                    self.out.write_u32::<BigEndian>(new)?;
+                },
+                StateChange::HeapResize { old_len, new_len } => {
+                    self.out.write_u8(HEAP_RESIZE_TAG)?;
+                    self.out.write_u64::<BigEndian>(old_len as u64)?;
+                    self.out.write_u64::<BigEndian>(new_len as u64)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Records an instruction at `pc` that faulted
+    pub fn write_fault(&mut self, pc: usize, instruction: &Instruction) -> io::Result<()> {
+        self.out.write_u32::<BigEndian>(pc as u32)?;
+        self.out.write_all(&instruction.to_bytes())?;
+        self.out.write_u8(FAULTED)?;
+        self.out.write_u16::<BigEndian>(0)
+    }
+
+    /// Flushes and returns the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Reads trace records back. Iterating yields each record in turn, stopping at the end of the
+/// input or at the first error.
+pub struct TraceReader<R: Read> {
+    input: R,
+    failed: bool,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Checks the trace header and returns a reader positioned at the first record
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+        if magic != TRACE_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an Iridium trace"));
+        }
+        let version = input.read_u8()?;
+        if version != TRACE_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported trace version {}", version)));
+        }
+        Ok(TraceReader { input, failed: false })
+    }
+
+    /// Reads the next record, or `None` if the trace ended cleanly between records
+    pub fn read_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        let mut pc = [0; 4];
+        let read = self.input.read(&mut pc)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        self.input.read_exact(&mut pc[read..])?;
+        let pc = u32::from_be_bytes(pc) as usize;
+
+        let mut instruction = [0; INSTRUCTION_LENGTH];
+        self.input.read_exact(&mut instruction)?;
+        let instruction = Instruction::decode(&instruction);
+
+        let faulted = match self.input.read_u8()? {
+            FINISHED => false,
+            FAULTED => true,
+            status => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown status {}", status))),
+        };
+
+        let count = self.input.read_u16::<BigEndian>()?;
+        let mut changes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let change = match self.input.read_u8()? {
+                REGISTER_TAG => StateChange::Register {
+                    index: self.input.read_u8()?,
+                    old: self.input.read_i32::<BigEndian>()?,
+                    new: self.input.read_i32::<BigEndian>()?,
+                },
+                EQUAL_FLAG_TAG => StateChange::EqualFlag {
+                    old: self.input.read_u8()? != 0,
+                    new: self.input.read_u8()? != 0,
+                },
+                REMAINDER_TAG => StateChange::Remainder {
+                    old: self.input.read_u32::<BigEndian>()?,
+                    new: self.input.read_u32::<BigEndian>()?,
+                },
+                HEAP_RESIZE_TAG => StateChange::HeapResize {
+                    old_len: self.input.read_u64::<BigEndian>()? as usize,
+                    new_len: self.input.read_u64::<BigEndian>()? as usize,
+                },
+                tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown change tag {}", tag))),
+            };
+            changes.push(change);
+        }
+
+        Ok(Some(TraceRecord { pc, instruction, changes, faulted }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.read_record() {
+            Ok(record) => record.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl VM {
+    /// Starts recording a trace of every instruction executed to `out`. Any trace already being
+    /// recorded is finished first.
+    pub fn start_trace(&mut self, out: Box<dyn Write + Send>) -> io::Result<()> {
+        self.stop_trace()?;
+        self.trace = Some(TraceWriter::new(out)?);
+        self.update_recording();
+        Ok(())
+    }
+
+    /// Stops recording the trace and flushes it
+    pub fn stop_trace(&mut self) -> io::Result<()> {
+        let trace = self.trace.take();
+        self.update_recording();
+        match trace {
+            Some(trace) => trace.finish().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.trace.is_some()
+    }
+
+    /// Adds the instruction that just ran at `pc` to the trace, or marks it as faulted. A trace
+    /// that can't be written is abandoned rather than stopping the program.
+    pub(super) fn write_trace(&mut self, pc: usize, instruction: &Instruction, faulted: bool) {
+        if let Some(trace) = self.trace.as_mut() {
+            let written = match faulted {
+                true => trace.write_fault(pc, instruction),
+                false => trace.write_record(pc, instruction, &self.changes),
+            };
+            if let Err(e) = written {
+                error!("Unable to write trace, stopping it: {}", e);
+                self.trace = None;
+                self.update_recording();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::Opcode;
+    use crate::vm::{test::vm_with_code, ExitReason, VmFault};
+
+    #[test]
+    fn test_round_trip() {
+        let load = Instruction::decode(&[1, 2, 0, 7]);
+        let changes = vec![
+            StateChange::Register { index: 2, old: 0, new: -7 },
+            StateChange::EqualFlag { old: true, new: false },
+            StateChange::Remainder { old: 3, new: 4 },
+            StateChange::HeapResize { old_len: 0, new_len: 1 << 20 },
+        ];
+        let mut writer = TraceWriter::new(vec![]).unwrap();
+        writer.write_record(65, &load, &changes).unwrap();
+        writer.write_record(69, &Instruction::new(Opcode::HLT), &[]).unwrap();
+        writer.write_fault(73, &Instruction::new(Opcode::DIV)).unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let records: Vec<TraceRecord> = TraceReader::new(&bytes[..]).unwrap().map(Result::unwrap).collect();
+        assert_eq!(records, vec![
+            TraceRecord { pc: 65, instruction: load, changes, faulted: false },
+            TraceRecord { pc: 69, instruction: Instruction::new(Opcode::HLT), changes: vec![], faulted: false },
+            TraceRecord { pc: 73, instruction: Instruction::new(Opcode::DIV), changes: vec![], faulted: true },
+        ]);
+    }
+
+    #[test]
+    fn test_bad_traces() {
+        assert!(TraceReader::new(&b"EPIE\x01"[..]).is_err());
+        assert!(TraceReader::new(&b"IRTR\x01"[..]).is_err());
+
+        let mut reader = TraceReader::new(&b"IRTR\x02\x00\x00\x00\x41\x01"[..]).unwrap();
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+
+        let mut reader = TraceReader::new(&b"IRTR\x02\x00\x00\x00\x41\x00\x00\x00\x00\x07\x00\x00"[..]).unwrap();
+        assert!(reader.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_vm_trace() {
+        let path = std::env::temp_dir().join(format!("iridium-trace-test-{}.irt", std::process::id()));
+        let mut vm = vm_with_code(vec![1, 0, 0, 5, 3, 0, 0, 0, 0]);
+        vm.start_trace(Box::new(std::fs::File::create(&path).unwrap())).unwrap();
+        assert_eq!(vm.run(), ExitReason::Halted);
+        vm.stop_trace().unwrap();
+        assert!(!vm.is_tracing());
+
+        let records: Vec<TraceRecord> = TraceReader::new(std::fs::File::open(&path).unwrap())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<String> = records.iter().map(|r| r.to_string()).collect();
+        assert_eq!(lines, vec![
+            "    65: load $0 #5  $0: 0 -> 5",
+            "    69: dec $0  $0: 5 -> 4",
+            "    73: hlt",
+        ]);
+    }
+
+    #[test]
+    fn test_vm_trace_fault() {
+        let path = std::env::temp_dir().join(format!("iridium-trace-fault-test-{}.irt", std::process::id()));
+        let mut vm = vm_with_code(vec![1, 0, 0, 5, 7, 0, 1, 2, 0]);
+        vm.start_trace(Box::new(std::fs::File::create(&path).unwrap())).unwrap();
+        assert_eq!(vm.run(), ExitReason::Fault(VmFault::DivideByZero));
+        vm.stop_trace().unwrap();
+
+        let records: Vec<TraceRecord> = TraceReader::new(std::fs::File::open(&path).unwrap())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<String> = records.iter().map(|r| r.to_string()).collect();
+        assert_eq!(lines, vec![
+            "    65: load $0 #5  $0: 0 -> 5",
+            "    69: div $0 $1 $2  (fault)",
+        ]);
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt;
+
+use super::{VM, ExitReason};
+use super::state_change::StateChange;
+
+/// The piece of VM state a watchpoint looks at
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum WatchTarget {
+    Register(u8),
+    EqualFlag,
+    Remainder,
+    /// `length` bytes of the heap starting at `start`
+    Heap { start: usize, length: usize },
+}
+
+/// Whether a watchpoint triggers on any write or only when the value is different afterwards
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum WatchCondition {
+    Write,
+    Change,
+}
+
+/// What happens when a watchpoint triggers
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum WatchAction {
+    /// Stop execution with `ExitReason::Watchpoint`
+    Break,
+    /// Keep running and add the hit to the watch log
+    Log,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Watchpoint {
+    pub target: WatchTarget,
+    pub condition: WatchCondition,
+    pub action: WatchAction,
+}
+
+impl Watchpoint {
+    pub fn new(target: WatchTarget, condition: WatchCondition, action: WatchAction) -> Self {
+        Watchpoint { target, condition, action }
+    }
+
+    fn triggered_by(&self, change: &StateChange) -> bool {
+        let watched = match (self.target, change) {
+            (WatchTarget::Register(r), StateChange::Register { index, .. }) => r == *index,
+            (WatchTarget::EqualFlag, StateChange::EqualFlag { .. }) => true,
+            (WatchTarget::Remainder, StateChange::Remainder { .. }) => true,
+            // Growing or shrinking the heap over the range writes every byte in it
+            (WatchTarget::Heap { start, length }, StateChange::HeapResize { old_len, new_len }) => {
+                let (low, high) = (*old_len.min(new_len), *old_len.max(new_len));
+                start < high && start.saturating_add(length) > low
+            },
+            _ => false,
+        };
+        watched && (self.condition == WatchCondition::Write || change.is_change())
+    }
+}
+
+/// A watchpoint that was triggered by the instruction at `pc`
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    pub pc: usize,
+    pub change: StateChange,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Instruction at {} wrote {}", self.pc, self.change)
+    }
+}
+
+impl VM {
+    /// Adds a watchpoint and returns its index
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.watchpoints.push(watchpoint);
+        self.update_recording();
+        self.watchpoints.len() - 1
+    }
+
+    /// Removes the watchpoint at `index`, returning it if there was one
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        if index >= self.watchpoints.len() {
+            return None;
+        }
+        let removed = self.watchpoints.remove(index);
+        self.update_recording();
+        Some(removed)
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+        self.update_recording();
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Returns, and forgets, the hits of `WatchAction::Log` watchpoints since the last call
+    pub fn take_watch_log(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watch_log)
+    }
+
+    /// Checks the changes made by the instruction at `pc` against the watchpoints. Logged hits
+    /// are saved; the first hit of a breaking watchpoint is returned as the reason to stop.
+    pub(super) fn check_watchpoints(&mut self, pc: usize) -> Option<ExitReason> {
+        let mut stop = None;
+        for change in &self.changes {
+            for watchpoint in &self.watchpoints {
+                if !watchpoint.triggered_by(change) {
+                    continue;
+                }
+                let hit = WatchHit { watchpoint: *watchpoint, pc, change: *change };
+                match watchpoint.action {
+                    WatchAction::Log => self.watch_log.push(hit),
+                    WatchAction::Break => {
+                        stop = stop.or(Some(ExitReason::Watchpoint(hit)));
+                    },
+                }
+            }
+        }
+        stop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::test::vm_with_code;
+
+    #[test]
+    fn test_register_watchpoint_breaks() {
+        let mut vm = vm_with_code(vec![
+            1, 0, 0, 5, // Load 5 => r0
+            1, 7, 0, 9, // Load 9 => r7
+            2, 0, 0, 0, // INC r0
+            0]);
+        vm.add_watchpoint(Watchpoint::new(WatchTarget::Register(7), WatchCondition::Change, WatchAction::Break));
+        let reason = vm.run();
+        let expected = WatchHit {
+            watchpoint: vm.watchpoints()[0],
+            pc: 69,
+            change: StateChange::Register { index: 7, old: 0, new: 9 },
+        };
+        assert_eq!(reason, ExitReason::Watchpoint(expected));
+        assert_eq!(vm.pc(), 73);
+        assert_eq!(vm.resume(), ExitReason::Halted);
+    }
+
+    #[test]
+    fn test_write_and_change_conditions() {
+        let mut vm = vm_with_code(vec![
+            1, 3, 0, 0, // Load 0 => r3
+            1, 3, 0, 1, // Load 1 => r3
+            0]);
+        vm.add_watchpoint(Watchpoint::new(WatchTarget::Register(3), WatchCondition::Write, WatchAction::Log));
+        vm.add_watchpoint(Watchpoint::new(WatchTarget::Register(3), WatchCondition::Change, WatchAction::Log));
+        assert_eq!(vm.run(), ExitReason::Halted);
+
+        let log = vm.take_watch_log();
+        let conditions: Vec<(usize, WatchCondition)> = log.iter().map(|h| (h.pc, h.watchpoint.condition)).collect();
+        assert_eq!(conditions, vec![(65, WatchCondition::Write), (69, WatchCondition::Write), (69, WatchCondition::Change)]);
+        assert!(vm.take_watch_log().is_empty());
+    }
+
+    #[test]
+    fn test_flag_remainder_and_heap_watchpoints() {
+        let mut vm = vm_with_code(vec![
+            1, 0, 0, 7,   // Load 7 => r0
+            1, 1, 0, 2,   // Load 2 => r1
+            11, 0, 1, 0,  // EQ r0 r1
+            7, 0, 1, 2,   // DIV r0 / r1 => r2
+            19, 0, 0, 0,  // ALOC r0 bytes
+            0]);
+        vm.add_watchpoint(Watchpoint::new(WatchTarget::EqualFlag, WatchCondition::Write, WatchAction::Log));
+        vm.add_watchpoint(Watchpoint::new(WatchTarget::Remainder, WatchCondition::Change, WatchAction::Log));
+        vm.add_watchpoint(Watchpoint::new(WatchTarget::Heap { start: 4, length: 2 }, WatchCondition::Change, WatchAction::Break));
+        let reason = vm.run();
+        assert_eq!(reason, ExitReason::Watchpoint(WatchHit {
+            watchpoint: vm.watchpoints()[2],
+            pc: 81,
+            change: StateChange::HeapResize { old_len: 0, new_len: 7 },
+        }));
+
+        let changes: Vec<StateChange> = vm.take_watch_log().iter().map(|h| h.change).collect();
+        assert_eq!(changes, vec![
+            StateChange::EqualFlag { old: false, new: false },
+            StateChange::Remainder { old: 0, new: 1 },
+        ]);
+
+        // A range running off the end of memory still covers the bytes up to the end
+        let watchpoint = Watchpoint::new(WatchTarget::Heap { start: 6, length: usize::MAX }, WatchCondition::Write, WatchAction::Log);
+        assert!(watchpoint.triggered_by(&StateChange::HeapResize { old_len: 0, new_len: 7 }));
+        assert!(!watchpoint.triggered_by(&StateChange::HeapResize { old_len: 0, new_len: 6 }));
+    }
+
+    #[test]
+    fn test_remove_watchpoint() {
+        let mut vm = vm_with_code(vec![1, 0, 0, 5, 0]);
+        let index = vm.add_watchpoint(Watchpoint::new(WatchTarget::Register(0), WatchCondition::Write, WatchAction::Break));
+        assert!(vm.remove_watchpoint(index).is_some());
+        assert!(vm.remove_watchpoint(index).is_none());
+        assert_eq!(vm.run(), ExitReason::Halted);
+        assert!(vm.changes.is_empty());
+    }
+}